@@ -1,11 +1,20 @@
+use nix::libc;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::sys::termios::{self, ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg, Termios};
+use nix::unistd::{self, AccessFlags, User};
 use regex::Regex;
+use std::cell::Cell;
 use std::cmp;
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Read, Write};
+use std::io::{self, Read, Write};
 use std::mem;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::prelude::AsFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::thread;
@@ -14,11 +23,140 @@ use unicode_width::UnicodeWidthChar;
 
 const VERSION: &str = "0.0.1";
 
-// TODO: get config from config file.
+// Set by the SIGCONT handler when we're resumed after being suspended.
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+// Set by --no-color or the NO_COLOR environment variable, to draw without
+// colors. Inverse video is kept so that the status bar stands out, and the
+// selection, search matches and the current find match use it instead.
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+
+// Defaults. Options can be set in ~/.kilorc, but the rest are fixed for now.
 const TAB_STOP: usize = 8;
+const EXPAND_TABS: bool = false;
+const INDENT_SIZE: usize = 4;
+const TRIM_TRAILING_WHITESPACE: bool = false;
+const INSERT_FINAL_NEWLINE: bool = true;
+// Save named buffers this many seconds after they're changed. 0 turns it off.
+const AUTO_SAVE_SECS: u64 = 0;
+// Save named buffers when the terminal loses focus.
+const AUTO_SAVE_ON_FOCUS_LOSS: bool = false;
+// Copy unsaved changes to the filename plus BACKUP_SUFFIX after this many
+// seconds without a keypress, leaving the file itself alone. 0 turns it off.
+const BACKUP_IDLE_SECS: u64 = 0;
+const BACKUP_SUFFIX: &str = ".kilo.bak";
+// Create a file that doesn't exist as soon as it's opened, rather than when
+// it's first saved.
+const CREATE_MISSING_FILES: bool = false;
 const MAX_STATUS_FILENAME_LENGTH: usize = 20;
+// Show the filename in the terminal's title.
+const SET_TITLE: bool = true;
 const QUIT_TIMES: u8 = 3;
+// Lines of context kept on screen by PageUp and PageDown.
+const PAGE_OVERLAP: usize = 2;
+// Put the line jumped to in the middle of the screen rather than at the top.
+const CENTER_ON_JUMP: bool = true;
+// How many lines to keep below the cursor when Enter is pressed near the
+// bottom of the screen.
+const NEWLINE_SCROLL_MARGIN: usize = 0;
+// Listed with their keys on the welcome screen.
+const WELCOME_ACTIONS: [Action; 4] = [
+    Action::Save,
+    Action::Find,
+    Action::Quit,
+    Action::CommandPalette,
+];
+// How many positions Ctrl-O can go back through.
+const JUMP_LIST_SIZE: usize = 100;
+// How many files the list of recently opened files keeps.
+const RECENT_FILES_SIZE: usize = 20;
+// Marks that are set automatically, like Vim's.
+const LAST_EDIT_MARK: char = '.';
+const LAST_JUMP_MARK: char = '\'';
+// How many rows to look at when guessing the delimiter for CSV view.
+const CSV_SAMPLE_ROWS: usize = 10;
+// How many matching commands the command palette shows at once.
+const PALETTE_MATCHES_SHOWN: usize = 5;
 const RENDER_WHITESPACE: bool = false;
+// Show invisible chars, like zero width spaces and bidi controls, as their
+// codepoints.
+const LIST_MODE: bool = false;
+// Color the text by its filetype.
+const SYNTAX_HIGHLIGHT: bool = true;
+// More interpreters to recognise in a `#!` line, as interpreter:filetype
+// pairs like "python3:python,node:javascript". They're tried before the
+// filetypes' own.
+const INTERPRETER_SYNTAX: &str = "";
+// Rows longer than this many bytes, like minified code, aren't highlighted
+// since it would slow down every keypress. 0 highlights every row.
+const MAX_HIGHLIGHT_LENGTH: usize = 10_000;
+// For rows that long, search matches and other words like the one under the
+// cursor are only looked for this many columns either side of the screen.
+const LONG_LINE_MARGIN: usize = 1_000;
+const SOFT_WRAP: bool = false;
+// With soft wrap, move the cursor up and down by lines on the screen rather
+// than by rows of the file.
+const VISUAL_LINE_MOVEMENT: bool = false;
+const RAINBOW_BRACKETS: bool = false;
+// Highlight edited rows just before drawing, instead of after every change.
+const DEFER_HIGHLIGHT: bool = true;
+const CURSOR_LINE: bool = false;
+const CURSOR_LINE_BACKGROUND: u8 = 236;
+// Keep the last search highlighted after the search is done.
+const HIGHLIGHT_SEARCH: bool = false;
+// Ignore case in searches with no uppercase letters, unless the query sets
+// the (?i) flag itself.
+const SMART_CASE: bool = true;
+const SEARCH_HIGHLIGHT_BACKGROUND: u8 = 24;
+// The labels put on words to jump to, in order.
+const JUMP_LABELS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const JUMP_LABEL_BACKGROUND: u8 = 124;
+// Highlight other occurrences of the word under the cursor.
+const HIGHLIGHT_WORD: bool = false;
+const WORD_HIGHLIGHT_BACKGROUND: u8 = 238;
+const SELECTION_BACKGROUND: u8 = 239;
+// Tint the part of a line past this many columns. 0 turns it off.
+const MAX_LINE_LENGTH: usize = 0;
+const LONG_LINE_BACKGROUND: u8 = 52;
+// Tint this screen column, counting from 1, to show where lines should end.
+// 0 turns it off. With COLOR_COLUMN_FILL, every column after it is tinted too.
+const COLOR_COLUMN: usize = 0;
+const COLOR_COLUMN_FILL: bool = false;
+const COLOR_COLUMN_BACKGROUND: u8 = 235;
+// Flag indentation that mixes tabs and spaces, and recipes in files that need
+// tabs that don't start with one.
+const WARN_MIXED_INDENT: bool = true;
+const MIXED_INDENT_BACKGROUND: u8 = 94;
+// Make line numbers links to the line, for terminals that support it.
+const GUTTER_LINKS: bool = false;
+const LINE_NUMBER_MODE: LineNumberMode = LineNumberMode::Absolute;
+const STATUS_COLUMN: StatusColumn = StatusColumn::Both;
+const GUTTER_SEPARATOR: &str = "";
+// Drawn in the gutter instead of a line number on the rest of a wrapped row.
+const WRAP_MARKER: &str = "↪";
+// Drawn on the lines past the end of the file. Empty to leave them blank.
+const EMPTY_LINE_MARKER: &str = "~";
+const EMPTY_LINE_MARKER_COLOR: Color = Color::Blue;
+const GUTTER_PADDING_LEFT: usize = 0;
+const GUTTER_PADDING_RIGHT: usize = 1;
+const TEXT_WIDTH: usize = 80;
+// Indent the rest of a reflowed paragraph to match its first line.
+const REFLOW_HANGING_INDENT: bool = true;
+// Remember each file's marks between sessions.
+const PERSIST_MARKS: bool = true;
+// Whether $VARS in filenames that aren't set are an error rather than empty.
+const UNSET_VARIABLE_IS_ERROR: bool = false;
+// How much of a file to look at when deciding whether it's binary.
+const BINARY_SAMPLE_SIZE: usize = 8192;
+// How often to say how far loading a big file has got.
+const LOAD_PROGRESS_MS: u64 = 200;
+// How often to check whether we've been continued while waiting for a key.
+const RESUME_POLL_MS: u64 = 100;
+const UTF8_BOM: &str = "\u{feff}";
+const HEX_BYTES_PER_ROW: usize = 16;
+// The extensions of compressed files that are opened decompressed.
+#[cfg(feature = "compression")]
+const COMPRESSED_EXTENSIONS: [&str; 3] = [".gz", ".bz2", ".xz"];
 
 // Create a way to read chars from stdin without blocking.
 fn spawn_stdin_channel() -> Receiver<char> {
@@ -40,17 +178,763 @@ fn spawn_stdin_channel() -> Receiver<char> {
     rx
 }
 
-fn get_window_size() -> Dimensions {
+fn get_window_size() -> Option<Dimensions> {
     // Interfacing with ioctl in Rust is a bit of a pain so we use a library.
-    let (width, height) =
-        term_size::dimensions_stdin().expect("Failed to get terminal dimensions.");
-    Dimensions {
+    let (width, height) = term_size::dimensions_stdin()?;
+    Some(Dimensions {
         rows: height,
         cols: width,
+    })
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
     }
 }
 
-#[derive(Copy, Clone)]
+// Split text into lines at LF or CRLF, along with the ending each line had.
+// A CR on its own only ends a line in files without any LFs, like old Mac
+// ones, since elsewhere it's more likely to be part of the line.
+fn split_lines(contents: &str) -> (Vec<&str>, Vec<LineEnding>) {
+    if !contents.contains('\n') {
+        let mut lines: Vec<&str> = contents.split('\r').collect();
+        if lines.last() == Some(&"") {
+            lines.pop();
+        }
+        return (lines, vec![LineEnding::Cr; contents.matches('\r').count()]);
+    }
+    let mut lines = Vec::new();
+    let mut endings = Vec::new();
+    let mut rest = contents;
+    while let Some(i) = rest.find('\n') {
+        let (line, ending) = match rest[..i].strip_suffix('\r') {
+            Some(line) => (line, LineEnding::CrLf),
+            None => (&rest[..i], LineEnding::Lf),
+        };
+        lines.push(line);
+        endings.push(ending);
+        rest = &rest[i + 1..];
+    }
+    if !rest.is_empty() {
+        lines.push(rest);
+    }
+    (lines, endings)
+}
+
+// How a file's bytes are turned into text. Rows are always kept as UTF-8.
+#[derive(Copy, Clone, PartialEq)]
+enum Encoding {
+    Utf8,
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Latin1 => "latin-1",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Some(Encoding::Utf16Be),
+            _ => None,
+        }
+    }
+
+    // Turn a file's bytes into UTF-8. UTF-8 is checked when it's loaded.
+    fn decode(self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let utf16 = |unit: fn([u8; 2]) -> u16| {
+            if !bytes.len().is_multiple_of(2) {
+                return Err(format!("it isn't valid {}", self.name()));
+            }
+            let units = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]]));
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .map(String::into_bytes)
+                .map_err(|_| format!("it isn't valid {}", self.name()))
+        };
+        match self {
+            Encoding::Utf8 => Ok(bytes),
+            Encoding::Latin1 => Ok(bytes
+                .iter()
+                .map(|&byte| byte as char)
+                .collect::<String>()
+                .into_bytes()),
+            Encoding::Utf16Le => utf16(u16::from_le_bytes),
+            Encoding::Utf16Be => utf16(u16::from_be_bytes),
+        }
+    }
+
+    fn encode(self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Latin1 => text
+                .chars()
+                .map(|c| {
+                    if (c as u32) <= 0xff {
+                        Ok(c as u8)
+                    } else {
+                        Err(format!("{} can't be written in {}", c, self.name()))
+                    }
+                })
+                .collect(),
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum LineNumberMode {
+    Absolute,
+    // Distance from the cursor's line.
+    Relative,
+    // Relative, except for the cursor's line which is absolute.
+    Hybrid,
+}
+
+impl LineNumberMode {
+    fn name(self) -> &'static str {
+        match self {
+            LineNumberMode::Absolute => "absolute",
+            LineNumberMode::Relative => "relative",
+            LineNumberMode::Hybrid => "hybrid",
+        }
+    }
+
+    fn next(self) -> LineNumberMode {
+        match self {
+            LineNumberMode::Absolute => LineNumberMode::Relative,
+            LineNumberMode::Relative => LineNumberMode::Hybrid,
+            LineNumberMode::Hybrid => LineNumberMode::Absolute,
+        }
+    }
+}
+
+// Which column the status bar shows for the cursor.
+#[derive(Copy, Clone, PartialEq)]
+enum StatusColumn {
+    // The char index in the line.
+    Char,
+    // Where the cursor is on the screen, counting tabs and wide chars.
+    Screen,
+    // The screen column, and the char index too when it's different.
+    Both,
+}
+
+impl StatusColumn {
+    fn name(self) -> &'static str {
+        match self {
+            StatusColumn::Char => "char",
+            StatusColumn::Screen => "screen",
+            StatusColumn::Both => "both",
+        }
+    }
+}
+
+// Settings that can differ between buffers, eg. because of the filetype.
+#[derive(Clone)]
+struct Config {
+    tab_stop: usize,
+    // Whether Tab inserts indent_size spaces instead of a tab.
+    expand_tabs: bool,
+    indent_size: usize,
+    trim_trailing_whitespace: bool,
+    insert_final_newline: bool,
+    auto_save_secs: u64,
+    auto_save_on_focus_loss: bool,
+    backup_idle_secs: u64,
+    backup_suffix: String,
+    create_missing_files: bool,
+    set_title: bool,
+    line_ending: LineEnding,
+    soft_wrap: bool,
+    visual_line_movement: bool,
+    render_whitespace: bool,
+    list_mode: bool,
+    syntax_highlight: bool,
+    // (interpreter, filetype name) pairs.
+    interpreter_syntax: Vec<(String, String)>,
+    max_highlight_length: usize,
+    rainbow_brackets: bool,
+    defer_highlight: bool,
+    center_on_jump: bool,
+    newline_scroll_margin: usize,
+    // Give the line the cursor is on a different background.
+    cursor_line: bool,
+    highlight_search: bool,
+    smart_case: bool,
+    highlight_word: bool,
+    max_line_length: usize,
+    color_column: usize,
+    color_column_fill: bool,
+    warn_mixed_indent: bool,
+    gutter_links: bool,
+    persist_marks: bool,
+    line_number_mode: LineNumberMode,
+    status_column: StatusColumn,
+    // Drawn between the line numbers and the text.
+    gutter_separator: String,
+    gutter_padding_left: usize,
+    gutter_padding_right: usize,
+    empty_line_marker: String,
+    empty_line_marker_color: Color,
+    // How wide reflowed paragraphs are.
+    text_width: usize,
+    reflow_hanging_indent: bool,
+    unset_variable_is_error: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tab_stop: TAB_STOP,
+            expand_tabs: EXPAND_TABS,
+            indent_size: INDENT_SIZE,
+            trim_trailing_whitespace: TRIM_TRAILING_WHITESPACE,
+            insert_final_newline: INSERT_FINAL_NEWLINE,
+            auto_save_secs: AUTO_SAVE_SECS,
+            auto_save_on_focus_loss: AUTO_SAVE_ON_FOCUS_LOSS,
+            backup_idle_secs: BACKUP_IDLE_SECS,
+            backup_suffix: BACKUP_SUFFIX.to_string(),
+            create_missing_files: CREATE_MISSING_FILES,
+            set_title: SET_TITLE,
+            line_ending: LineEnding::Lf,
+            soft_wrap: SOFT_WRAP,
+            visual_line_movement: VISUAL_LINE_MOVEMENT,
+            render_whitespace: RENDER_WHITESPACE,
+            list_mode: LIST_MODE,
+            syntax_highlight: SYNTAX_HIGHLIGHT,
+            interpreter_syntax: parse_interpreter_syntax(INTERPRETER_SYNTAX).unwrap(),
+            max_highlight_length: MAX_HIGHLIGHT_LENGTH,
+            rainbow_brackets: RAINBOW_BRACKETS,
+            defer_highlight: DEFER_HIGHLIGHT,
+            center_on_jump: CENTER_ON_JUMP,
+            newline_scroll_margin: NEWLINE_SCROLL_MARGIN,
+            cursor_line: CURSOR_LINE,
+            highlight_search: HIGHLIGHT_SEARCH,
+            smart_case: SMART_CASE,
+            highlight_word: HIGHLIGHT_WORD,
+            max_line_length: MAX_LINE_LENGTH,
+            color_column: COLOR_COLUMN,
+            color_column_fill: COLOR_COLUMN_FILL,
+            warn_mixed_indent: WARN_MIXED_INDENT,
+            gutter_links: GUTTER_LINKS,
+            persist_marks: PERSIST_MARKS,
+            line_number_mode: LINE_NUMBER_MODE,
+            status_column: STATUS_COLUMN,
+            gutter_separator: GUTTER_SEPARATOR.to_string(),
+            gutter_padding_left: GUTTER_PADDING_LEFT,
+            gutter_padding_right: GUTTER_PADDING_RIGHT,
+            empty_line_marker: EMPTY_LINE_MARKER.to_string(),
+            empty_line_marker_color: EMPTY_LINE_MARKER_COLOR,
+            text_width: TEXT_WIDTH,
+            reflow_hanging_indent: REFLOW_HANGING_INDENT,
+            unset_variable_is_error: UNSET_VARIABLE_IS_ERROR,
+        }
+    }
+}
+
+// An option that can be looked at and changed with the set command.
+struct OptionInfo {
+    name: &'static str,
+    get: fn(&Config) -> String,
+    set: fn(&mut Config, &str) -> Result<(), String>,
+}
+
+const OPTIONS: [OptionInfo; 43] = [
+    OptionInfo {
+        name: "tab_stop",
+        get: |config| config.tab_stop.to_string(),
+        set: |config, value| {
+            let tab_stop = parse_number(value)?;
+            if tab_stop == 0 {
+                return Err("tab_stop must be at least 1".to_string());
+            }
+            config.tab_stop = tab_stop;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "expand_tabs",
+        get: |config| config.expand_tabs.to_string(),
+        set: |config, value| {
+            config.expand_tabs = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "indent_size",
+        get: |config| config.indent_size.to_string(),
+        set: |config, value| {
+            let indent_size = parse_number(value)?;
+            if indent_size == 0 {
+                return Err("indent_size must be at least 1".to_string());
+            }
+            config.indent_size = indent_size;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "trim_trailing_whitespace",
+        get: |config| config.trim_trailing_whitespace.to_string(),
+        set: |config, value| {
+            config.trim_trailing_whitespace = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "insert_final_newline",
+        get: |config| config.insert_final_newline.to_string(),
+        set: |config, value| {
+            config.insert_final_newline = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "auto_save_secs",
+        get: |config| config.auto_save_secs.to_string(),
+        set: |config, value| {
+            config.auto_save_secs = parse_number(value)? as u64;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "auto_save_on_focus_loss",
+        get: |config| config.auto_save_on_focus_loss.to_string(),
+        set: |config, value| {
+            config.auto_save_on_focus_loss = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "backup_idle_secs",
+        get: |config| config.backup_idle_secs.to_string(),
+        set: |config, value| {
+            config.backup_idle_secs = parse_number(value)? as u64;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "backup_suffix",
+        get: |config| config.backup_suffix.clone(),
+        set: |config, value| {
+            if value.is_empty() || value.contains('/') {
+                return Err("backup_suffix can't be empty or contain /".to_string());
+            }
+            config.backup_suffix = value.to_string();
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "create_missing_files",
+        get: |config| config.create_missing_files.to_string(),
+        set: |config, value| {
+            config.create_missing_files = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "set_title",
+        get: |config| config.set_title.to_string(),
+        set: |config, value| {
+            config.set_title = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "line_ending",
+        get: |config| config.line_ending.name().to_lowercase(),
+        set: |config, value| {
+            config.line_ending = match value {
+                "lf" => LineEnding::Lf,
+                "crlf" => LineEnding::CrLf,
+                "cr" => LineEnding::Cr,
+                _ => return Err(format!("Expected lf, crlf or cr, not {}", value)),
+            };
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "soft_wrap",
+        get: |config| config.soft_wrap.to_string(),
+        set: |config, value| {
+            config.soft_wrap = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "visual_line_movement",
+        get: |config| config.visual_line_movement.to_string(),
+        set: |config, value| {
+            config.visual_line_movement = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "render_whitespace",
+        get: |config| config.render_whitespace.to_string(),
+        set: |config, value| {
+            config.render_whitespace = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "list_mode",
+        get: |config| config.list_mode.to_string(),
+        set: |config, value| {
+            config.list_mode = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "syntax_highlight",
+        get: |config| config.syntax_highlight.to_string(),
+        set: |config, value| {
+            config.syntax_highlight = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "interpreter_syntax",
+        get: |config| {
+            config
+                .interpreter_syntax
+                .iter()
+                .map(|(interpreter, filetype)| format!("{}:{}", interpreter, filetype))
+                .collect::<Vec<_>>()
+                .join(",")
+        },
+        set: |config, value| {
+            config.interpreter_syntax = parse_interpreter_syntax(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "max_highlight_length",
+        get: |config| config.max_highlight_length.to_string(),
+        set: |config, value| {
+            config.max_highlight_length = parse_number(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "rainbow_brackets",
+        get: |config| config.rainbow_brackets.to_string(),
+        set: |config, value| {
+            config.rainbow_brackets = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "defer_highlight",
+        get: |config| config.defer_highlight.to_string(),
+        set: |config, value| {
+            config.defer_highlight = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "center_on_jump",
+        get: |config| config.center_on_jump.to_string(),
+        set: |config, value| {
+            config.center_on_jump = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "newline_scroll_margin",
+        get: |config| config.newline_scroll_margin.to_string(),
+        set: |config, value| {
+            config.newline_scroll_margin = parse_number(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "cursor_line",
+        get: |config| config.cursor_line.to_string(),
+        set: |config, value| {
+            config.cursor_line = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "highlight_search",
+        get: |config| config.highlight_search.to_string(),
+        set: |config, value| {
+            config.highlight_search = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "smart_case",
+        get: |config| config.smart_case.to_string(),
+        set: |config, value| {
+            config.smart_case = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "highlight_word",
+        get: |config| config.highlight_word.to_string(),
+        set: |config, value| {
+            config.highlight_word = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "max_line_length",
+        get: |config| config.max_line_length.to_string(),
+        set: |config, value| {
+            config.max_line_length = parse_number(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "color_column",
+        get: |config| config.color_column.to_string(),
+        set: |config, value| {
+            config.color_column = parse_number(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "color_column_fill",
+        get: |config| config.color_column_fill.to_string(),
+        set: |config, value| {
+            config.color_column_fill = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "warn_mixed_indent",
+        get: |config| config.warn_mixed_indent.to_string(),
+        set: |config, value| {
+            config.warn_mixed_indent = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "gutter_links",
+        get: |config| config.gutter_links.to_string(),
+        set: |config, value| {
+            config.gutter_links = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "persist_marks",
+        get: |config| config.persist_marks.to_string(),
+        set: |config, value| {
+            config.persist_marks = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "line_number_mode",
+        get: |config| config.line_number_mode.name().to_string(),
+        set: |config, value| {
+            config.line_number_mode = match value {
+                "absolute" => LineNumberMode::Absolute,
+                "relative" => LineNumberMode::Relative,
+                "hybrid" => LineNumberMode::Hybrid,
+                _ => {
+                    return Err(format!(
+                        "Expected absolute, relative or hybrid, not {}",
+                        value
+                    ))
+                }
+            };
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "status_column",
+        get: |config| config.status_column.name().to_string(),
+        set: |config, value| {
+            config.status_column = match value {
+                "char" => StatusColumn::Char,
+                "screen" => StatusColumn::Screen,
+                "both" => StatusColumn::Both,
+                _ => return Err(format!("Expected char, screen or both, not {}", value)),
+            };
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "gutter_separator",
+        get: |config| config.gutter_separator.clone(),
+        set: |config, value| {
+            config.gutter_separator = value.to_string();
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "gutter_padding_left",
+        get: |config| config.gutter_padding_left.to_string(),
+        set: |config, value| {
+            config.gutter_padding_left = parse_number(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "gutter_padding_right",
+        get: |config| config.gutter_padding_right.to_string(),
+        set: |config, value| {
+            config.gutter_padding_right = parse_number(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "empty_line_marker",
+        get: |config| config.empty_line_marker.clone(),
+        set: |config, value| {
+            config.empty_line_marker = value.to_string();
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "empty_line_marker_color",
+        get: |config| config.empty_line_marker_color.name().to_string(),
+        set: |config, value| {
+            config.empty_line_marker_color =
+                Color::from_name(value).ok_or_else(|| format!("Unknown color: {}", value))?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "text_width",
+        get: |config| config.text_width.to_string(),
+        set: |config, value| {
+            let text_width = parse_number(value)?;
+            if text_width == 0 {
+                return Err("text_width must be at least 1".to_string());
+            }
+            config.text_width = text_width;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "reflow_hanging_indent",
+        get: |config| config.reflow_hanging_indent.to_string(),
+        set: |config, value| {
+            config.reflow_hanging_indent = parse_bool(value)?;
+            Ok(())
+        },
+    },
+    OptionInfo {
+        name: "unset_variable_is_error",
+        get: |config| config.unset_variable_is_error.to_string(),
+        set: |config, value| {
+            config.unset_variable_is_error = parse_bool(value)?;
+            Ok(())
+        },
+    },
+];
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(format!("Expected true or false, not {}", value)),
+    }
+}
+
+// Parse "python3:python,node:javascript" into pairs. The interpreter is
+// matched like the ones in the filetypes, so "python" covers python3.11.
+fn parse_interpreter_syntax(value: &str) -> Result<Vec<(String, String)>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((interpreter, filetype))
+                if !interpreter.trim().is_empty() && !filetype.trim().is_empty() =>
+            {
+                Ok((interpreter.trim().to_string(), filetype.trim().to_string()))
+            }
+            _ => Err(format!("Expected interpreter:filetype, not {}", pair)),
+        })
+        .collect()
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Expected a number, not {}", value))
+}
+
+// Set an option from "name = value", "name" to turn it on or "name!" to
+// toggle it.
+fn set_config_option(config: &mut Config, argument: &str) -> Result<&'static OptionInfo, String> {
+    let (name, value) = if let Some(name) = argument.strip_suffix('!') {
+        (name, None)
+    } else if let Some((name, value)) = argument.split_once('=') {
+        (name.trim(), Some(value.trim()))
+    } else {
+        (argument, Some("true"))
+    };
+    let option = OPTIONS
+        .iter()
+        .find(|option| option.name == name)
+        .ok_or_else(|| format!("Unknown option: {}", name))?;
+
+    match value {
+        Some(value) => (option.set)(config, value)?,
+        None => {
+            let toggled = !parse_bool(&(option.get)(config))
+                .map_err(|_| format!("{} can't be toggled", name))?;
+            (option.set)(config, &toggled.to_string())?;
+        }
+    }
+    Ok(option)
+}
+
+// A config file has an option on each line, as it would be given to set.
+// Blank lines and lines starting with # are ignored.
+fn parse_config_file(contents: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        set_config_option(&mut config, line)
+            .map_err(|error| format!("line {}: {}", i + 1, error))?;
+    }
+    Ok(config)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 struct Position {
     x: usize,
     y: usize,
@@ -83,7 +967,44 @@ enum Color {
     Default,
 }
 
-#[derive(Copy, Clone, PartialEq)]
+impl Color {
+    const NAMES: [(&'static str, Color); 17] = [
+        ("black", Color::Black),
+        ("red", Color::Red),
+        ("green", Color::Green),
+        ("yellow", Color::Yellow),
+        ("blue", Color::Blue),
+        ("magenta", Color::Magenta),
+        ("cyan", Color::Cyan),
+        ("white", Color::White),
+        ("bright_black", Color::BrightBlack),
+        ("bright_red", Color::BrightRed),
+        ("bright_green", Color::BrightGreen),
+        ("bright_yellow", Color::BrightYellow),
+        ("bright_blue", Color::BrightBlue),
+        ("bright_magenta", Color::BrightMagenta),
+        ("bright_cyan", Color::BrightCyan),
+        ("bright_white", Color::BrightWhite),
+        ("default", Color::Default),
+    ];
+
+    fn name(self) -> &'static str {
+        Color::NAMES
+            .iter()
+            .find(|&&(_, color)| color == self)
+            .unwrap()
+            .0
+    }
+
+    fn from_name(name: &str) -> Option<Color> {
+        Color::NAMES
+            .iter()
+            .find(|&&(color_name, _)| color_name == name)
+            .map(|&(_, color)| color)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum Highlight {
     Normal,
     Number,
@@ -92,15 +1013,33 @@ enum Highlight {
     MultilineComment,
     Keyword1,
     Keyword2,
+    Keyword3,
+    Operator,
     Match,
+    Heading,
+    Emphasis,
+    Code,
+    Link,
+    ListBullet,
+    // The nesting depth of the bracket, modulo the number of bracket colors.
+    Bracket(u8),
 }
 
+const BRACKET_COLORS: [Color; 6] = [
+    Color::BrightYellow,
+    Color::BrightMagenta,
+    Color::BrightCyan,
+    Color::BrightGreen,
+    Color::BrightBlue,
+    Color::BrightRed,
+];
+
 enum KeypressResult {
     Continue,
     Terminate,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Arrow {
     Left,
     Right,
@@ -108,1491 +1047,9497 @@ enum Arrow {
     Down,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Key {
     Char(char),
     Ctrl(char),
+    // Sent by the terminal as an escape followed by the char.
+    Alt(char),
     Arrow(Arrow),
     PageUp,
     PageDown,
     Home,
     End,
+    Insert,
     Delete,
     Backspace,
     Esc,
     Enter,
+    Tab,
+    // Sent by the terminal when focus reporting is on.
+    FocusIn,
+    FocusOut,
 }
 
-// *** Filetypes ***
-
-const HIGHLIGHT_NUMBERS: u32 = 1 << 0;
-const HIGHLIGHT_STRINGS: u32 = 1 << 1;
-
-struct Filetype {
-    name: &'static str,
-    filename_patterns: &'static [&'static str],
-    singleline_comment_start: &'static str,
-    multiline_comment_start: &'static str,
-    multiline_comment_end: &'static str,
-    keywords1: &'static [&'static str],
-    keywords2: &'static [&'static str],
-    flags: u32,
-}
-const FILETYPES: [Filetype; 3] = [
-    Filetype {
-        name: "c",
-        filename_patterns: &[".c", ".h", ".cpp"],
-        singleline_comment_start: "//",
-        multiline_comment_start: "/*",
-        multiline_comment_end: "*/",
-        keywords1: &[
-            "switch", "if", "while", "for", "break", "continue", "return", "else", "struct",
-            "union", "typedef", "static", "enum", "class", "case",
-        ],
-        keywords2: &[
-            "int", "long", "double", "float", "char", "unsigned", "signed", "void",
-        ],
-        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
-    },
-    Filetype {
-        name: "rust",
-        filename_patterns: &[".rs"],
-        singleline_comment_start: "//",
-        multiline_comment_start: "/*",
-        multiline_comment_end: "*/",
-        keywords1: &[
-            "if", "while", "for", "loop", "break", "continue", "return", "else", "match", "mut",
-            "fn", "move", "in", "as", "impl", "where", "use",
-        ],
-        keywords2: &["let", "struct", "const", "enum"],
-        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
-    },
-    Filetype {
-        name: "python",
-        filename_patterns: &[".py"],
-        singleline_comment_start: "#",
-        multiline_comment_start: "",
-        multiline_comment_end: "",
-        keywords1: &[
-            "import", "from", "yield", "return", "if", "elif", "else", "while", "for", "in", "is",
-            "not", "and", "or",
-        ],
-        keywords2: &[
-            "def",
-            "str",
-            "set",
-            "dict",
-            "list",
-            "float",
-            "int",
-            "bool",
-            "print",
-            "enumerate",
-            "len",
-            "input",
-            "reversed",
-        ],
-        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
-    },
-];
-
-fn is_separator(c: char) -> bool {
-    c.is_whitespace() || "&,.()+-/*=~%<>[]; ".contains(c)
+fn key_name(key: Key) -> String {
+    match key {
+        Key::Char(c) => c.to_string(),
+        // Ctrl-Space sends a null byte, which reads as Ctrl-`.
+        Key::Ctrl('`') => "Ctrl-Space".to_string(),
+        Key::Ctrl(c) => format!("Ctrl-{}", c.to_ascii_uppercase()),
+        Key::Alt(c) => format!("Alt-{}", c),
+        key => format!("{:?}", key),
+    }
 }
 
-struct Row {
-    chars: String,
-    render: String,
-    highlight: Vec<Highlight>,
-    continue_multiline_comment: bool,
-    continue_multiline_string: Option<char>,
+#[derive(Clone, Copy)]
+enum Case {
+    Upper,
+    Lower,
+    // Uppercase the first char and lowercase the rest.
+    Title,
 }
 
-impl Row {
-    fn zip(&self) -> Vec<(char, usize, char, Highlight)> {
-        let mut result = Vec::new();
-
-        let mut render_iter = self.render.chars();
-        let mut render_length = 0;
-        let mut highlight_iter = self.highlight.iter();
-
-        for (i, c) in self.chars.chars().enumerate() {
-            if c == '\t' {
-                let mut tab_size = TAB_STOP - (render_length % TAB_STOP);
-                while tab_size > 0 {
-                    result.push((
-                        c,
-                        i,
-                        render_iter.next().unwrap(),
-                        *highlight_iter.next().unwrap(),
-                    ));
-                    render_length += 1;
-                    tab_size -= 1;
-                }
-            } else if c.is_control() {
-                result.push((
-                    c,
-                    i,
-                    render_iter.next().unwrap(),
-                    *highlight_iter.next().unwrap(),
-                ));
-                render_length += 1;
-            } else {
-                result.push((
-                    c,
-                    i,
-                    render_iter.next().unwrap(),
-                    *highlight_iter.next().unwrap(),
-                ));
-                render_length += 1;
-                for _ in 0..UnicodeWidthChar::width(c).unwrap_or(1) - 1 {
-                    render_length += 1;
+impl Case {
+    fn apply(self, word: &str) -> String {
+        match self {
+            Case::Upper => word.to_uppercase(),
+            Case::Lower => word.to_lowercase(),
+            Case::Title => {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first
+                        .to_uppercase()
+                        .chain(chars.as_str().to_lowercase().chars())
+                        .collect(),
+                    None => String::new(),
                 }
             }
         }
-        result
     }
 }
 
-struct Editor {
-    screen_dimensions: Dimensions,
-    cursor_position: Position,
-    input: Receiver<char>,
-    text_offset: Position,
-    rows: Vec<Row>,
-    filename: Option<String>,
-    filetype: Option<&'static Filetype>,
-    status_message: String,
-    status_message_time: Instant,
-    dirty: bool,
-    quit_times: u8,
-    matches: Vec<usize>,
-    match_index: usize,
-    saved_highlight: Vec<Highlight>,
-    saved_highlight_index: usize,
+// *** Actions ***
+
+// Things that can be done from a key or the command palette.
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Save,
+    Find,
+    Quit,
+    CommandPalette,
+    CommandLine,
+    Reflow,
+    QuotedInsert,
+    ClearSearchHighlight,
+    LineStart,
+    LineEnd,
+    HalfPageUp,
+    HalfPageDown,
+    CenterView,
+    Suspend,
+    ToggleSoftWrap,
+    ToggleRenderWhitespace,
+    ToggleCursorLine,
+    ToggleWordHighlight,
+    CycleLineNumberMode,
+    JumpBack,
+    JumpForward,
+    SetMark,
+    JumpToMark,
+    ToggleSplit,
+    SwitchPane,
+    ScrollPageLeft,
+    ScrollPageRight,
+    FirstNonBlank,
+    LastNonBlank,
+    ToggleCsvView,
+    ToggleListMode,
+    NextLongLine,
+    ToggleOverwrite,
+    TransposeChars,
+    TransposeWords,
+    UppercaseWord,
+    LowercaseWord,
+    TitlecaseWord,
+    RepeatChange,
+    JumpToWord,
+    CountMatches,
+    ToggleSyntaxHighlight,
+    OpenRecentFile,
+    CompleteWord,
+    OpenLineBelow,
+    OpenLineAbove,
+    ToggleSelection,
+    DuplicateLines,
+    ToggleComment,
+    GotoLine,
+    Replace,
+    GotoDefinition,
 }
 
-impl Editor {
-    fn new() -> Editor {
-        let mut screen_dimensions = get_window_size();
-        screen_dimensions.rows -= 2; // Make room for status bar and message.
+impl Action {
+    // Whether the action edits the buffer, so that it can be repeated.
+    fn is_change(self) -> bool {
+        matches!(
+            self,
+            Action::Reflow
+                | Action::TransposeChars
+                | Action::TransposeWords
+                | Action::UppercaseWord
+                | Action::LowercaseWord
+                | Action::TitlecaseWord
+                | Action::OpenLineBelow
+                | Action::OpenLineAbove
+                | Action::DuplicateLines
+                | Action::ToggleComment
+        )
+    }
+}
 
-        Editor {
-            screen_dimensions,
+// The last change, which can be repeated. It's either a run of typing
+// (chars, Enter, Tab, Backspace and Delete with no other keys in between)
+// or one editing action.
+#[derive(Clone)]
+enum Change {
+    Typing(Vec<Key>),
+    Action(Action),
+}
+
+struct ActionInfo {
+    action: Action,
+    name: &'static str,
+    key: Option<Key>,
+}
+
+// All actions, with the key each is bound to. The command palette and
+// welcome screen read this, so it's the one place to add an action.
+const ACTIONS: [ActionInfo; 52] = [
+    ActionInfo {
+        action: Action::Save,
+        name: "save",
+        key: Some(Key::Ctrl('s')),
+    },
+    ActionInfo {
+        action: Action::Find,
+        name: "find",
+        key: Some(Key::Ctrl('r')),
+    },
+    ActionInfo {
+        action: Action::Quit,
+        name: "quit",
+        key: Some(Key::Ctrl('q')),
+    },
+    ActionInfo {
+        action: Action::CommandPalette,
+        name: "command palette",
+        key: Some(Key::Ctrl('p')),
+    },
+    ActionInfo {
+        action: Action::CommandLine,
+        name: "command line",
+        key: Some(Key::Alt('x')),
+    },
+    ActionInfo {
+        action: Action::Reflow,
+        name: "reflow paragraph",
+        key: Some(Key::Ctrl('j')),
+    },
+    ActionInfo {
+        action: Action::QuotedInsert,
+        name: "insert literally",
+        key: Some(Key::Ctrl('v')),
+    },
+    ActionInfo {
+        action: Action::ClearSearchHighlight,
+        name: "clear search highlight",
+        key: Some(Key::Ctrl('l')),
+    },
+    ActionInfo {
+        action: Action::LineStart,
+        name: "go to start of line",
+        key: Some(Key::Ctrl('a')),
+    },
+    ActionInfo {
+        action: Action::LineEnd,
+        name: "go to end of line",
+        key: Some(Key::Ctrl('e')),
+    },
+    ActionInfo {
+        action: Action::HalfPageUp,
+        name: "scroll half a page up",
+        key: Some(Key::Ctrl('u')),
+    },
+    ActionInfo {
+        action: Action::HalfPageDown,
+        name: "scroll half a page down",
+        key: Some(Key::Ctrl('d')),
+    },
+    ActionInfo {
+        action: Action::CenterView,
+        name: "center current line",
+        key: Some(Key::Alt('z')),
+    },
+    ActionInfo {
+        action: Action::Suspend,
+        name: "suspend",
+        key: Some(Key::Ctrl('z')),
+    },
+    ActionInfo {
+        action: Action::ToggleSoftWrap,
+        name: "toggle soft wrap",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::ToggleRenderWhitespace,
+        name: "toggle showing whitespace",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::ToggleCursorLine,
+        name: "toggle cursor line highlight",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::ToggleWordHighlight,
+        name: "toggle highlighting the word under the cursor",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::CycleLineNumberMode,
+        name: "cycle line numbers between absolute, relative and hybrid",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::JumpBack,
+        name: "jump back",
+        key: Some(Key::Ctrl('o')),
+    },
+    ActionInfo {
+        action: Action::JumpForward,
+        name: "jump forward",
+        // Ctrl-I is Tab.
+        key: Some(Key::Alt('i')),
+    },
+    ActionInfo {
+        action: Action::SetMark,
+        name: "set mark",
+        key: Some(Key::Alt('m')),
+    },
+    ActionInfo {
+        action: Action::JumpToMark,
+        name: "jump to mark",
+        key: Some(Key::Alt('\'')),
+    },
+    ActionInfo {
+        action: Action::ToggleSplit,
+        name: "toggle split screen",
+        key: Some(Key::Alt('s')),
+    },
+    ActionInfo {
+        action: Action::SwitchPane,
+        name: "switch pane",
+        key: Some(Key::Alt('w')),
+    },
+    ActionInfo {
+        action: Action::ScrollPageLeft,
+        name: "scroll left by a screenful",
+        key: Some(Key::Alt(',')),
+    },
+    ActionInfo {
+        action: Action::ScrollPageRight,
+        name: "scroll right by a screenful",
+        key: Some(Key::Alt('.')),
+    },
+    ActionInfo {
+        action: Action::FirstNonBlank,
+        name: "go to the first non-blank on the screen line",
+        key: Some(Key::Alt('a')),
+    },
+    ActionInfo {
+        action: Action::LastNonBlank,
+        name: "go to the last non-blank on the screen line",
+        key: Some(Key::Alt('e')),
+    },
+    ActionInfo {
+        action: Action::ToggleCsvView,
+        name: "toggle lining up CSV columns",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::ToggleListMode,
+        name: "toggle showing invisible characters",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::NextLongLine,
+        name: "go to the next line longer than max_line_length",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::ToggleOverwrite,
+        name: "toggle overwrite mode",
+        key: Some(Key::Insert),
+    },
+    ActionInfo {
+        action: Action::TransposeChars,
+        name: "transpose characters",
+        key: Some(Key::Ctrl('t')),
+    },
+    ActionInfo {
+        action: Action::TransposeWords,
+        name: "transpose words",
+        key: Some(Key::Alt('t')),
+    },
+    ActionInfo {
+        action: Action::UppercaseWord,
+        name: "uppercase word",
+        key: Some(Key::Alt('u')),
+    },
+    ActionInfo {
+        action: Action::LowercaseWord,
+        name: "lowercase word",
+        key: Some(Key::Alt('l')),
+    },
+    ActionInfo {
+        action: Action::TitlecaseWord,
+        name: "titlecase word",
+        key: Some(Key::Alt('c')),
+    },
+    ActionInfo {
+        action: Action::RepeatChange,
+        name: "repeat last change",
+        key: Some(Key::Ctrl('y')),
+    },
+    ActionInfo {
+        action: Action::JumpToWord,
+        name: "jump to a word on the screen",
+        key: Some(Key::Alt('j')),
+    },
+    ActionInfo {
+        action: Action::CountMatches,
+        name: "count matches on each line",
+        key: Some(Key::Alt('n')),
+    },
+    ActionInfo {
+        action: Action::ToggleSyntaxHighlight,
+        name: "toggle syntax highlighting",
+        key: Some(Key::Alt('h')),
+    },
+    ActionInfo {
+        action: Action::OpenRecentFile,
+        name: "open a recent file",
+        key: None,
+    },
+    ActionInfo {
+        action: Action::CompleteWord,
+        name: "complete word",
+        key: Some(Key::Ctrl('n')),
+    },
+    ActionInfo {
+        action: Action::OpenLineBelow,
+        name: "open a line below",
+        key: Some(Key::Alt('o')),
+    },
+    ActionInfo {
+        action: Action::OpenLineAbove,
+        name: "open a line above",
+        key: Some(Key::Alt('O')),
+    },
+    ActionInfo {
+        action: Action::ToggleSelection,
+        name: "start or clear a selection",
+        key: Some(Key::Ctrl('`')),
+    },
+    ActionInfo {
+        action: Action::DuplicateLines,
+        name: "duplicate line or selected lines",
+        key: Some(Key::Alt('d')),
+    },
+    ActionInfo {
+        action: Action::ToggleComment,
+        name: "comment or uncomment line or selected lines",
+        key: Some(Key::Alt(';')),
+    },
+    ActionInfo {
+        action: Action::GotoLine,
+        name: "go to line",
+        key: Some(Key::Ctrl('g')),
+    },
+    ActionInfo {
+        action: Action::Replace,
+        name: "replace all",
+        key: Some(Key::Alt('r')),
+    },
+    ActionInfo {
+        action: Action::GotoDefinition,
+        name: "go to definition",
+        key: Some(Key::Alt('g')),
+    },
+];
+
+fn action_info(action: Action) -> &'static ActionInfo {
+    ACTIONS.iter().find(|info| info.action == action).unwrap()
+}
+
+// Score how well a query matches a name, if its chars all appear in order.
+// Runs of chars and the starts of words score higher.
+fn fuzzy_score(query: &str, name: &str) -> Option<usize> {
+    let name: Vec<char> = name.chars().collect();
+    let mut score = 0;
+    let mut i = 0;
+    let mut prev_match: Option<usize> = None;
+    for q in query.chars() {
+        while !name.get(i)?.eq_ignore_ascii_case(&q) {
+            i += 1;
+        }
+        score += 1;
+        if prev_match.is_some_and(|prev| prev + 1 == i) {
+            score += 4;
+        }
+        if i == 0 || name[i - 1] == ' ' {
+            score += 2;
+        }
+        prev_match = Some(i);
+        i += 1;
+    }
+    Some(score)
+}
+
+// The actions matching a query, best first.
+fn palette_matches(query: &str) -> Vec<&'static ActionInfo> {
+    let mut matches: Vec<(usize, &ActionInfo)> = ACTIONS
+        .iter()
+        .filter_map(|info| Some((fuzzy_score(query, info.name)?, info)))
+        .collect();
+    matches.sort_by_key(|&(score, _)| cmp::Reverse(score));
+    matches.into_iter().map(|(_, info)| info).collect()
+}
+
+// *** EditorConfig ***
+
+// Match a path against an EditorConfig glob.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            (0..=text.len()).any(|i| glob_match(&pattern[2..], &text[i..]))
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(&pattern[1..], &text[i..])),
+        Some('?') => !text.is_empty() && text[0] != '/' && glob_match(&pattern[1..], &text[1..]),
+        Some('[') => match pattern.iter().position(|&c| c == ']') {
+            Some(end) if !text.is_empty() => {
+                let (negated, class) = match pattern[1..end].split_first() {
+                    Some(('!', rest)) => (true, rest),
+                    _ => (false, &pattern[1..end]),
+                };
+                let mut matched = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == '-' {
+                        matched |= (class[i]..=class[i + 2]).contains(&text[0]);
+                        i += 3;
+                    } else {
+                        matched |= class[i] == text[0];
+                        i += 1;
+                    }
+                }
+                matched != negated && glob_match(&pattern[end + 1..], &text[1..])
+            }
+            _ => !text.is_empty() && text[0] == '[' && glob_match(&pattern[1..], &text[1..]),
+        },
+        Some('{') => {
+            // Expand {a,b} into each of its alternatives.
+            let end = match pattern.iter().position(|&c| c == '}') {
+                Some(end) => end,
+                None => return text.first() == Some(&'{') && glob_match(&pattern[1..], &text[1..]),
+            };
+            pattern[1..end].split(|&c| c == ',').any(|alternative| {
+                let expanded: Vec<char> = alternative
+                    .iter()
+                    .chain(&pattern[end + 1..])
+                    .copied()
+                    .collect();
+                glob_match(&expanded, text)
+            })
+        }
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(&c) => text.first() == Some(&c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Check whether an EditorConfig section name applies to a path relative to
+// the directory containing the .editorconfig file.
+fn section_matches(section: &str, relative_path: &str) -> bool {
+    let pattern = if section.contains('/') {
+        section.trim_start_matches('/').to_string()
+    } else {
+        // Sections without a slash can match a file in any subdirectory.
+        format!("**/{}", section)
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let with_root: Vec<char> = format!("/{}", relative_path).chars().collect();
+    let path: Vec<char> = relative_path.chars().collect();
+    glob_match(&pattern, &path) || glob_match(&pattern, &with_root)
+}
+
+impl Config {
+    fn apply_editorconfig(&mut self, properties: &[(String, String)]) {
+        let get = |key: &str| {
+            properties
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.as_str())
+        };
+
+        match get("indent_style") {
+            Some("tab") => self.expand_tabs = false,
+            Some("space") => self.expand_tabs = true,
+            _ => {}
+        }
+
+        let tab_width = get("tab_width").and_then(|value| value.parse().ok());
+        let indent_size = match get("indent_size") {
+            Some("tab") => Some(tab_width.unwrap_or(self.tab_stop)),
+            Some(value) => value.parse().ok(),
+            None => None,
+        };
+        if let Some(indent_size) = indent_size.filter(|&size| size > 0) {
+            self.indent_size = indent_size;
+        }
+        // The tab width defaults to the indent size.
+        if let Some(tab_width) = tab_width.or(indent_size).filter(|&width| width > 0) {
+            self.tab_stop = tab_width;
+        }
+
+        match get("trim_trailing_whitespace") {
+            Some("true") => self.trim_trailing_whitespace = true,
+            Some("false") => self.trim_trailing_whitespace = false,
+            _ => {}
+        }
+        match get("insert_final_newline") {
+            Some("true") => self.insert_final_newline = true,
+            Some("false") => self.insert_final_newline = false,
+            _ => {}
+        }
+        match get("end_of_line") {
+            Some("lf") => self.line_ending = LineEnding::Lf,
+            Some("crlf") => self.line_ending = LineEnding::CrLf,
+            Some("cr") => self.line_ending = LineEnding::Cr,
+            _ => {}
+        }
+    }
+}
+
+// Find the EditorConfig properties for a file from the .editorconfig files in
+// its directory and its ancestors. Closer files take precedence.
+fn editorconfig_properties(filename: &str) -> Vec<(String, String)> {
+    let path = match env::current_dir() {
+        Ok(dir) => dir.join(filename),
+        Err(_) => return Vec::new(),
+    };
+
+    let mut files = Vec::new();
+    for dir in path.ancestors().skip(1) {
+        let contents = match std::fs::read_to_string(dir.join(".editorconfig")) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let is_root = contents.lines().any(|line| {
+            line.split_once('=').is_some_and(|(key, value)| {
+                key.trim().eq_ignore_ascii_case("root") && value.trim().eq_ignore_ascii_case("true")
+            })
+        });
+        files.push((dir.to_path_buf(), contents));
+        if is_root {
+            break;
+        }
+    }
+
+    let mut properties: Vec<(String, String)> = Vec::new();
+    for (dir, contents) in files.iter().rev() {
+        let relative_path = match path.strip_prefix(dir) {
+            Ok(relative_path) => relative_path.to_string_lossy().to_string(),
+            Err(_) => continue,
+        };
+        let mut in_matching_section = false;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                in_matching_section = section_matches(&line[1..line.len() - 1], &relative_path);
+            } else if let Some((key, value)) = line.split_once('=') {
+                if in_matching_section {
+                    let key = key.trim().to_lowercase();
+                    let value = value.trim().to_lowercase();
+                    properties.retain(|(k, _)| *k != key);
+                    properties.push((key, value));
+                }
+            }
+        }
+    }
+    properties
+}
+
+// *** Paths ***
+
+fn home_directory(user: &str) -> Option<String> {
+    if user.is_empty() {
+        if let Ok(home) = env::var("HOME") {
+            return Some(home);
+        }
+        let user = User::from_uid(unistd::getuid()).ok()??;
+        return Some(user.dir.to_string_lossy().into_owned());
+    }
+    let user = User::from_name(user).ok()??;
+    Some(user.dir.to_string_lossy().into_owned())
+}
+
+// The config file to use if none is given.
+fn default_config_path() -> Option<String> {
+    Some(format!("{}/.kilorc", home_directory("")?))
+}
+
+// Load the config from the file given with --config or $KILO_CONFIG, which
+// has to exist, or else from ~/.kilorc if there is one.
+fn load_config(path: Option<String>) -> Result<Config, String> {
+    let explicit = path.or_else(|| env::var("KILO_CONFIG").ok().filter(|path| !path.is_empty()));
+    let path = match explicit.clone().or_else(default_config_path) {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if explicit.is_none() && error.kind() == io::ErrorKind::NotFound => {
+            return Ok(Config::default())
+        }
+        Err(error) => return Err(format!("can't read config file {}: {}", path, error)),
+    };
+    parse_config_file(&contents).map_err(|error| format!("{}: {}", path, error))
+}
+
+// Where files that remember things between sessions go.
+fn state_directory() -> Option<String> {
+    let state_home = match env::var("XDG_STATE_HOME") {
+        Ok(state_home) if !state_home.is_empty() => state_home,
+        _ => format!("{}/.local/state", home_directory("")?),
+    };
+    Some(format!("{}/kilo", state_home))
+}
+
+// The recently opened files, most recent first.
+fn read_recent_files() -> Vec<String> {
+    state_directory()
+        .and_then(|directory| std::fs::read_to_string(format!("{}/recent", directory)).ok())
+        .map(|contents| contents.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn write_recent_files(files: &[String]) {
+    let directory = match state_directory() {
+        Some(directory) => directory,
+        None => return,
+    };
+    let _ = std::fs::create_dir_all(&directory);
+    let contents: String = files.iter().map(|file| format!("{}\n", file)).collect();
+    let _ = std::fs::write(format!("{}/recent", directory), contents);
+}
+
+// Expand a leading ~ or ~user and any $VAR or ${VAR} references in a
+// filename. Variables that aren't set are empty unless unset_is_error.
+fn expand_path(path: &str, unset_is_error: bool) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = path;
+
+    if let Some(tilde_rest) = path.strip_prefix('~') {
+        let user_end = tilde_rest.find('/').unwrap_or(tilde_rest.len());
+        let user = &tilde_rest[..user_end];
+        match home_directory(user) {
+            Some(home) => expanded.push_str(home.trim_end_matches('/')),
+            None => return Err(format!("No home directory for ~{}", user)),
+        }
+        rest = &tilde_rest[user_end..];
+    }
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(format!("Unterminated ${{ in {}", path)),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if name.is_empty() {
+                // A lone $ is just a character.
+                expanded.push('$');
+                continue;
+            }
+        }
+
+        match env::var(&name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) if unset_is_error => {
+                return Err(format!("Environment variable {} is not set", name))
+            }
+            Err(_) => {}
+        }
+    }
+
+    Ok(expanded)
+}
+
+// The filenames that what's been typed of a path could be completed to, with
+// a / on the end of directories.
+fn complete_path(typed: &str) -> Vec<String> {
+    if typed == "~" {
+        return vec!["~/".to_string()];
+    }
+    let (directory, name_prefix) = match typed.rfind('/') {
+        Some(i) => typed.split_at(i + 1),
+        None => ("", typed),
+    };
+    // Completions are only suggestions, so an unset variable is just left out.
+    let search_directory = if directory.is_empty() { "." } else { directory };
+    let search_directory = match expand_path(search_directory, false) {
+        Ok(search_directory) => search_directory,
+        Err(_) => return Vec::new(),
+    };
+    let entries = match std::fs::read_dir(search_directory) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            // Hidden files only show up if asked for.
+            if !name.starts_with(name_prefix)
+                || (name.starts_with('.') && !name_prefix.starts_with('.'))
+            {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", directory, name, slash))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+fn common_prefix(strings: &[String]) -> &str {
+    let first = match strings.first() {
+        Some(first) => first,
+        None => return "",
+    };
+    let mut length = first.len();
+    for string in &strings[1..] {
+        length = first
+            .char_indices()
+            .zip(string.chars())
+            .take_while(|((i, a), b)| *i < length && a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0);
+    }
+    &first[..length]
+}
+
+// *** Filetypes ***
+
+const HIGHLIGHT_NUMBERS: u32 = 1 << 0;
+const HIGHLIGHT_STRINGS: u32 = 1 << 1;
+// Markdown doesn't fit the keyword/comment model so it has its own highlighter.
+const HIGHLIGHT_MARKDOWN: u32 = 1 << 2;
+
+// Config settings a filetype overrides.
+struct FiletypeOptions {
+    tab_stop: Option<usize>,
+    expand_tabs: Option<bool>,
+    indent_size: Option<usize>,
+    // Always indent with tabs, even if an EditorConfig file says otherwise,
+    // because the file doesn't work without them.
+    require_tabs: bool,
+    soft_wrap: Option<bool>,
+    render_whitespace: Option<bool>,
+}
+
+impl FiletypeOptions {
+    const NONE: FiletypeOptions = FiletypeOptions {
+        tab_stop: None,
+        expand_tabs: None,
+        indent_size: None,
+        require_tabs: false,
+        soft_wrap: None,
+        render_whitespace: None,
+    };
+
+    fn apply(&self, config: &mut Config) {
+        if let Some(tab_stop) = self.tab_stop {
+            config.tab_stop = tab_stop;
+        }
+        if let Some(expand_tabs) = self.expand_tabs {
+            config.expand_tabs = expand_tabs;
+        }
+        if let Some(indent_size) = self.indent_size {
+            config.indent_size = indent_size;
+        }
+        if let Some(soft_wrap) = self.soft_wrap {
+            config.soft_wrap = soft_wrap;
+        }
+        if let Some(render_whitespace) = self.render_whitespace {
+            config.render_whitespace = render_whitespace;
+        }
+    }
+}
+
+struct Filetype {
+    name: &'static str,
+    // Extensions like ".c", globs like "*.config" and whole names like
+    // "Makefile". Globs and names are matched against the basename.
+    filename_patterns: &'static [&'static str],
+    // Interpreters named in a `#!` line, for files without a known extension.
+    interpreters: &'static [&'static str],
+    singleline_comment_start: &'static str,
+    multiline_comment_start: &'static str,
+    multiline_comment_end: &'static str,
+    keywords1: &'static [&'static str],
+    keywords2: &'static [&'static str],
+    // Built-in constants and types.
+    keywords3: &'static [&'static str],
+    // Operators and punctuation, highlighted wherever they appear outside
+    // strings and comments.
+    operators: &'static [&'static str],
+    // Regexes for a line that defines {name}, for going to a definition.
+    definition_patterns: &'static [&'static str],
+    flags: u32,
+    options: FiletypeOptions,
+}
+const FILETYPES: [Filetype; 8] = [
+    Filetype {
+        name: "c",
+        filename_patterns: &[".c", ".h", ".cpp"],
+        interpreters: &[],
+        singleline_comment_start: "//",
+        multiline_comment_start: "/*",
+        multiline_comment_end: "*/",
+        keywords1: &[
+            "switch", "if", "while", "for", "break", "continue", "return", "else", "struct",
+            "union", "typedef", "static", "enum", "class", "case",
+        ],
+        keywords2: &[
+            "int", "long", "double", "float", "char", "unsigned", "signed", "void",
+        ],
+        keywords3: &["NULL", "true", "false", "size_t", "bool", "FILE", "EOF"],
+        operators: &[
+            "->", "++", "--", "==", "!=", "<=", ">=", "&&", "||", "<<", ">>", "+", "-", "*", "/",
+            "%", "=", "<", ">", "!", "&", "|", "^", "~", "?", ":",
+        ],
+        definition_patterns: &[
+            r"^\s*#\s*define\s+{name}\b",
+            r"\b(struct|union|enum)\s+{name}\s*(\{|$)",
+            r"^}\s*{name}\s*;",
+            r"^[A-Za-z_][\w\s*]*\b{name}\s*\([^;]*$",
+        ],
+        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
+        options: FiletypeOptions::NONE,
+    },
+    Filetype {
+        name: "rust",
+        filename_patterns: &[".rs"],
+        interpreters: &[],
+        singleline_comment_start: "//",
+        multiline_comment_start: "/*",
+        multiline_comment_end: "*/",
+        keywords1: &[
+            "if", "while", "for", "loop", "break", "continue", "return", "else", "match", "mut",
+            "fn", "move", "in", "as", "impl", "where", "use",
+        ],
+        keywords2: &["let", "struct", "const", "enum"],
+        keywords3: &[
+            "true", "false", "Some", "None", "Ok", "Err", "Self", "self", "String", "Vec",
+            "Option", "Result", "Box", "str", "bool", "char", "u8", "u16", "u32", "u64", "usize",
+            "i8", "i16", "i32", "i64", "isize", "f32", "f64",
+        ],
+        operators: &[
+            "::", "->", "=>", "==", "!=", "<=", ">=", "&&", "||", "..", "+", "-", "*", "/", "%",
+            "=", "<", ">", "!", "&", "|", "^", "?",
+        ],
+        definition_patterns: &[
+            r"\b(fn|struct|enum|union|trait|type|const|static|mod)\s+{name}\b",
+            r"\blet\s+(mut\s+)?{name}\b",
+            r"\bmacro_rules!\s*{name}\b",
+        ],
+        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
+        options: FiletypeOptions {
+            expand_tabs: Some(true),
+            indent_size: Some(4),
+            ..FiletypeOptions::NONE
+        },
+    },
+    Filetype {
+        name: "python",
+        filename_patterns: &[".py"],
+        interpreters: &["python"],
+        singleline_comment_start: "#",
+        multiline_comment_start: "",
+        multiline_comment_end: "",
+        keywords1: &[
+            "import", "from", "yield", "return", "if", "elif", "else", "while", "for", "in", "is",
+            "not", "and", "or",
+        ],
+        keywords2: &[
+            "def",
+            "str",
+            "set",
+            "dict",
+            "list",
+            "float",
+            "int",
+            "bool",
+            "print",
+            "enumerate",
+            "len",
+            "input",
+            "reversed",
+        ],
+        keywords3: &["True", "False", "None", "self"],
+        operators: &[
+            "**", "//", "==", "!=", "<=", ">=", "->", "+", "-", "*", "/", "%", "=", "<", ">", "&",
+            "|", "^", "~", "@",
+        ],
+        definition_patterns: &[
+            r"^\s*(async\s+)?(def|class)\s+{name}\b",
+            r"^{name}\s*(:[^=]*)?=[^=]",
+        ],
+        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
+        options: FiletypeOptions {
+            expand_tabs: Some(true),
+            indent_size: Some(4),
+            ..FiletypeOptions::NONE
+        },
+    },
+    Filetype {
+        name: "shell",
+        filename_patterns: &[
+            ".sh",
+            ".bash",
+            ".zsh",
+            ".bashrc",
+            ".bash_profile",
+            ".bash_aliases",
+            ".profile",
+            ".zshrc",
+            ".zprofile",
+        ],
+        interpreters: &["sh", "bash", "zsh", "dash", "ksh"],
+        singleline_comment_start: "#",
+        multiline_comment_start: "",
+        multiline_comment_end: "",
+        keywords1: &[
+            "if", "then", "else", "elif", "fi", "case", "esac", "for", "while", "until", "do",
+            "done", "in", "function", "return", "break", "continue",
+        ],
+        keywords2: &[
+            "export", "local", "readonly", "declare", "echo", "set", "unset", "shift", "exit",
+        ],
+        keywords3: &["true", "false"],
+        operators: &["&&", "||", ";;", "|", "&", ";", "<", ">", "="],
+        definition_patterns: &[
+            r"^\s*(function\s+)?{name}\s*\(\)",
+            r"^\s*function\s+{name}\b",
+            r"^\s*(local\s+|export\s+|readonly\s+)?{name}=",
+        ],
+        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
+        options: FiletypeOptions::NONE,
+    },
+    Filetype {
+        name: "go",
+        filename_patterns: &[".go"],
+        interpreters: &[],
+        singleline_comment_start: "//",
+        multiline_comment_start: "/*",
+        multiline_comment_end: "*/",
+        keywords1: &[
+            "if",
+            "else",
+            "for",
+            "range",
+            "switch",
+            "case",
+            "default",
+            "break",
+            "continue",
+            "return",
+            "go",
+            "defer",
+            "select",
+            "fallthrough",
+            "goto",
+            "func",
+            "package",
+            "import",
+        ],
+        keywords2: &[
+            "var",
+            "const",
+            "type",
+            "struct",
+            "interface",
+            "map",
+            "chan",
+            "int",
+            "string",
+            "bool",
+            "byte",
+            "rune",
+            "error",
+        ],
+        keywords3: &[
+            "true", "false", "nil", "iota", "len", "cap", "make", "new", "append", "panic",
+        ],
+        operators: &[
+            ":=", "<-", "==", "!=", "<=", ">=", "&&", "||", "++", "--", "+", "-", "*", "/", "%",
+            "=", "<", ">", "!", "&", "|", "^",
+        ],
+        definition_patterns: &[
+            r"\bfunc\s+(\([^)]*\)\s*)?{name}\b",
+            r"\b(type|var|const)\s+{name}\b",
+            r"\b{name}\s*:=",
+        ],
+        flags: HIGHLIGHT_NUMBERS | HIGHLIGHT_STRINGS,
+        options: FiletypeOptions {
+            expand_tabs: Some(false),
+            ..FiletypeOptions::NONE
+        },
+    },
+    Filetype {
+        name: "makefile",
+        filename_patterns: &["Makefile", "makefile", "GNUmakefile", "Makefile.*", ".mk"],
+        interpreters: &["make"],
+        singleline_comment_start: "#",
+        multiline_comment_start: "",
+        multiline_comment_end: "",
+        keywords1: &[
+            "ifeq", "ifneq", "ifdef", "ifndef", "else", "endif", "include", "define", "endef",
+        ],
+        keywords2: &["export", "override", ".PHONY"],
+        keywords3: &[],
+        operators: &[":=", "?=", "+=", "=", ":"],
+        definition_patterns: &[
+            r"^{name}\s*:([^=]|$)",
+            r"^(export\s+|override\s+)?{name}\s*[:?+]?=",
+        ],
+        flags: HIGHLIGHT_NUMBERS,
+        options: FiletypeOptions {
+            expand_tabs: Some(false),
+            require_tabs: true,
+            ..FiletypeOptions::NONE
+        },
+    },
+    Filetype {
+        name: "markdown",
+        filename_patterns: &[".md", ".markdown"],
+        interpreters: &[],
+        singleline_comment_start: "",
+        multiline_comment_start: "",
+        multiline_comment_end: "",
+        keywords1: &[],
+        keywords2: &[],
+        keywords3: &[],
+        operators: &[],
+        definition_patterns: &[],
+        flags: HIGHLIGHT_MARKDOWN,
+        options: FiletypeOptions {
+            soft_wrap: Some(true),
+            render_whitespace: Some(false),
+            ..FiletypeOptions::NONE
+        },
+    },
+    Filetype {
+        name: "prose",
+        filename_patterns: &[".txt", "README", "LICENSE", "COPYING"],
+        interpreters: &[],
+        singleline_comment_start: "",
+        multiline_comment_start: "",
+        multiline_comment_end: "",
+        keywords1: &[],
+        keywords2: &[],
+        keywords3: &[],
+        operators: &[],
+        definition_patterns: &[],
+        flags: 0,
+        options: FiletypeOptions {
+            soft_wrap: Some(true),
+            render_whitespace: Some(false),
+            ..FiletypeOptions::NONE
+        },
+    },
+];
+
+// Get the name of the interpreter from a `#!` line, looking through `env`.
+fn shebang_interpreter(line: &str) -> Option<&str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    Some(interpreter)
+}
+
+// Filetypes defined in .kilo/syntax.toml files in a directory and the ones
+// above it, nearest first, so that they win over the built-in ones.
+fn project_filetypes(directory: &Path) -> Result<Vec<&'static Filetype>, String> {
+    let mut filetypes = Vec::new();
+    for dir in directory.ancestors() {
+        let syntax_file = dir.join(".kilo/syntax.toml");
+        if let Ok(contents) = std::fs::read_to_string(&syntax_file) {
+            let parsed = parse_syntax_file(&contents)
+                .map_err(|error| format!("{}: {}", syntax_file.display(), error))?;
+            filetypes.extend(parsed);
+        }
+    }
+    Ok(filetypes)
+}
+
+// Read filetypes from a small subset of TOML, like:
+//
+//     [[filetype]]
+//     name = "nix"
+//     filename_patterns = [".nix"]
+//     singleline_comment_start = "#"
+//     keywords1 = ["let", "in"]
+//     highlight_numbers = true
+//
+// Filetypes have to live for the rest of the program, so they're leaked.
+fn parse_syntax_file(contents: &str) -> Result<Vec<&'static Filetype>, String> {
+    fn leak(s: String) -> &'static str {
+        Box::leak(s.into_boxed_str())
+    }
+    fn leak_all(strings: Vec<String>) -> &'static [&'static str] {
+        Box::leak(
+            strings
+                .into_iter()
+                .map(leak)
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        )
+    }
+
+    let mut filetypes = Vec::new();
+    let mut current: Option<Filetype> = None;
+    let mut lines = contents.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let error = |message: &str| format!("line {}: {}", i + 1, message);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[filetype]]" {
+            filetypes.extend(current.take());
+            current = Some(Filetype {
+                name: "",
+                filename_patterns: &[],
+                interpreters: &[],
+                singleline_comment_start: "",
+                multiline_comment_start: "",
+                multiline_comment_end: "",
+                keywords1: &[],
+                keywords2: &[],
+                keywords3: &[],
+                operators: &[],
+                definition_patterns: &[],
+                flags: 0,
+                options: FiletypeOptions::NONE,
+            });
+            continue;
+        }
+        let filetype = current
+            .as_mut()
+            .ok_or_else(|| error("expected [[filetype]]"))?;
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let mut value = value.trim().to_string();
+        // Arrays can go over several lines.
+        while value.starts_with('[') && !value.ends_with(']') {
+            match lines.next() {
+                Some((_, line)) => value.push_str(line.trim()),
+                None => return Err(error("unclosed array")),
+            }
+        }
+
+        match key.trim() {
+            key @ ("highlight_numbers" | "highlight_strings") => {
+                let flag = if key == "highlight_numbers" {
+                    HIGHLIGHT_NUMBERS
+                } else {
+                    HIGHLIGHT_STRINGS
+                };
+                match value.as_str() {
+                    "true" => filetype.flags |= flag,
+                    "false" => filetype.flags &= !flag,
+                    _ => return Err(error("expected true or false")),
+                }
+            }
+            key @ ("name"
+            | "singleline_comment_start"
+            | "multiline_comment_start"
+            | "multiline_comment_end") => {
+                let string =
+                    leak(parse_toml_string(&value).ok_or_else(|| error("expected a string"))?);
+                match key {
+                    "name" => filetype.name = string,
+                    "singleline_comment_start" => filetype.singleline_comment_start = string,
+                    "multiline_comment_start" => filetype.multiline_comment_start = string,
+                    _ => filetype.multiline_comment_end = string,
+                }
+            }
+            key => {
+                let field = match key {
+                    "filename_patterns" => &mut filetype.filename_patterns,
+                    "interpreters" => &mut filetype.interpreters,
+                    "keywords1" => &mut filetype.keywords1,
+                    "keywords2" => &mut filetype.keywords2,
+                    "keywords3" => &mut filetype.keywords3,
+                    "operators" => &mut filetype.operators,
+                    "definition_patterns" => &mut filetype.definition_patterns,
+                    _ => return Err(error(&format!("unknown key {}", key))),
+                };
+                *field = leak_all(
+                    parse_toml_array(&value)
+                        .ok_or_else(|| error("expected an array of strings"))?,
+                );
+            }
+        }
+    }
+    filetypes.extend(current);
+
+    if filetypes.iter().any(|filetype| filetype.name.is_empty()) {
+        return Err("every filetype needs a name".to_string());
+    }
+    Ok(filetypes
+        .into_iter()
+        .map(|filetype| &*Box::leak(Box::new(filetype)))
+        .collect())
+}
+
+// A basic TOML string, with the escapes that are likely in a syntax file.
+fn parse_toml_string(value: &str) -> Option<String> {
+    let mut chars = value.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut string = String::new();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            string.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            });
+        } else {
+            string.push(c);
+        }
+    }
+    Some(string)
+}
+
+fn parse_toml_array(value: &str) -> Option<Vec<String>> {
+    let inside = value.strip_prefix('[')?.strip_suffix(']')?.trim();
+    let mut strings = Vec::new();
+    let mut rest = inside;
+    while !rest.is_empty() {
+        // Find the closing quote, skipping escaped ones.
+        let mut end = None;
+        let mut escaped = false;
+        for (i, c) in rest.char_indices().skip(1) {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => {
+                    end = Some(i);
+                    break;
+                }
+                _ => escaped = false,
+            }
+        }
+        let end = end?;
+        strings.push(parse_toml_string(&rest[..=end])?);
+        rest = rest[end + 1..].trim_start();
+        // Allow a trailing comma.
+        rest = match rest.strip_prefix(',') {
+            Some(rest) => rest.trim_start(),
+            None if rest.is_empty() => rest,
+            None => return None,
+        };
+    }
+    Some(strings)
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || "&,.()+-/*=~%<>[]; ".contains(c)
+}
+
+// Cut a string off so that it fits in a number of screen columns, without
+// splitting a char.
+fn truncate_to_width(s: &str, max_width: usize) -> &str {
+    let mut width = 0;
+    let end = s
+        .char_indices()
+        .find(|&(_, c)| {
+            width += UnicodeWidthChar::width(c).unwrap_or(0);
+            width > max_width
+        })
+        .map_or(s.len(), |(i, _)| i);
+    &s[..end]
+}
+
+// How wide each field of a row is in CSV view.
+fn csv_field_widths(chars: &str, delimiter: char, tab_stop: usize) -> Vec<usize> {
+    chars
+        .split(delimiter)
+        .map(|field| display_width(field, tab_stop))
+        .collect()
+}
+
+// The number of screen columns a string takes up when drawn from the start
+// of a line.
+fn display_width(s: &str, tab_stop: usize) -> usize {
+    let mut width = 0;
+    for c in s.chars() {
+        if c == '\t' {
+            width += tab_stop - (width % tab_stop);
+        } else if c.is_control() {
+            width += 1;
+        } else {
+            width += UnicodeWidthChar::width(c).unwrap_or(0);
+        }
+    }
+    width
+}
+
+// Fields of delimited data lined up in columns, by drawing the delimiter
+// stretched like a tab. Delimiters inside quotes aren't treated specially.
+struct CsvView {
+    delimiter: char,
+    // The screen column each field after the first starts at.
+    column_starts: Vec<usize>,
+    // The widest field in each column.
+    widths: Vec<usize>,
+}
+
+// How chars that take up several cells of the screen are laid out: tabs,
+// delimiters in CSV view and invisible chars in list mode.
+#[derive(Clone, Copy)]
+struct CellLayout<'a> {
+    tab_stop: usize,
+    csv_view: Option<&'a CsvView>,
+    list_mode: bool,
+}
+
+impl CellLayout<'_> {
+    fn new<'a>(config: &Config, csv_view: &'a Option<CsvView>) -> CellLayout<'a> {
+        CellLayout {
+            tab_stop: config.tab_stop,
+            csv_view: csv_view.as_ref(),
+            list_mode: config.list_mode,
+        }
+    }
+
+    // Whether c is drawn over several cells: stretched to reach the next
+    // stop, or escaped.
+    fn expands(&self, c: char) -> bool {
+        c == '\t'
+            || self
+                .csv_view
+                .is_some_and(|csv_view| c == csv_view.delimiter)
+            || self.escapes(c)
+    }
+
+    // Whether c is drawn as its codepoint, like <U+202E>.
+    fn escapes(&self, c: char) -> bool {
+        self.list_mode && is_invisible(c)
+    }
+
+    // How many screen columns a char that expands takes up when it starts at
+    // column.
+    fn width(&self, c: char, column: usize) -> usize {
+        if self.escapes(c) {
+            return escape_char(c).len();
+        }
+        if let Some(csv_view) = self.csv_view.filter(|csv_view| c == csv_view.delimiter) {
+            match csv_view.column_starts.iter().find(|&&start| start > column) {
+                Some(start) => return start - column,
+                None if c != '\t' => return 1,
+                None => {}
+            }
+        }
+        self.tab_stop - (column % self.tab_stop)
+    }
+}
+
+// Chars that don't show up, or that change how the text around them is
+// shown, like bidi overrides. Drawing them as they are lets a file look like it
+// says something it doesn't. Tabs are left out since they're expected.
+fn is_invisible(c: char) -> bool {
+    (c.is_control() && c != '\t')
+        || matches!(c,
+            '\u{ad}' | '\u{61c}' | '\u{180e}' | '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}' | '\u{feff}' | '\u{fff9}'..='\u{fffb}')
+}
+
+// Invisible chars other than control chars, which are at least drawn as
+// something. These are the ones that can hide how code really reads, as in
+// "trojan source" attacks.
+fn is_deceptive(c: char) -> bool {
+    is_invisible(c) && !c.is_control()
+}
+
+fn escape_char(c: char) -> String {
+    format!("<U+{:04X}>", c as u32)
+}
+
+struct Row {
+    chars: String,
+    // The number of chars in chars, kept up to date by update_row so that it
+    // doesn't need counting on every keypress.
+    char_len: usize,
+    render: String,
+    highlight: Vec<Highlight>,
+    continue_multiline_comment: bool,
+    continue_multiline_string: Option<char>,
+    continue_code_block: bool,
+    continue_bracket_depth: usize,
+    // Whether highlight needs updating before it's drawn.
+    highlight_stale: bool,
+    // In CSV view, how wide each of the row's fields is.
+    csv_widths: Vec<usize>,
+    // Whether it's wider than max_line_length, as counted in long_lines.
+    too_long: bool,
+    // How many chars of its indentation should be flagged, as counted in
+    // bad_indents.
+    bad_indent: usize,
+    // Whether a row after this one indented with spaces would be a recipe in
+    // a file that needs tabs. Blank rows pass on the state of the row before.
+    continue_recipe: bool,
+}
+
+impl Row {
+    // The byte offset in chars of the char at char_index, or the length of
+    // chars if it's past the end.
+    fn byte_index(&self, char_index: usize) -> usize {
+        self.chars
+            .char_indices()
+            .nth(char_index)
+            .map_or(self.chars.len(), |(byte_index, _)| byte_index)
+    }
+
+    // Go through the row's chars alongside the render chars and highlights
+    // they became, one entry per render char.
+    fn zip<'a>(&'a self, layout: CellLayout<'a>) -> RowCells<'a> {
+        RowCells {
+            chars: self.chars.chars().enumerate(),
+            render: self.render.chars(),
+            highlight: self.highlight.iter(),
+            layout,
+            render_length: 0,
+            tab: None,
+        }
+    }
+}
+
+// Lazily zips a row so that drawing a small part of a long row doesn't have to
+// go through the whole thing.
+struct RowCells<'a> {
+    chars: std::iter::Enumerate<std::str::Chars<'a>>,
+    render: std::str::Chars<'a>,
+    highlight: std::slice::Iter<'a, Highlight>,
+    layout: CellLayout<'a>,
+    render_length: usize,
+    // The tab, or delimiter in CSV view, being expanded, its index and how
+    // many columns of it are left.
+    tab: Option<(char, usize, usize)>,
+}
+
+impl Iterator for RowCells<'_> {
+    type Item = (char, usize, char, Highlight);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (c, i) = match self.tab {
+            Some((c, i, tab_size)) => {
+                self.tab = if tab_size > 1 {
+                    Some((c, i, tab_size - 1))
+                } else {
+                    None
+                };
+                self.render_length += 1;
+                (c, i)
+            }
+            None => {
+                let (i, c) = self.chars.next()?;
+                if self.layout.expands(c) {
+                    let tab_size = self.layout.width(c, self.render_length);
+                    if tab_size > 1 {
+                        self.tab = Some((c, i, tab_size - 1));
+                    }
+                    self.render_length += 1;
+                } else if c.is_control() {
+                    self.render_length += 1;
+                } else {
+                    self.render_length += UnicodeWidthChar::width(c).unwrap_or(1).max(1);
+                }
+                (c, i)
+            }
+        };
+        Some((
+            c,
+            i,
+            self.render.next().unwrap(),
+            // Unhighlighted long rows have no highlights, or only as many as
+            // reach a match being shown.
+            self.highlight.next().copied().unwrap_or(Highlight::Normal),
+        ))
+    }
+}
+
+// The rows of a buffer, kept as a gap buffer: there's a gap where rows were
+// last inserted or removed, so editing in one place only moves the rows
+// between there and the last place instead of every row after it.
+struct Rows {
+    // The rows before the gap, in order.
+    before: Vec<Row>,
+    // The rows after the gap, last row first, so that the gap can be moved
+    // with pushes and pops.
+    after: Vec<Row>,
+}
+
+type RowsIter<'a> =
+    std::iter::Chain<std::slice::Iter<'a, Row>, std::iter::Rev<std::slice::Iter<'a, Row>>>;
+
+impl Rows {
+    fn new() -> Rows {
+        Rows {
+            before: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.before.len() + self.after.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn clear(&mut self) {
+        self.before.clear();
+        self.after.clear();
+    }
+
+    // Move the gap so that index rows come before it.
+    fn move_gap(&mut self, index: usize) {
+        while self.before.len() > index {
+            self.after.push(self.before.pop().unwrap());
+        }
+        while self.before.len() < index {
+            match self.after.pop() {
+                Some(row) => self.before.push(row),
+                None => panic!("row index {} out of range for {} rows", index, self.len()),
+            }
+        }
+    }
+
+    fn insert(&mut self, index: usize, row: Row) {
+        self.move_gap(index);
+        self.before.push(row);
+    }
+
+    fn remove(&mut self, index: usize) -> Row {
+        self.move_gap(index);
+        self.after.pop().expect("row index out of range")
+    }
+
+    // Where a row past the gap is in after.
+    fn after_index(&self, index: usize) -> Option<usize> {
+        let from_gap = index.checked_sub(self.before.len())?;
+        self.after.len().checked_sub(from_gap + 1)
+    }
+
+    fn get(&self, index: usize) -> Option<&Row> {
+        match self.before.get(index) {
+            Some(row) => Some(row),
+            None => self.after_index(index).map(|i| &self.after[i]),
+        }
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut Row> {
+        if index < self.before.len() {
+            return self.before.get_mut(index);
+        }
+        let i = self.after_index(index)?;
+        self.after.get_mut(i)
+    }
+
+    fn first(&self) -> Option<&Row> {
+        self.get(0)
+    }
+
+    fn iter(&self) -> RowsIter<'_> {
+        self.range(0, self.len())
+    }
+
+    // The rows in start..end.
+    fn range(&self, start: usize, end: usize) -> RowsIter<'_> {
+        let end = cmp::min(end, self.len());
+        let start = cmp::min(start, end);
+        let gap = self.before.len();
+        let before = &self.before[cmp::min(start, gap)..cmp::min(end, gap)];
+        // The rows after the gap are backwards, so the range is too.
+        let last = self.after.len();
+        let after = &self.after[last - (end.max(gap) - gap)..last - (start.max(gap) - gap)];
+        before.iter().chain(after.iter().rev())
+    }
+
+    // A row to change, along with the one before it to carry on from.
+    fn with_previous_mut(&mut self, index: usize) -> (Option<&Row>, &mut Row) {
+        let before_len = self.before.len();
+        if index < before_len {
+            let (first, last) = self.before.split_at_mut(index);
+            (first.last(), &mut last[0])
+        } else if index == before_len {
+            let row = self.after.last_mut().expect("row index out of range");
+            (self.before.last(), row)
+        } else {
+            let i = self.after_index(index).expect("row index out of range");
+            let (row, previous) = self.after.split_at_mut(i + 1);
+            (previous.first(), &mut row[i])
+        }
+    }
+}
+
+impl std::ops::Index<usize> for Rows {
+    type Output = Row;
+
+    fn index(&self, index: usize) -> &Row {
+        self.get(index).expect("row index out of range")
+    }
+}
+
+impl std::ops::IndexMut<usize> for Rows {
+    fn index_mut(&mut self, index: usize) -> &mut Row {
+        self.get_mut(index).expect("row index out of range")
+    }
+}
+
+impl<'a> IntoIterator for &'a Rows {
+    type Item = &'a Row;
+    type IntoIter = RowsIter<'a>;
+
+    fn into_iter(self) -> RowsIter<'a> {
+        self.iter()
+    }
+}
+
+// The words a word is being completed to, while Ctrl-N is pressed again and
+// again.
+struct Completion {
+    // Where the word being completed starts.
+    start: Position,
+    candidates: Vec<String>,
+    // The candidate that's been put in, once they're being cycled through.
+    index: Option<usize>,
+}
+
+// Where the pane that isn't active is looking.
+#[derive(Copy, Clone)]
+struct Pane {
+    cursor_position: Position,
+    text_offset: Position,
+    goal_column: Option<usize>,
+}
+
+struct Editor {
+    // None when running a script without a terminal.
+    orig_termios: Option<Termios>,
+    screen_dimensions: Dimensions,
+    cursor_position: Position,
+    // The screen column to aim for when moving up and down, so that passing
+    // through short lines doesn't lose it.
+    goal_column: Option<usize>,
+    input: Receiver<char>,
+    // A char taken from input to check whether any was waiting.
+    pending_input: Cell<Option<char>>,
+    text_offset: Position,
+    rows: Rows,
+    filename: Option<String>,
+    filetype: Option<&'static Filetype>,
+    // Filetypes from the .kilo/syntax.toml files above the file.
+    project_filetypes: Vec<&'static Filetype>,
+    global_config: Config,
+    config: Config,
+    // The EditorConfig properties that apply to the file.
+    editorconfig: Vec<(String, String)>,
+    // The line ending the file used when it was opened.
+    detected_line_ending: Option<LineEnding>,
+    // Whether the file ended with a line ending when it was opened, so saving
+    // doesn't add or remove one behind the user's back.
+    detected_final_newline: Option<bool>,
+    // Whether the file started with a UTF-8 byte order mark. It's kept out of
+    // the rows and written back on save.
+    had_bom: bool,
+    // What the file is read and written as.
+    encoding: Encoding,
+    // When the buffer was last seen without unsaved changes, for auto-save.
+    clean_at: Instant,
+    // When the last key was pressed, and whether the changes have been
+    // backed up since, for the idle backup.
+    last_key_at: Instant,
+    backed_up: bool,
+    // Where jumps started from, oldest first, for Ctrl-O.
+    jump_list: Vec<Position>,
+    // Where we are in the jump list. It's the length of the list unless
+    // we've gone back through it.
+    jump_index: usize,
+    // Positions saved with Alt-m, plus the automatic marks.
+    marks: HashMap<char, Position>,
+    // Where we were in the files we've switched away from, by absolute path.
+    views: HashMap<String, Pane>,
+    // The other pane when the screen is split. The active pane's view is in
+    // cursor_position and text_offset like when there's only one.
+    other_pane: Option<Pane>,
+    csv_view: Option<CsvView>,
+    top_pane_active: bool,
+    // The escape sequences and text that make up a frame.
+    output: String,
+    status_message: String,
+    status_message_time: Instant,
+    dirty: bool,
+    read_only: bool,
+    // The buffer is read-only because we can't write to its file.
+    no_write_permission: bool,
+    // Typing replaces the character under the cursor instead of inserting.
+    overwrite: bool,
+    last_change: Option<Change>,
+    completion: Option<Completion>,
+    // Whether typing now adds to the last change rather than starting one.
+    typing_change: bool,
+    // Whether the rows are a hex dump of the file.
+    hex_view: bool,
+    // The terminal title we last set, if we've set one.
+    title: Option<String>,
+    // The number of matches of a query on each row, shown until the next
+    // keypress.
+    match_counts: Vec<usize>,
+    // Labels drawn over the words that can be jumped to.
+    jump_labels: Vec<(Position, char)>,
+    // Where the selection started. It goes from here to the cursor.
+    selection_anchor: Option<Position>,
+    quit_times: u8,
+    matches: Vec<usize>,
+    match_index: usize,
+    saved_highlight: Vec<Highlight>,
+    saved_highlight_index: usize,
+    // No row before this has a stale highlight.
+    stale_highlight_from: Option<usize>,
+    // How many rows are wider than max_line_length.
+    long_lines: usize,
+    // How many rows have indentation that should be flagged.
+    bad_indents: usize,
+    // Which of the command palette's matches is selected.
+    palette_selection: usize,
+    // The recently opened files, most recent first, while choosing one.
+    recent_files: Vec<String>,
+    // The last query and its compiled regex.
+    find_regex: Option<(String, Regex)>,
+    // The last search, while it's still highlighted.
+    search_highlight: Option<Regex>,
+    // The word under the cursor, when its other occurrences are highlighted.
+    cursor_word: Option<String>,
+}
+
+impl Editor {
+    fn new(orig_termios: Termios, config: Config) -> Result<Editor, StartupError> {
+        let mut screen_dimensions = get_window_size()
+            .ok_or_else(|| StartupError::NotATerminal("can't get the terminal size".to_string()))?;
+        // Make room for status bar and message.
+        screen_dimensions.rows = screen_dimensions.rows.saturating_sub(2);
+
+        Ok(Editor::with_input(
+            Some(orig_termios),
+            screen_dimensions,
+            spawn_stdin_channel(),
+            config,
+        ))
+    }
+
+    // An editor that never touches the terminal, for running a script. Nothing
+    // is ever drawn, so the screen size only matters to commands that scroll.
+    fn headless(config: Config) -> Editor {
+        let (_, input) = mpsc::channel();
+        Editor::with_input(None, Dimensions { rows: 22, cols: 80 }, input, config)
+    }
+
+    fn with_input(
+        orig_termios: Option<Termios>,
+        screen_dimensions: Dimensions,
+        input: Receiver<char>,
+        config: Config,
+    ) -> Editor {
+        Editor {
+            orig_termios,
+            screen_dimensions,
             cursor_position: Position { x: 0, y: 0 },
-            input: spawn_stdin_channel(),
+            goal_column: None,
+            input,
+            pending_input: Cell::new(None),
             text_offset: Position { x: 0, y: 0 },
-            rows: Vec::new(),
+            rows: Rows::new(),
             filename: None,
             filetype: None,
+            project_filetypes: Vec::new(),
+            global_config: config.clone(),
+            config,
+            editorconfig: Vec::new(),
+            detected_line_ending: None,
+            detected_final_newline: None,
+            had_bom: false,
+            encoding: Encoding::Utf8,
+            clean_at: Instant::now(),
+            last_key_at: Instant::now(),
+            backed_up: false,
+            jump_list: Vec::new(),
+            jump_index: 0,
+            marks: HashMap::new(),
+            views: HashMap::new(),
+            other_pane: None,
+            csv_view: None,
+            top_pane_active: true,
+            output: String::new(),
             status_message: String::new(),
             status_message_time: Instant::now(),
             dirty: false,
+            read_only: false,
+            no_write_permission: false,
+            overwrite: false,
+            last_change: None,
+            completion: None,
+            typing_change: false,
+            hex_view: false,
+            title: None,
+            match_counts: Vec::new(),
+            jump_labels: Vec::new(),
+            selection_anchor: None,
             quit_times: QUIT_TIMES,
             matches: Vec::new(),
             match_index: 0,
             saved_highlight: Vec::new(),
             saved_highlight_index: 0,
+            stale_highlight_from: None,
+            long_lines: 0,
+            bad_indents: 0,
+            palette_selection: 0,
+            recent_files: Vec::new(),
+            find_regex: None,
+            search_highlight: None,
+            cursor_word: None,
+        }
+    }
+
+    fn highlight_to_color(highlight: Highlight) -> Color {
+        match highlight {
+            Highlight::Number => Color::Magenta,
+            Highlight::String => Color::Yellow,
+            Highlight::Comment | Highlight::MultilineComment => Color::BrightBlack,
+            Highlight::Keyword1 => Color::Red,
+            Highlight::Keyword2 => Color::Cyan,
+            Highlight::Keyword3 => Color::Green,
+            Highlight::Operator => Color::BrightYellow,
+            Highlight::Match => Color::Blue,
+            Highlight::Heading => Color::BrightCyan,
+            Highlight::Emphasis => Color::Green,
+            Highlight::Code => Color::Yellow,
+            Highlight::Link => Color::BrightBlue,
+            Highlight::ListBullet => Color::Red,
+            Highlight::Bracket(depth) => BRACKET_COLORS[depth as usize],
+            _ => Color::White,
+        }
+    }
+
+    fn detect_filetype(&mut self) {
+        self.filetype = self
+            .filetype_from_filename()
+            .or_else(|| self.filetype_from_shebang());
+        self.update_config();
+    }
+
+    // Work out the config for this buffer. The global config is overridden by
+    // the filetype, then by the file itself and finally by EditorConfig.
+    fn update_config(&mut self) {
+        self.config = self.global_config.clone();
+        if let Some(filetype) = self.filetype {
+            filetype.options.apply(&mut self.config);
+        }
+        if let Some(line_ending) = self.detected_line_ending {
+            self.config.line_ending = line_ending;
+        }
+        if let Some(final_newline) = self.detected_final_newline {
+            self.config.insert_final_newline = final_newline;
+        }
+        self.config.apply_editorconfig(&self.editorconfig);
+        if self
+            .filetype
+            .is_some_and(|filetype| filetype.options.require_tabs)
+        {
+            self.config.expand_tabs = false;
+        }
+
+        // Settings like the tab stop change how rows are rendered.
+        self.update_all_rows();
+    }
+
+    fn update_all_rows(&mut self) {
+        self.update_csv_columns();
+        for y in 0..self.rows.len() {
+            self.update_row_display(y);
+        }
+    }
+
+    // The project's filetypes, then the built-in ones.
+    fn filetypes(&self) -> impl Iterator<Item = &'static Filetype> + '_ {
+        self.project_filetypes
+            .iter()
+            .copied()
+            .chain(FILETYPES.iter())
+    }
+
+    fn filetype_from_filename(&self) -> Option<&'static Filetype> {
+        let name = Path::new(self.filename.as_ref()?).file_name()?.to_str()?;
+        let name_chars: Vec<char> = name.chars().collect();
+        self.filetypes().find(|filetype| {
+            filetype.filename_patterns.iter().any(|pattern| {
+                if pattern.contains(['*', '?', '[', '{']) {
+                    glob_match(&pattern.chars().collect::<Vec<_>>(), &name_chars)
+                } else if pattern.starts_with('.') {
+                    name.ends_with(pattern)
+                } else {
+                    name == *pattern
+                }
+            })
+        })
+    }
+
+    fn filetype_from_shebang(&self) -> Option<&'static Filetype> {
+        let interpreter = shebang_interpreter(&self.rows.first()?.chars)?;
+        // Allow versioned interpreters like python3.11.
+        let matches = |name: &str| {
+            interpreter
+                .strip_prefix(name)
+                .is_some_and(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+        };
+        let configured = self
+            .config
+            .interpreter_syntax
+            .iter()
+            .find(|(name, _)| matches(name))
+            .and_then(|(_, filetype)| self.filetypes().find(|f| f.name == filetype));
+        configured.or_else(|| {
+            self.filetypes()
+                .find(|filetype| filetype.interpreters.iter().any(|name| matches(name)))
+        })
+    }
+
+    // *** Row Operations ***
+
+    // Whether a row is too long to highlight.
+    fn is_unhighlighted(&self, y: usize) -> bool {
+        self.config.max_highlight_length > 0
+            && self
+                .rows
+                .get(y)
+                .is_some_and(|row| row.render.len() > self.config.max_highlight_length)
+    }
+
+    fn update_row_highlight(&mut self, y: usize) {
+        if y >= self.rows.len() {
+            return;
+        }
+        let unhighlighted = self.is_unhighlighted(y);
+        let (previous, row) = self.rows.with_previous_mut(y);
+        row.highlight_stale = false;
+
+        // The filetype is kept for everything else.
+        if !self.config.syntax_highlight {
+            row.highlight = vec![Highlight::Normal; row.render.chars().count()];
+            return;
+        }
+
+        // Pass the state of the row before on, as if this one had nothing
+        // that changes it. The row is drawn as Normal past the end of its
+        // highlights, so there's no need to go through it.
+        if unhighlighted {
+            row.highlight.clear();
+            let state = previous.map_or((false, None, false, 0), |prev| {
+                (
+                    prev.continue_multiline_comment,
+                    prev.continue_multiline_string,
+                    prev.continue_code_block,
+                    prev.continue_bracket_depth,
+                )
+            });
+            let changed = (
+                row.continue_multiline_comment,
+                row.continue_multiline_string,
+                row.continue_code_block,
+                row.continue_bracket_depth,
+            ) != state;
+            (
+                row.continue_multiline_comment,
+                row.continue_multiline_string,
+                row.continue_code_block,
+                row.continue_bracket_depth,
+            ) = state;
+            if changed {
+                self.mark_highlight_stale(y + 1);
+            }
+            return;
+        }
+
+        if self
+            .filetype
+            .is_some_and(|f| f.flags & HIGHLIGHT_MARKDOWN != 0)
+        {
+            let in_code_block = y != 0 && previous.unwrap().continue_code_block;
+            let (highlight, in_code_block) = Editor::markdown_highlight(&row.render, in_code_block);
+            row.highlight = highlight;
+            if row.continue_code_block != in_code_block {
+                row.continue_code_block = in_code_block;
+                self.mark_highlight_stale(y + 1);
+            }
+            return;
+        }
+
+        row.highlight.clear();
+        let mut chars = row.render.char_indices().enumerate();
+        let line_length = row.render.chars().count();
+
+        let singleline_comment_start = if let Some(f) = self.filetype {
+            f.singleline_comment_start
+        } else {
+            ""
+        };
+        let multiline_comment_start = if let Some(f) = self.filetype {
+            f.multiline_comment_start
+        } else {
+            ""
+        };
+        let multiline_comment_end = if let Some(f) = self.filetype {
+            f.multiline_comment_end
+        } else {
+            ""
+        };
+
+        let mut prev_separator = true;
+        let mut in_singleline_comment = false;
+        let mut in_multiline_comment = y != 0 && previous.unwrap().continue_multiline_comment;
+
+        let mut quote = if y == 0 || in_multiline_comment {
+            None
+        } else {
+            previous.unwrap().continue_multiline_string
+        };
+
+        let mut bracket_depth = if y == 0 {
+            0
+        } else {
+            previous.unwrap().continue_bracket_depth
+        };
+
+        loop {
+            let prev_highlight = *row.highlight.last().unwrap_or(&Highlight::Normal);
+            let next = chars.next();
+            if let Some((i, (byte_index, c))) = next {
+                // No syntax highlighting.
+                if self.filetype.is_none() {
+                    row.highlight.push(Highlight::Normal);
+                    continue;
+                }
+
+                // Continuing a single-line comment.
+                if in_singleline_comment {
+                    row.highlight.push(Highlight::Comment);
+                    continue;
+                }
+
+                // Starting a single-line comment.
+                if !singleline_comment_start.is_empty()
+                    && quote.is_none()
+                    && !in_multiline_comment
+                    && row.render[byte_index..].starts_with(singleline_comment_start)
+                {
+                    in_singleline_comment = true;
+                    row.highlight.push(Highlight::Comment);
+                    continue;
+                }
+
+                // Multi-line comments.
+                if !multiline_comment_start.is_empty() && quote.is_none() {
+                    if in_multiline_comment {
+                        if row.render[byte_index..].starts_with(multiline_comment_end) {
+                            row.highlight.push(Highlight::MultilineComment);
+                            for _ in 0..multiline_comment_end.len() - 1 {
+                                chars.next();
+                                row.highlight.push(Highlight::MultilineComment);
+                            }
+                            in_multiline_comment = false;
+                        } else {
+                            row.highlight.push(Highlight::MultilineComment);
+                        }
+                        continue;
+                    } else if row.render[byte_index..].starts_with(multiline_comment_start) {
+                        row.highlight.push(Highlight::MultilineComment);
+                        for _ in 0..multiline_comment_start.len() - 1 {
+                            chars.next();
+                            row.highlight.push(Highlight::MultilineComment);
+                        }
+                        in_multiline_comment = true;
+                        continue;
+                    }
+                }
+
+                // Strings.
+                if self.filetype.unwrap().flags & HIGHLIGHT_STRINGS != 0 {
+                    match quote {
+                        Some(q) => {
+                            // In a string.
+                            row.highlight.push(Highlight::String);
+                            if c == '\\' {
+                                if i < line_length - 1 {
+                                    row.highlight.push(Highlight::String);
+                                    chars.next();
+                                    if i == line_length - 2 {
+                                        quote = None;
+                                    }
+                                }
+                            } else if c == q {
+                                // String ends.
+                                quote = None;
+                                prev_separator = true;
+                            } else if i == line_length - 1 {
+                                quote = None;
+                            }
+                            continue;
+                        }
+                        None => {
+                            // Not in a string.
+                            if c == '"' || c == '\'' {
+                                // String starts.
+                                quote = Some(c);
+                                row.highlight.push(Highlight::String);
+                                if i == line_length - 1 {
+                                    quote = None;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                }
+
+                // Brackets, colored by how deeply they're nested. Unmatched
+                // closing brackets are left alone.
+                if self.config.rainbow_brackets && "([{}])".contains(c) {
+                    if "([{".contains(c) {
+                        let color = bracket_depth % BRACKET_COLORS.len();
+                        row.highlight.push(Highlight::Bracket(color as u8));
+                        bracket_depth += 1;
+                    } else if bracket_depth > 0 {
+                        bracket_depth -= 1;
+                        let color = bracket_depth % BRACKET_COLORS.len();
+                        row.highlight.push(Highlight::Bracket(color as u8));
+                    } else {
+                        row.highlight.push(Highlight::Normal);
+                    }
+                    prev_separator = is_separator(c);
+                    continue;
+                }
+
+                // Numbers.
+                if self.filetype.unwrap().flags & HIGHLIGHT_NUMBERS != 0
+                    && ((c.is_ascii_digit()
+                        && (prev_separator || prev_highlight == Highlight::Number))
+                        || (c == '.' && prev_highlight == Highlight::Number))
+                {
+                    row.highlight.push(Highlight::Number);
+                    prev_separator = false;
+                    continue;
+                }
+
+                // Keywords.
+                if prev_separator {
+                    let mut found_keyword = false;
+                    'outer: for (keywords, highlight) in [
+                        (self.filetype.unwrap().keywords1, Highlight::Keyword1),
+                        (self.filetype.unwrap().keywords2, Highlight::Keyword2),
+                        (self.filetype.unwrap().keywords3, Highlight::Keyword3),
+                    ] {
+                        for keyword in keywords {
+                            if row.render[byte_index..].starts_with(keyword)
+                                && is_separator(
+                                    row.render[byte_index + keyword.len()..]
+                                        .chars()
+                                        .next()
+                                        .unwrap_or(' '),
+                                )
+                            {
+                                row.highlight.push(highlight);
+                                for _ in 0..keyword.len() - 1 {
+                                    chars.next();
+                                    row.highlight.push(highlight);
+                                }
+                                found_keyword = true;
+                                break 'outer;
+                            }
+                        }
+                    }
+                    if found_keyword {
+                        prev_separator = false;
+                        continue;
+                    }
+                }
+
+                // Operators. The longest one that matches wins, so `->` isn't
+                // highlighted as `-` followed by `>`.
+                if let Some(operator) = self
+                    .filetype
+                    .unwrap()
+                    .operators
+                    .iter()
+                    .filter(|operator| row.render[byte_index..].starts_with(*operator))
+                    .max_by_key(|operator| operator.len())
+                {
+                    row.highlight.push(Highlight::Operator);
+                    for _ in 0..operator.len() - 1 {
+                        chars.next();
+                        row.highlight.push(Highlight::Operator);
+                    }
+                    prev_separator = true;
+                    continue;
+                }
+
+                row.highlight.push(Highlight::Normal);
+                prev_separator = is_separator(c);
+            } else {
+                break;
+            }
+        }
+
+        // Check whether we need to update the syntax of following lines.
+        // eg. we could start a multiline comment on this line which could
+        // comment out the rest of the file. They're updated as they're
+        // drawn, so this doesn't go through the whole file at once.
+        let changed = row.continue_multiline_string != quote
+            || row.continue_multiline_comment != in_multiline_comment
+            || row.continue_bracket_depth != bracket_depth;
+        row.continue_multiline_comment = in_multiline_comment;
+        row.continue_multiline_string = quote;
+        row.continue_bracket_depth = bracket_depth;
+        if changed {
+            self.mark_highlight_stale(y + 1);
+        }
+    }
+
+    // Highlight a line of Markdown, returning whether the following line is in
+    // a fenced code block.
+    fn markdown_highlight(render: &str, in_code_block: bool) -> (Vec<Highlight>, bool) {
+        let chars: Vec<char> = render.chars().collect();
+        let mut highlight = vec![Highlight::Normal; chars.len()];
+        let trimmed = render.trim_start();
+        let indent = chars.len() - trimmed.chars().count();
+
+        // Fenced code blocks.
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            highlight.fill(Highlight::Code);
+            return (highlight, !in_code_block);
+        }
+        if in_code_block {
+            highlight.fill(Highlight::Code);
+            return (highlight, true);
+        }
+
+        // Headings.
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&level) && trimmed[level..].chars().next().is_none_or(|c| c == ' ') {
+            highlight.fill(Highlight::Heading);
+            return (highlight, false);
+        }
+
+        // List bullets.
+        let mut i = indent;
+        let digits = chars[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+        let bullet_length = if digits == 0 && matches!(chars.get(i), Some('-' | '*' | '+')) {
+            1
+        } else if digits > 0 && matches!(chars.get(i + digits), Some('.' | ')')) {
+            digits + 1
+        } else {
+            0
+        };
+        if bullet_length > 0 && matches!(chars.get(i + bullet_length), Some(' ')) {
+            highlight[i..i + bullet_length].fill(Highlight::ListBullet);
+            i += bullet_length;
+        }
+
+        // Inline code, emphasis and links.
+        let find = |from: usize, pattern: &[char]| {
+            (from..chars.len()).find(|&j| chars[j..].starts_with(pattern))
+        };
+        while i < chars.len() {
+            let c = chars[i];
+            let span = match c {
+                '`' => find(i + 1, &['`']).map(|end| (end + 1, Highlight::Code)),
+                '*' | '_' => {
+                    let marker = if chars.get(i + 1) == Some(&c) {
+                        vec![c, c]
+                    } else {
+                        vec![c]
+                    };
+                    find(i + marker.len() + 1, &marker)
+                        .map(|end| (end + marker.len(), Highlight::Emphasis))
+                }
+                '[' => find(i + 1, &[']', '('])
+                    .and_then(|middle| find(middle + 2, &[')']))
+                    .map(|end| (end + 1, Highlight::Link)),
+                _ => None,
+            };
+            match span {
+                Some((end, span_highlight)) => {
+                    highlight[i..end].fill(span_highlight);
+                    i = end;
+                }
+                None => i += 1,
+            }
+        }
+
+        (highlight, false)
+    }
+
+    fn update_row_render(&mut self, y: usize) {
+        if y >= self.rows.len() {
+            return;
+        }
+        let layout = CellLayout::new(&self.config, &self.csv_view);
+        let row = &mut self.rows[y];
+        row.char_len = row.chars.chars().count();
+        row.render.clear();
+
+        let mut render_length = 0;
+
+        for c in row.chars.chars() {
+            if layout.escapes(c) {
+                let escaped = escape_char(c);
+                row.render.push_str(&escaped);
+                render_length += escaped.len();
+            } else if layout.expands(c) {
+                let tab_size = layout.width(c, render_length);
+                // A delimiter is still drawn, followed by the padding.
+                row.render.push(if c == '\t' { ' ' } else { c });
+                for _ in 1..tab_size {
+                    row.render.push(' ');
+                }
+                render_length += tab_size;
+            } else if c.is_control() {
+                row.render.push(c);
+                render_length += 1;
+            } else {
+                row.render.push(c);
+                render_length += 1;
+                for _ in 1..UnicodeWidthChar::width(c).unwrap_or(1) {
+                    render_length += 1;
+                }
+            }
+        }
+    }
+
+    fn update_row(&mut self, y: usize) {
+        // Changing one row can change how wide a CSV column is, and so how
+        // every row is drawn.
+        if self.update_csv_row(y) {
+            self.update_all_rows();
+        } else {
+            self.update_row_display(y);
+        }
+    }
+
+    fn update_row_display(&mut self, y: usize) {
+        self.update_row_render(y);
+        let too_long = self.is_long_line(y);
+        let row = &mut self.rows[y];
+        if too_long != row.too_long {
+            row.too_long = too_long;
+            if too_long {
+                self.long_lines += 1;
+            } else {
+                self.long_lines -= 1;
+            }
+        }
+        self.update_bad_indent(y);
+        if self.config.defer_highlight {
+            self.mark_highlight_stale(y);
+        } else {
+            self.update_row_highlight(y);
+        }
+    }
+
+    // Leave the row to be highlighted before the screen is next drawn.
+    fn mark_highlight_stale(&mut self, y: usize) {
+        if y >= self.rows.len() {
+            return;
+        }
+        self.rows[y].highlight_stale = true;
+        self.stale_highlight_from = Some(self.stale_highlight_from.map_or(y, |from| from.min(y)));
+    }
+
+    // Bring the highlight of stale rows before until up to date. Rows depend
+    // on the ones before them, so this goes in order from the first stale row.
+    fn update_stale_highlights(&mut self, until: usize) {
+        let from = match self.stale_highlight_from.take() {
+            Some(from) => from,
+            None => return,
+        };
+        let until = cmp::min(until, self.rows.len());
+        for y in from..until {
+            if self.rows[y].highlight_stale {
+                self.update_row_highlight(y);
+            }
+        }
+        // Rows from here on might still be stale.
+        if until < self.rows.len() {
+            self.stale_highlight_from = Some(cmp::max(from, until));
+        }
+    }
+
+    fn insert_char_in_row(&mut self, y: usize, mut index: usize, c: char) {
+        if y >= self.rows.len() {
+            return;
+        }
+        let row = &mut self.rows[y];
+        let count = row.char_len;
+        if index > count {
+            index = count;
+        }
+
+        // Insert in place rather than rebuilding the string.
+        let byte_index = row.byte_index(index);
+        row.chars.insert(byte_index, c);
+
+        self.marks.insert(LAST_EDIT_MARK, Position { x: index, y });
+        self.update_row(y);
+    }
+
+    fn append_string_to_row(&mut self, y: usize, s: &str) {
+        if y >= self.rows.len() {
+            return;
+        }
+        let row = &mut self.rows[y];
+        let x = row.char_len;
+        row.chars.push_str(s);
+        self.marks.insert(LAST_EDIT_MARK, Position { x, y });
+        self.update_row(y);
+    }
+
+    fn replace_row_chars(&mut self, y: usize, chars: String) {
+        if y >= self.rows.len() {
+            return;
+        }
+        self.rows[y].chars = chars;
+        self.marks.insert(LAST_EDIT_MARK, self.cursor_position);
+        self.update_row(y);
+    }
+
+    fn delete_char_in_row(&mut self, y: usize, index: usize) {
+        if y >= self.rows.len() {
+            return;
+        }
+        let row = &mut self.rows[y];
+        let count = row.char_len;
+        if index >= count {
+            return;
+        }
+
+        let byte_index = row.byte_index(index);
+        row.chars.remove(byte_index);
+        self.marks.insert(LAST_EDIT_MARK, Position { x: index, y });
+        self.update_row(y);
+    }
+
+    fn insert_row(&mut self, index: usize, chars: &str) {
+        if index > self.rows.len() {
+            return;
+        }
+
+        let row = Row {
+            chars: chars.to_string(),
+            char_len: 0,
+            render: String::new(),
+            highlight: Vec::new(),
+            continue_multiline_comment: false,
+            continue_multiline_string: None,
+            continue_code_block: false,
+            continue_bracket_depth: 0,
+            highlight_stale: false,
+            csv_widths: Vec::new(),
+            too_long: false,
+            bad_indent: 0,
+            continue_recipe: false,
+        };
+        self.rows.insert(index, row);
+        self.shift_marks(index, true);
+        self.marks
+            .insert(LAST_EDIT_MARK, Position { x: 0, y: index });
+        self.update_row(index);
+        // The next row carried on from a different row before. Its highlight
+        // passes any change further down.
+        self.mark_highlight_stale(index + 1);
+        self.update_bad_indent(index + 1);
+        self.dirty = true;
+    }
+
+    // TODO: get_screen_index, screen_index_to_char_index and get_render_index
+    // are pretty similar.
+    fn get_screen_index(&self, x: usize, y: usize) -> usize {
+        if y >= self.rows.len() || x == 0 {
+            return 0;
+        }
+
+        let mut screen_index = 0;
+
+        let row = &self.rows[y];
+        let layout = self.layout();
+
+        for c in row.chars.chars().take(x) {
+            if layout.expands(c) {
+                screen_index += layout.width(c, screen_index);
+            } else if c.is_control() {
+                screen_index += 1;
+            } else {
+                screen_index += UnicodeWidthChar::width(c).unwrap_or(0);
+            }
+        }
+        screen_index
+    }
+
+    fn get_current_screen_index(&self) -> usize {
+        self.get_screen_index(self.cursor_position.x, self.cursor_position.y)
+    }
+
+    fn screen_index_to_char_index(
+        screen_index: usize,
+        row: Option<&Row>,
+        layout: CellLayout,
+    ) -> usize {
+        if row.is_none() || screen_index == 0 {
+            return 0;
+        }
+
+        let mut char_index = 0;
+        let mut i = 0;
+
+        for c in row.unwrap().chars.chars() {
+            if layout.expands(c) {
+                i += layout.width(c, i);
+            } else if c.is_control() {
+                i += 1;
+            } else {
+                i += UnicodeWidthChar::width(c).unwrap_or(0);
+            }
+
+            char_index += 1;
+            if i >= screen_index {
+                return char_index;
+            }
+        }
+        char_index
+    }
+
+    fn get_render_index(&self, x: usize, y: usize) -> usize {
+        if y >= self.rows.len() || x == 0 {
+            return 0;
+        }
+
+        let mut render_index = 0;
+
+        let row = &self.rows[y];
+        let layout = self.layout();
+
+        for c in row.chars.chars().take(x) {
+            if layout.expands(c) {
+                render_index += layout.width(c, render_index);
+            } else {
+                render_index += 1;
+            }
+        }
+        render_index
+    }
+
+    // The number of screen columns taken up by the line numbers. A gutter
+    // that would leave no room for the text isn't drawn at all.
+    fn line_number_space(&self) -> usize {
+        let space = self.config.gutter_padding_left
+            + self.line_number_width()
+            + self.config.gutter_padding_right
+            + display_width(&self.config.gutter_separator, self.config.tab_stop);
+        if space < self.screen_dimensions.cols {
+            space
+        } else {
+            0
+        }
+    }
+
+    // The number of screen columns taken up by the longest line number,
+    // counting the line after the last row if the cursor is there.
+    fn line_number_width(&self) -> usize {
+        format!("{}", cmp::max(self.rows.len(), self.cursor_position.y + 1)).len()
+    }
+
+    // The number of screen columns available for text.
+    fn text_cols(&self) -> usize {
+        self.screen_dimensions
+            .cols
+            .saturating_sub(self.line_number_space())
+    }
+
+    fn layout(&self) -> CellLayout<'_> {
+        CellLayout::new(&self.config, &self.csv_view)
+    }
+
+    // Work out where the CSV columns start from the widest field in each,
+    // returning whether they've moved.
+    fn update_csv_columns(&mut self) -> bool {
+        let csv_view = match &mut self.csv_view {
+            Some(csv_view) => csv_view,
+            None => return false,
+        };
+        csv_view.widths.clear();
+        for y in 0..self.rows.len() {
+            let widths = csv_field_widths(
+                &self.rows[y].chars,
+                csv_view.delimiter,
+                self.config.tab_stop,
+            );
+            for (i, &width) in widths.iter().enumerate() {
+                match csv_view.widths.get_mut(i) {
+                    Some(widest) => *widest = cmp::max(*widest, width),
+                    None => csv_view.widths.push(width),
+                }
+            }
+            self.rows[y].csv_widths = widths;
+        }
+        self.update_csv_column_starts()
+    }
+
+    // Keep the CSV columns up to date after row y has changed, returning
+    // whether they've moved.
+    fn update_csv_row(&mut self, y: usize) -> bool {
+        let delimiter = match &self.csv_view {
+            Some(csv_view) => csv_view.delimiter,
+            None => return false,
+        };
+        let widths = csv_field_widths(&self.rows[y].chars, delimiter, self.config.tab_stop);
+        let old_widths = mem::replace(&mut self.rows[y].csv_widths, widths.clone());
+        self.update_csv_widths(&old_widths, &widths)
+    }
+
+    // Update the widest field in each CSV column after a row's fields change
+    // from old_widths to new_widths. Only a column whose widest field was in
+    // the row needs going through again.
+    fn update_csv_widths(&mut self, old_widths: &[usize], new_widths: &[usize]) -> bool {
+        let csv_view = match &mut self.csv_view {
+            Some(csv_view) => csv_view,
+            None => return false,
+        };
+        for i in 0..cmp::max(old_widths.len(), new_widths.len()) {
+            if i == csv_view.widths.len() {
+                csv_view.widths.push(0);
+            }
+            let widest = csv_view.widths[i];
+            match new_widths.get(i) {
+                Some(&width) if width >= widest => csv_view.widths[i] = width,
+                _ if old_widths.get(i) == Some(&widest) => {
+                    let column = self.rows.iter().filter_map(|row| row.csv_widths.get(i));
+                    match column.max() {
+                        Some(&width) => csv_view.widths[i] = width,
+                        // No row has this column, or any after it, any more.
+                        None => {
+                            csv_view.widths.truncate(i);
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.update_csv_column_starts()
+    }
+
+    // Each field is followed by its delimiter and a space.
+    fn update_csv_column_starts(&mut self) -> bool {
+        let csv_view = self.csv_view.as_mut().unwrap();
+        let mut column_starts = Vec::new();
+        let mut start = 0;
+        for width in csv_view
+            .widths
+            .iter()
+            .take(csv_view.widths.len().saturating_sub(1))
+        {
+            start += width + 2;
+            column_starts.push(start);
+        }
+        if column_starts == csv_view.column_starts {
+            return false;
+        }
+        csv_view.column_starts = column_starts;
+        true
+    }
+
+    fn toggle_csv_view(&mut self) {
+        if self.csv_view.take().is_some() {
+            self.set_status_message("CSV view off");
+        } else {
+            // Guess the delimiter from how often each appears near the start.
+            let delimiter = [',', '\t', ';', '|']
+                .iter()
+                .map(|&delimiter| {
+                    let count: usize = self
+                        .rows
+                        .iter()
+                        .take(CSV_SAMPLE_ROWS)
+                        .map(|row| row.chars.matches(delimiter).count())
+                        .sum();
+                    (count, delimiter)
+                })
+                .filter(|&(count, _)| count > 0)
+                .max_by_key(|&(count, _)| count);
+            match delimiter {
+                Some((_, delimiter)) => {
+                    self.csv_view = Some(CsvView {
+                        delimiter,
+                        column_starts: Vec::new(),
+                        widths: Vec::new(),
+                    });
+                    self.set_status_message(&format!(
+                        "CSV view on, split at {}",
+                        if delimiter == '\t' {
+                            "tabs".to_string()
+                        } else {
+                            format!("'{}'", delimiter)
+                        }
+                    ));
+                }
+                None => {
+                    self.set_status_message("No delimiters found for CSV view");
+                    return;
+                }
+            }
+        }
+        self.update_all_rows();
+    }
+
+    // The number of screen lines available for text in the active pane.
+    fn text_rows(&self) -> usize {
+        if self.other_pane.is_none() {
+            return self.screen_dimensions.rows;
+        }
+        // One line goes to the divider between the panes.
+        let rows = self.screen_dimensions.rows.saturating_sub(1);
+        if self.top_pane_active {
+            rows / 2
+        } else {
+            rows - rows / 2
+        }
+    }
+
+    // Split a row into the ranges of screen columns that go on each line of
+    // the screen when soft wrapping. Wide characters are never split.
+    fn wrap_segments(&self, y: usize) -> Vec<(usize, usize)> {
+        let width = self.text_cols();
+        let mut segments = Vec::new();
+        let mut start = 0;
+        let mut column = 0;
+
+        let layout = self.layout();
+        if let Some(row) = self.rows.get(y) {
+            for c in row.chars.chars() {
+                if layout.expands(c) {
+                    // Tabs are just spaces on the screen so they can be split.
+                    for _ in 0..layout.width(c, column) {
+                        if column - start == width {
+                            segments.push((start, column));
+                            start = column;
+                        }
+                        column += 1;
+                    }
+                } else {
+                    let char_width = if c.is_control() {
+                        1
+                    } else {
+                        UnicodeWidthChar::width(c).unwrap_or(0)
+                    };
+                    if column + char_width - start > width && column > start {
+                        segments.push((start, column));
+                        start = column;
+                    }
+                    column += char_width;
+                }
+            }
+        }
+        segments.push((start, column));
+
+        // Leave room for the cursor after a row that exactly fills the screen.
+        if column > 0 && column - start == width {
+            segments.push((column, column));
+        }
+        segments
+    }
+
+    fn get_current_row(&self) -> Option<&Row> {
+        if self.cursor_position.y >= self.rows.len() {
+            None
+        } else {
+            Some(&self.rows[self.cursor_position.y])
+        }
+    }
+
+    // *** Editor Operations ***
+
+    fn ensure_writable(&mut self) -> bool {
+        if self.read_only {
+            self.set_status_message("Buffer is read-only");
+        }
+        !self.read_only
+    }
+
+    fn insert_char(&mut self, c: char) {
+        if !self.ensure_writable() {
+            return;
+        }
+        if self.cursor_position.y == self.rows.len() {
+            self.insert_row(self.rows.len(), "");
+        }
+
+        let Position { x, y } = self.cursor_position;
+        if self.overwrite && x < self.rows[y].char_len {
+            self.delete_char_in_row(y, x);
+        }
+        self.insert_char_in_row(y, x, c);
+        self.cursor_position.x += 1;
+        self.dirty = true;
+    }
+
+    // Insert the next character typed exactly as it is, even if it's a
+    // control character.
+    fn quoted_insert(&mut self) {
+        self.set_status_message(
+            "Quoted insert: press a key to insert literally, or u/U for a codepoint",
+        );
+        self.refresh_screen();
+        // Read straight from the input so that an escape comes through as
+        // itself instead of starting an escape sequence.
+        let c = self.read_char();
+        self.set_status_message("");
+        match c {
+            // Up to 4 or 8 hex digits, like Vim.
+            'u' => self.insert_codepoint(4),
+            'U' => self.insert_codepoint(8),
+            c => self.insert_char(c),
+        }
+    }
+
+    // Read a hex codepoint and insert its character. It's inserted after
+    // max_digits digits or on Enter.
+    fn insert_codepoint(&mut self, max_digits: usize) {
+        let mut digits = String::new();
+        loop {
+            self.set_status_message(&format!("Unicode: U+{} (ESC to cancel)", digits));
+            self.refresh_screen();
+            match self.read_key() {
+                Key::Char(c) if c.is_ascii_hexdigit() => {
+                    digits.push(c.to_ascii_uppercase());
+                    if digits.len() == max_digits {
+                        break;
+                    }
+                }
+                Key::Backspace | Key::Delete => {
+                    digits.pop();
+                }
+                Key::Enter if !digits.is_empty() => break,
+                Key::Esc => {
+                    self.set_status_message("");
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        let codepoint = u32::from_str_radix(&digits, 16).unwrap();
+        match char::from_u32(codepoint) {
+            Some(c) => {
+                self.set_status_message("");
+                self.insert_char(c);
+            }
+            // Surrogates and anything past U+10FFFF.
+            None => self.set_status_message(&format!("Invalid codepoint U+{}", digits)),
+        }
+    }
+
+    fn insert_tab(&mut self) {
+        if self.config.expand_tabs {
+            let indent_size = self.config.indent_size;
+            let spaces = indent_size - (self.get_current_screen_index() % indent_size);
+            for _ in 0..spaces {
+                self.insert_char(' ');
+            }
+        } else {
+            self.insert_char('\t');
+        }
+    }
+
+    fn delete_char(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        if self.cursor_position.x == 0 && self.cursor_position.y == 0 {
+            return;
+        }
+        if self.cursor_position.y == self.rows.len() {
+            return;
+        }
+
+        if self.cursor_position.x > 0 {
+            self.delete_char_in_row(self.cursor_position.y, self.cursor_position.x - 1);
+            self.cursor_position.x -= 1;
+            self.dirty = true;
+        } else {
+            self.cursor_position.x = self.rows[self.cursor_position.y - 1].char_len;
+            let chars = mem::take(&mut self.rows[self.cursor_position.y].chars);
+            self.append_string_to_row(self.cursor_position.y - 1, &chars);
+            self.delete_row(self.cursor_position.y);
+            self.cursor_position.y -= 1;
+        }
+    }
+
+    fn delete_char_forward(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let y = self.cursor_position.y;
+        if y >= self.rows.len() {
+            return;
+        }
+
+        if self.cursor_position.x < self.rows[y].char_len {
+            self.delete_char_in_row(y, self.cursor_position.x);
+            self.dirty = true;
+        } else if y + 1 < self.rows.len() {
+            // Join the next line onto this one, leaving the cursor in place.
+            let chars = mem::take(&mut self.rows[y + 1].chars);
+            self.append_string_to_row(y, &chars);
+            self.delete_row(y + 1);
+        }
+    }
+
+    // Put a copy of the cursor's row, or of all the selected rows, after
+    // them and move the cursor onto the copy.
+    fn duplicate_lines(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let (first, last) = match self.selected_rows() {
+            Some(rows) => rows,
+            None => return,
+        };
+        let copies: Vec<String> = self
+            .rows
+            .range(first, last + 1)
+            .map(|row| row.chars.clone())
+            .collect();
+        for (i, chars) in copies.iter().enumerate() {
+            self.insert_row(last + 1 + i, chars);
+        }
+        self.selection_anchor = None;
+        self.cursor_position.y += copies.len();
+        self.marks.insert(LAST_EDIT_MARK, self.cursor_position);
+    }
+
+    // Comment out the cursor's row or the selected rows with the filetype's
+    // line comment, or uncomment them if they all are already. Blank lines
+    // are left alone.
+    fn toggle_comment(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let marker = match self
+            .filetype
+            .map(|filetype| filetype.singleline_comment_start)
+        {
+            Some(marker) if !marker.is_empty() => marker,
+            _ => {
+                self.set_status_message("This filetype has no line comments");
+                return;
+            }
+        };
+        let (first, last) = match self.selected_rows() {
+            Some(rows) => rows,
+            None => return,
+        };
+        let indent = |chars: &str| chars.chars().take_while(|c| c.is_whitespace()).count();
+        let lines: Vec<usize> = (first..=last)
+            .filter(|&y| !self.rows[y].chars.trim().is_empty())
+            .collect();
+        let comment = lines
+            .iter()
+            .any(|&y| !self.rows[y].chars.trim_start().starts_with(marker));
+        // Line the comment markers up with the least indented line.
+        let column = lines
+            .iter()
+            .map(|&y| indent(&self.rows[y].chars))
+            .min()
+            .unwrap_or(0);
+
+        for y in lines {
+            let chars = &self.rows[y].chars;
+            let (at, changed, shift) = if comment {
+                let at = chars
+                    .char_indices()
+                    .nth(column)
+                    .map_or(chars.len(), |(i, _)| i);
+                let inserted = format!("{} ", marker);
+                let changed = format!("{}{}{}", &chars[..at], inserted, &chars[at..]);
+                (column, changed, inserted.chars().count() as isize)
+            } else {
+                let start = chars.len() - chars.trim_start().len();
+                let rest = &chars[start + marker.len()..];
+                let removed_space = rest.starts_with(' ');
+                let rest = if removed_space { &rest[1..] } else { rest };
+                let removed = marker.chars().count() + removed_space as usize;
+                let changed = format!("{}{}", &chars[..start], rest);
+                (indent(chars), changed, -(removed as isize))
+            };
+
+            // Keep the cursor on the same char.
+            if y == self.cursor_position.y && self.cursor_position.x >= at {
+                let x = (self.cursor_position.x as isize + shift).max(at as isize);
+                self.cursor_position.x = x as usize;
+            }
+            self.replace_row_chars(y, changed);
+        }
+        self.dirty = true;
+    }
+
+    // Swap the chars either side of the cursor and move past them, like
+    // readline. At the end of a line the last two chars are swapped.
+    fn transpose_chars(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let mut chars: Vec<char> = match self.get_current_row() {
+            Some(row) => row.chars.chars().collect(),
+            None => return,
+        };
+        let x = cmp::min(self.cursor_position.x, chars.len());
+        if x == 0 || chars.len() < 2 {
+            return;
+        }
+        let x = if x == chars.len() { x - 1 } else { x };
+        chars.swap(x - 1, x);
+        self.cursor_position.x = x + 1;
+        self.replace_row_chars(self.cursor_position.y, chars.into_iter().collect());
+        self.dirty = true;
+    }
+
+    // Swap the word before the cursor with the word after it and move past
+    // both, like readline. At the end of a line the last two words are
+    // swapped.
+    fn transpose_words(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let chars: Vec<char> = match self.get_current_row() {
+            Some(row) => row.chars.chars().collect(),
+            None => return,
+        };
+        let forward_word = |mut i: usize| {
+            while i < chars.len() && is_separator(chars[i]) {
+                i += 1;
+            }
+            while i < chars.len() && !is_separator(chars[i]) {
+                i += 1;
+            }
+            i
+        };
+        let backward_word = |mut i: usize| {
+            while i > 0 && is_separator(chars[i - 1]) {
+                i -= 1;
+            }
+            while i > 0 && !is_separator(chars[i - 1]) {
+                i -= 1;
+            }
+            i
+        };
+
+        let x = cmp::min(self.cursor_position.x, chars.len());
+        let second_start = backward_word(forward_word(x));
+        let second_end = forward_word(second_start);
+        let first_start = backward_word(second_start);
+        let first_end = forward_word(first_start);
+        if first_start == second_start || first_end > second_start {
+            return;
+        }
+
+        let transposed = chars[..first_start]
+            .iter()
+            .chain(&chars[second_start..second_end])
+            .chain(&chars[first_end..second_start])
+            .chain(&chars[first_start..first_end])
+            .chain(&chars[second_end..])
+            .collect();
+        self.cursor_position.x = second_end;
+        self.replace_row_chars(self.cursor_position.y, transposed);
+        self.dirty = true;
+    }
+
+    // Change the case of the word under the cursor, or the next one if the
+    // cursor isn't on a word, and move past it.
+    fn change_word_case(&mut self, case: Case) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let chars: Vec<char> = match self.get_current_row() {
+            Some(row) => row.chars.chars().collect(),
+            None => return,
+        };
+        let mut start = cmp::min(self.cursor_position.x, chars.len());
+        if start < chars.len() && !is_separator(chars[start]) {
+            while start > 0 && !is_separator(chars[start - 1]) {
+                start -= 1;
+            }
+        } else {
+            while start < chars.len() && is_separator(chars[start]) {
+                start += 1;
+            }
+        }
+        let mut end = start;
+        while end < chars.len() && !is_separator(chars[end]) {
+            end += 1;
+        }
+        if start == end {
+            return;
+        }
+
+        // The case mapping can change the number of chars.
+        let word = case.apply(&chars[start..end].iter().collect::<String>());
+        self.cursor_position.x = start + word.chars().count();
+        let changed = chars[..start]
+            .iter()
+            .copied()
+            .chain(word.chars())
+            .chain(chars[end..].iter().copied())
+            .collect();
+        self.replace_row_chars(self.cursor_position.y, changed);
+        self.dirty = true;
+    }
+
+    // Complete the word before the cursor from the other words in the
+    // buffer, as far as they agree. Pressing it again cycles through them.
+    fn complete_word(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let Position { x, y } = self.cursor_position;
+        let chars: Vec<char> = match self.get_current_row() {
+            Some(row) => row.chars.chars().collect(),
+            None => return,
+        };
+
+        if let Some(completion) = &mut self.completion {
+            let index = completion.index.map_or(0, |index| index + 1) % completion.candidates.len();
+            completion.index = Some(index);
+            let start = completion.start.x;
+            let word = completion.candidates[index].clone();
+            let message = Editor::completion_message(&completion.candidates, index);
+            for _ in start..x {
+                self.delete_char_in_row(y, start);
+            }
+            self.cursor_position.x = start;
+            self.insert_completion(&word);
+            self.set_status_message(&message);
+            return;
+        }
+
+        let x = cmp::min(x, chars.len());
+        let mut start = x;
+        while start > 0 && !is_separator(chars[start - 1]) {
+            start -= 1;
+        }
+        if start == x {
+            self.set_status_message("Nothing to complete");
+            return;
+        }
+        let prefix: String = chars[start..x].iter().collect();
+
+        let mut candidates: Vec<String> = self
+            .rows
+            .iter()
+            .flat_map(|row| row.chars.split(is_separator))
+            .filter(|word| word.starts_with(&prefix) && word.len() > prefix.len())
+            .map(String::from)
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        if candidates.is_empty() {
+            self.set_status_message("No completions");
+            return;
+        }
+
+        let common = common_prefix(&candidates).to_string();
+        if candidates.len() == 1 || common.len() > prefix.len() {
+            self.insert_completion(&common[prefix.len()..]);
+            if candidates.len() > 1 {
+                self.set_status_message(&candidates.join(" "));
+                self.completion = Some(Completion {
+                    start: Position { x: start, y },
+                    candidates,
+                    index: None,
+                });
+            }
+        } else {
+            self.insert_completion(&candidates[0][prefix.len()..]);
+            self.set_status_message(&Editor::completion_message(&candidates, 0));
+            self.completion = Some(Completion {
+                start: Position { x: start, y },
+                candidates,
+                index: Some(0),
+            });
+        }
+    }
+
+    fn insert_completion(&mut self, text: &str) {
+        for c in text.chars() {
+            self.insert_char_in_row(self.cursor_position.y, self.cursor_position.x, c);
+            self.cursor_position.x += 1;
+        }
+        if !text.is_empty() {
+            self.dirty = true;
+        }
+    }
+
+    fn completion_message(candidates: &[String], index: usize) -> String {
+        candidates
+            .iter()
+            .enumerate()
+            .map(|(i, candidate)| {
+                if i == index {
+                    format!("[{}]", candidate)
+                } else {
+                    candidate.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn insert_newline(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        if self.cursor_position.x == 0 {
+            self.insert_row(self.cursor_position.y, "");
+        } else {
+            let row = &mut self.rows[self.cursor_position.y];
+            let split_at = row.byte_index(self.cursor_position.x);
+            let new_row_contents = row.chars.split_at(split_at).1.to_string();
+
+            row.chars.truncate(split_at);
+
+            // Highlight the top half first, so the new row carries on from
+            // what's left of it rather than from the whole line.
+            self.update_row(self.cursor_position.y);
+            self.insert_row(self.cursor_position.y + 1, &new_row_contents);
+        }
+        self.cursor_position.y += 1;
+        self.cursor_position.x = 0;
+
+        // Scroll ahead so that there's room to carry on typing. A margin of
+        // more than half the screen would keep the cursor above the middle.
+        let margin = cmp::min(self.config.newline_scroll_margin, self.text_rows() / 2);
+        if margin > 0 {
+            let bottom = self.cursor_position.y + margin + 1;
+            if bottom > self.text_offset.y + self.text_rows() {
+                self.text_offset.y = bottom.saturating_sub(self.text_rows());
+            }
+        }
+    }
+
+    // Start an empty line below or above the cursor's, leaving the current
+    // line as it is.
+    fn open_line(&mut self, below: bool) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let y = cmp::min(self.cursor_position.y, self.rows.len());
+        let y = if below && y < self.rows.len() {
+            y + 1
+        } else {
+            y
+        };
+        self.insert_row(y, "");
+        self.cursor_position = Position { x: 0, y };
+        self.goal_column = None;
+    }
+
+    fn delete_row(&mut self, index: usize) {
+        if index >= self.rows.len() {
+            return;
+        }
+
+        let row = self.rows.remove(index);
+        if row.too_long {
+            self.long_lines -= 1;
+        }
+        if row.bad_indent > 0 {
+            self.bad_indents -= 1;
+        }
+        // The row that's moved up now follows a different row.
+        self.update_bad_indent(index);
+        if self.update_csv_widths(&row.csv_widths, &[]) {
+            self.update_all_rows();
+        }
+        self.shift_marks(index, false);
+        self.marks
+            .insert(LAST_EDIT_MARK, Position { x: 0, y: index });
+        // Stale rows after this one have moved up.
+        self.stale_highlight_from = self.stale_highlight_from.map(|from| from.min(index));
+        // The row that's moved up now carries on from a different row.
+        self.mark_highlight_stale(index);
+        self.dirty = true;
+    }
+
+    fn reflow_paragraph(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let y = self.cursor_position.y;
+        if y >= self.rows.len() || self.rows[y].chars.trim().is_empty() {
+            return;
+        }
+
+        // Find the blank-line-delimited paragraph around the cursor.
+        let mut start = y;
+        while start > 0 && !self.rows[start - 1].chars.trim().is_empty() {
+            start -= 1;
+        }
+        let mut end = y + 1;
+        while end < self.rows.len() && !self.rows[end].chars.trim().is_empty() {
+            end += 1;
+        }
+
+        let indent: String = self.rows[start]
+            .chars
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .collect();
+
+        // Rejoin the paragraph into words and split it back up into lines
+        // that fit. A word wider than the text width gets a line to itself.
+        let mut lines = Vec::new();
+        let mut line = indent.clone();
+        let mut line_is_empty = true;
+        for row in self.rows.range(start, end) {
+            for word in row.chars.split_whitespace() {
+                if !line_is_empty {
+                    let tab_stop = self.config.tab_stop;
+                    if display_width(&line, tab_stop) + 1 + display_width(word, tab_stop)
+                        > self.config.text_width
+                    {
+                        lines.push(mem::take(&mut line));
+                        if self.config.reflow_hanging_indent {
+                            line.push_str(&indent);
+                        }
+                    } else {
+                        line.push(' ');
+                    }
+                }
+                line.push_str(word);
+                line_is_empty = false;
+            }
+        }
+        lines.push(line);
+
+        if self
+            .rows
+            .range(start, end)
+            .map(|row| &row.chars)
+            .eq(lines.iter())
+        {
+            return;
+        }
+
+        for _ in start..end {
+            self.delete_row(start);
+        }
+        for (i, line) in lines.iter().enumerate() {
+            self.insert_row(start + i, line);
+        }
+        // The row after the paragraph may need its highlighting updated.
+        self.update_row_highlight(start + lines.len());
+
+        self.cursor_position.y = start;
+        self.cursor_position.x = 0;
+    }
+
+    // *** File I/O ***
+
+    // The filename is used as it is. The shell has already expanded ~ and
+    // variables in arguments, and the other callers have full paths.
+    fn open(&mut self, filename: &str) -> Result<(), String> {
+        #[cfg(feature = "compression")]
+        if COMPRESSED_EXTENSIONS
+            .iter()
+            .any(|extension| filename.ends_with(extension))
+        {
+            return self.open_compressed(filename);
+        }
+        let error = |error: io::Error| format!("can't open {}: {}", filename, error);
+        let mut new_file = false;
+        let mut bytes = Vec::new();
+        match File::open(filename) {
+            Ok(mut f) => {
+                f.read_to_end(&mut bytes).map_err(error)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if self.global_config.create_missing_files {
+                    File::create(filename).map_err(error)?;
+                } else {
+                    // It's created when it's saved.
+                    new_file = true;
+                }
+            }
+            Err(e) => return Err(error(e)),
+        }
+
+        if self.load_text(filename, bytes) {
+            self.editorconfig = editorconfig_properties(filename);
+            let path = env::current_dir().map(|dir| dir.join(filename));
+            if let Some(directory) = path.as_deref().ok().and_then(Path::parent) {
+                self.project_filetypes = project_filetypes(directory).unwrap_or_else(|error| {
+                    self.set_status_message(&error);
+                    Vec::new()
+                });
+            }
+            self.detect_filetype();
+            self.restore_marks();
+            // Say so now rather than when saving fails.
+            if new_file {
+                self.set_status_message("[New File]");
+            } else if unistd::access(filename, AccessFlags::W_OK).is_err() {
+                self.read_only = true;
+                self.no_write_permission = true;
+            }
+            self.remember_recent_file();
+            if let Some(path) = self.backup_path().filter(|path| Path::new(path).exists()) {
+                if self.config.backup_idle_secs > 0 {
+                    self.set_status_message(&format!(
+                        "There's a backup of unsaved changes in {}",
+                        path
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Put the file at the top of the recent files list.
+    fn remember_recent_file(&self) {
+        let path = match self
+            .filename
+            .as_ref()
+            .and_then(|filename| std::path::absolute(filename).ok())
+        {
+            Some(path) => path,
+            None => return,
+        };
+        let path = match path.to_str() {
+            Some(path) if !path.contains('\n') => path.to_string(),
+            _ => return,
+        };
+        let mut files = read_recent_files();
+        files.retain(|file| *file != path);
+        files.insert(0, path);
+        files.truncate(RECENT_FILES_SIZE);
+        write_recent_files(&files);
+    }
+
+    fn open_recent_file(&mut self) {
+        let mut files = read_recent_files();
+        // Forget files that have gone.
+        let count = files.len();
+        files.retain(|file| Path::new(file).exists());
+        if files.len() != count {
+            write_recent_files(&files);
+        }
+        if files.is_empty() {
+            self.set_status_message("No recent files");
+            return;
+        }
+
+        self.recent_files = files;
+        self.palette_selection = 0;
+        let query = self.prompt(
+            "Open recent: {} (Use ESC/Arrows/Enter)",
+            Editor::recent_files_callback,
+        );
+        let selected = query.and_then(|query| {
+            self.recent_file_matches(&query)
+                .get(self.palette_selection)
+                .cloned()
+        });
+        self.recent_files.clear();
+
+        let filename = match selected {
+            Some(filename) => filename,
+            None => return,
+        };
+        if self.dirty && !self.confirm("Discard unsaved changes? (y/n)") {
+            self.set_status_message("Open aborted");
+            return;
+        }
+        if let Err(error) = self.switch_to_file(&filename) {
+            self.set_status_message(&error);
+        }
+    }
+
+    // Open another file in the buffer, back where we were if we've had it
+    // open before.
+    fn switch_to_file(&mut self, filename: &str) -> Result<(), String> {
+        self.clear_buffer();
+        self.open(filename)?;
+        let view = std::path::absolute(filename)
+            .ok()
+            .and_then(|path| self.views.get(path.to_str()?).copied());
+        if let Some(view) = view {
+            self.cursor_position.y = cmp::min(view.cursor_position.y, self.rows.len());
+            self.cursor_position.x = self
+                .get_current_row()
+                .map_or(0, |row| cmp::min(view.cursor_position.x, row.char_len));
+            self.text_offset = view.text_offset;
+            self.goal_column = view.goal_column;
+        }
+        Ok(())
+    }
+
+    // The recent files matching a query, best first.
+    fn recent_file_matches(&self, query: &str) -> Vec<String> {
+        let mut matches: Vec<(usize, &String)> = self
+            .recent_files
+            .iter()
+            .filter_map(|file| Some((fuzzy_score(query, file)?, file)))
+            .collect();
+        // Ties stay most recent first.
+        matches.sort_by_key(|&(score, _)| cmp::Reverse(score));
+        matches.into_iter().map(|(_, file)| file.clone()).collect()
+    }
+
+    fn recent_files_callback(&mut self, query: &str, key: Key) -> String {
+        let matches = self.recent_file_matches(query);
+        match key {
+            Key::Arrow(Arrow::Up) | Key::Arrow(Arrow::Left) => {
+                self.palette_selection = self.palette_selection.saturating_sub(1);
+            }
+            Key::Arrow(Arrow::Down) | Key::Arrow(Arrow::Right) => {
+                if self.palette_selection + 1 < matches.len() {
+                    self.palette_selection += 1;
+                }
+            }
+            Key::Enter | Key::Esc => return String::new(),
+            _ => self.palette_selection = 0,
+        }
+
+        if matches.is_empty() {
+            return ": No matching files".to_string();
+        }
+        let shown = matches
+            .iter()
+            .enumerate()
+            .skip(self.palette_selection)
+            .take(PALETTE_MATCHES_SHOWN)
+            .map(|(i, file)| {
+                if i == self.palette_selection {
+                    format!("[{}]", file)
+                } else {
+                    file.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        format!(": {}", shown.join(" | "))
+    }
+
+    // Empty the buffer to open another file in it.
+    fn clear_buffer(&mut self) {
+        if !self.dirty {
+            self.persist_marks();
+        }
+        if let Some(path) = self
+            .filename
+            .as_ref()
+            .and_then(|filename| std::path::absolute(filename).ok())
+            .and_then(|path| Some(path.to_str()?.to_string()))
+        {
+            let view = Pane {
+                cursor_position: self.cursor_position,
+                text_offset: self.text_offset,
+                goal_column: self.goal_column,
+            };
+            self.views.insert(path, view);
+        }
+        self.rows.clear();
+        self.long_lines = 0;
+        self.bad_indents = 0;
+        self.filename = None;
+        self.filetype = None;
+        self.editorconfig.clear();
+        self.project_filetypes.clear();
+        self.detected_line_ending = None;
+        self.detected_final_newline = None;
+        self.had_bom = false;
+        self.encoding = Encoding::Utf8;
+        self.cursor_position = Position { x: 0, y: 0 };
+        self.text_offset = Position { x: 0, y: 0 };
+        self.goal_column = None;
+        self.jump_list.clear();
+        self.jump_index = 0;
+        self.marks.clear();
+        self.selection_anchor = None;
+        if let Some(pane) = &mut self.other_pane {
+            pane.cursor_position = Position { x: 0, y: 0 };
+            pane.text_offset = Position { x: 0, y: 0 };
+            pane.goal_column = None;
+        }
+        self.csv_view = None;
+        self.dirty = false;
+        self.read_only = false;
+        self.no_write_permission = false;
+        self.hex_view = false;
+        self.matches.clear();
+        self.saved_highlight.clear();
+        self.stale_highlight_from = None;
+        self.search_highlight = None;
+        self.update_config();
+    }
+
+    // The file marks are kept in, and the key for this file's marks in it.
+    fn marks_file(&self) -> Option<(String, String)> {
+        if !self.config.persist_marks || self.read_only {
+            return None;
+        }
+        let path = std::path::absolute(self.filename.as_ref()?).ok()?;
+        let path = path.to_str()?;
+        if path.contains(['\t', '\n']) {
+            return None;
+        }
+        Some((format!("{}/marks", state_directory()?), path.to_string()))
+    }
+
+    // Each line of the marks file is a path, a mark, a line and a column,
+    // separated by tabs.
+    fn restore_marks(&mut self) {
+        let (marks_file, key) = match self.marks_file() {
+            Some(marks_file) => marks_file,
+            None => return,
+        };
+        let contents = std::fs::read_to_string(marks_file).unwrap_or_default();
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (mark, y, x) = match fields[..] {
+                [path, mark, y, x] if path == key => (mark, y, x),
+                _ => continue,
+            };
+            if let (Some(mark), Ok(y), Ok(x)) = (mark.chars().next(), y.parse(), x.parse()) {
+                // The file may have been changed by something else.
+                if y <= self.rows.len() {
+                    self.marks.insert(mark, Position { x, y });
+                }
+            }
+        }
+    }
+
+    // Best effort: not being able to remember marks isn't worth bothering
+    // anyone about.
+    fn persist_marks(&self) {
+        let (marks_file, key) = match self.marks_file() {
+            Some(marks_file) => marks_file,
+            None => return,
+        };
+        let old_contents = std::fs::read_to_string(&marks_file).unwrap_or_default();
+        let mut contents: String = old_contents
+            .lines()
+            .filter(|line| line.split('\t').next() != Some(key.as_str()))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let mut marks: Vec<_> = self.marks.iter().collect();
+        marks.sort_by_key(|&(&mark, _)| mark);
+        for (mark, position) in marks {
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                key, mark, position.y, position.x
+            ));
+        }
+        if let Some(directory) = Path::new(&marks_file).parent() {
+            let _ = std::fs::create_dir_all(directory);
+        }
+        let _ = std::fs::write(marks_file, contents);
+    }
+
+    // Fill the buffer with the contents of a file, returning whether they
+    // could be shown as text.
+    fn load_text(&mut self, filename: &str, bytes: Vec<u8>) -> bool {
+        let mut bytes = match self.encoding.decode(bytes) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                self.set_status_message(&format!("Not opening {}: {}", filename, error));
+                return false;
+            }
+        };
+        self.had_bom = bytes.starts_with(UTF8_BOM.as_bytes());
+        if self.had_bom {
+            bytes.drain(..UTF8_BOM.len());
+        }
+        if Editor::looks_binary(&bytes) {
+            self.set_status_message(&format!(
+                "Not opening {}: it looks like a binary file (use --hex to view it)",
+                filename
+            ));
+            return false;
+        }
+        let contents = match String::from_utf8(bytes) {
+            Ok(contents) => contents,
+            Err(_) => {
+                self.set_status_message(&format!(
+                    "Not opening {}: it isn't valid UTF-8 (try --encoding latin-1)",
+                    filename
+                ));
+                return false;
+            }
+        };
+
+        let (lines, endings) = split_lines(&contents);
+        // Saving uses the most common line ending for every line.
+        let count = |line_ending| endings.iter().filter(|&&e| e == line_ending).count();
+        let counts = [LineEnding::Lf, LineEnding::CrLf, LineEnding::Cr].map(|e| (e, count(e)));
+        self.detected_line_ending = counts
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .max_by_key(|(_, count)| *count)
+            .map(|(line_ending, _)| *line_ending);
+        self.detected_final_newline = if contents.is_empty() {
+            None
+        } else {
+            Some(endings.len() == lines.len())
+        };
+        let mixed = counts.iter().filter(|(_, count)| *count > 0).count() > 1;
+        let mut last_progress = Instant::now();
+        let mut shown_progress = false;
+        for (i, line) in lines.into_iter().enumerate() {
+            self.insert_row(self.rows.len(), line);
+            // Checking the time for every line would slow things down.
+            if i % 1024 == 0 && last_progress.elapsed() >= Duration::from_millis(LOAD_PROGRESS_MS) {
+                self.draw_load_progress();
+                last_progress = Instant::now();
+                shown_progress = true;
+            }
+        }
+        if shown_progress {
+            self.set_status_message("");
+        }
+
+        self.filename = Some(filename.to_string());
+        self.dirty = false;
+        // Loading the file isn't an edit.
+        self.marks.clear();
+
+        // Both warnings go in the one message so neither hides the other.
+        let mut warnings = Vec::new();
+        if mixed {
+            warnings.push(format!(
+                "mixed line endings. Saving will use {} for every line",
+                self.detected_line_ending.unwrap().name()
+            ));
+        }
+        let deceptive = self.count_deceptive_chars();
+        if deceptive > 0 {
+            warnings.push(format!(
+                "{} invisible or bidi control character{} found. \
+                 Use the reveal or strip command to see or remove them",
+                deceptive,
+                if deceptive == 1 { "" } else { "s" }
+            ));
+        }
+        if !warnings.is_empty() {
+            self.set_status_message(&format!("Warning: {}", warnings.join("; ")));
+        }
+        true
+    }
+
+    // Fetch a file into a read-only buffer named after the URL.
+    #[cfg(feature = "url")]
+    fn open_url(&mut self, url: &str) -> Result<(), String> {
+        // ureq's errors already start with the URL.
+        let response = ureq::get(url)
+            .call()
+            .map_err(|error| format!("can't fetch {}", error))?;
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .map_err(|error| format!("can't fetch {}: {}", url, error))?;
+
+        if self.load_text(url, bytes) {
+            self.read_only = true;
+            self.detect_filetype();
+        }
+        Ok(())
+    }
+
+    // Decompress a file by its extension into a read-only buffer. A corrupt
+    // file can still give some output, but none of it is shown.
+    #[cfg(feature = "compression")]
+    fn open_compressed(&mut self, filename: &str) -> Result<(), String> {
+        let file =
+            File::open(filename).map_err(|error| format!("can't open {}: {}", filename, error))?;
+        let mut reader = io::BufReader::new(file);
+        let mut bytes = Vec::new();
+        let result = if filename.ends_with(".gz") {
+            flate2::bufread::MultiGzDecoder::new(reader)
+                .read_to_end(&mut bytes)
+                .map(|_| ())
+        } else if filename.ends_with(".bz2") {
+            bzip2::bufread::MultiBzDecoder::new(reader)
+                .read_to_end(&mut bytes)
+                .map(|_| ())
+        } else {
+            lzma_rs::xz_decompress(&mut reader, &mut bytes)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+        };
+        result.map_err(|error| format!("can't decompress {}: {}", filename, error))?;
+
+        if self.load_text(filename, bytes) {
+            self.read_only = true;
+            self.editorconfig = editorconfig_properties(filename);
+            self.detect_filetype();
+        }
+        Ok(())
+    }
+
+    // Open a file as a read-only hex dump.
+    fn open_hex(&mut self, filename: &str) {
+        let bytes = match std::fs::read(filename) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                self.set_status_message(&format!("Can't open {}: {}", filename, error));
+                return;
+            }
+        };
+
+        for (i, chunk) in bytes.chunks(HEX_BYTES_PER_ROW).enumerate() {
+            let mut line = format!("{:08x}  ", i * HEX_BYTES_PER_ROW);
+            for j in 0..HEX_BYTES_PER_ROW {
+                match chunk.get(j) {
+                    Some(byte) => line.push_str(&format!("{:02x} ", byte)),
+                    None => line.push_str("   "),
+                }
+                if j == HEX_BYTES_PER_ROW / 2 - 1 {
+                    line.push(' ');
+                }
+            }
+            line.push_str(" |");
+            line.extend(chunk.iter().map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            }));
+            line.push('|');
+            self.insert_row(self.rows.len(), &line);
+        }
+
+        self.filename = Some(filename.to_string());
+        self.marks.clear();
+        self.hex_view = true;
+        self.read_only = true;
+        self.dirty = false;
+    }
+
+    // Guess whether a file is binary from its first few KB: text files don't
+    // have NUL bytes and are mostly printable.
+    fn looks_binary(bytes: &[u8]) -> bool {
+        let sample = &bytes[..cmp::min(bytes.len(), BINARY_SAMPLE_SIZE)];
+        if sample.contains(&0) {
+            return true;
+        }
+        let control_bytes = sample
+            .iter()
+            .filter(|&&b| b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b))
+            .count();
+        control_bytes * 10 > sample.len()
+    }
+
+    fn rows_to_string(&self) -> String {
+        let line_ending = self.config.line_ending.as_str();
+        let mut file_contents = if self.had_bom {
+            UTF8_BOM.to_string()
+        } else {
+            String::new()
+        };
+        file_contents.push_str(
+            &self
+                .rows
+                .iter()
+                .map(|row| row.chars.as_str())
+                .collect::<Vec<_>>()
+                .join(line_ending),
+        );
+
+        if self.config.insert_final_newline && !self.rows.is_empty() {
+            file_contents.push_str(line_ending);
+        }
+        file_contents
+    }
+
+    fn trim_trailing_whitespace(&mut self) {
+        for y in 0..self.rows.len() {
+            let trimmed_length = self.rows[y].chars.trim_end().len();
+            if trimmed_length != self.rows[y].chars.len() {
+                self.rows[y].chars.truncate(trimmed_length);
+                self.update_row(y);
+            }
+        }
+
+        let row_length = self.get_current_row().map_or(0, |row| row.char_len);
+        self.cursor_position.x = cmp::min(self.cursor_position.x, row_length);
+    }
+
+    fn save(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        if self.filename.is_none() {
+            let filename = match self.prompt_with_completion(
+                "Save as: {} (ESC to cancel)",
+                |_, _, _| String::new(),
+                Some(complete_path),
+            ) {
+                Some(filename) => filename,
+                None => {
+                    self.set_status_message("Save aborted");
+                    return;
+                }
+            };
+
+            let filename = match expand_path(&filename, self.config.unset_variable_is_error) {
+                Ok(filename) => filename,
+                Err(error) => {
+                    self.set_status_message(&format!("Save failed: {}", error));
+                    return;
+                }
+            };
+
+            let path = Path::new(&filename);
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                    self.set_status_message(&format!(
+                        "Save failed: directory {} does not exist",
+                        parent.display()
+                    ));
+                    return;
+                }
+            }
+            if path.exists() && !self.confirm("File exists. Overwrite? (y/n)") {
+                self.set_status_message("Save aborted");
+                return;
+            }
+
+            self.filename = Some(filename);
+            self.editorconfig = editorconfig_properties(self.filename.as_ref().unwrap());
+            self.detect_filetype();
+        }
+
+        if self.config.trim_trailing_whitespace {
+            self.trim_trailing_whitespace();
+        }
+        // Before the file is truncated.
+        let bytes = match self.file_bytes() {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                self.set_status_message(&format!("Save failed: {}", error));
+                return;
+            }
+        };
+
+        match Editor::create_file(self.filename.as_ref().unwrap()) {
+            Ok((file, before)) => match self.write_file(file, before, &bytes) {
+                Ok((length, None)) => self.set_status_message(&format!("{} bytes written", length)),
+                Ok((length, Some(warning))) => {
+                    self.set_status_message(&format!("{} bytes written, but {}", length, warning))
+                }
+                // An error here means the file contents are lost. Oh well.
+                Err(error) => self.set_status_message(&format!("Save failed: {}", error)),
+            },
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                if self.confirm("Permission denied. Save with sudo? (y/n)") {
+                    self.save_with_sudo(bytes);
+                } else {
+                    self.set_status_message("Save aborted");
+                }
+            }
+            Err(error) => self.set_status_message(&format!("Save failed: {:?}", error)),
+        }
+    }
+
+    // The rows as they're saved, in the file's encoding.
+    fn file_bytes(&self) -> Result<Vec<u8>, String> {
+        self.encoding.encode(&self.rows_to_string())
+    }
+
+    // Open the file to be written over, along with what it was like before,
+    // since truncating it can clear its setuid and setgid bits.
+    fn create_file(filename: &str) -> io::Result<(File, Option<std::fs::Metadata>)> {
+        let before = std::fs::metadata(filename).ok();
+        Ok((File::create(filename)?, before))
+    }
+
+    // Everything that goes with the buffer having been written to its file.
+    fn finish_save(&mut self) {
+        self.dirty = false;
+        self.remove_backup();
+        self.persist_marks();
+        self.remember_recent_file();
+    }
+
+    // Returns how much was written, and a warning if the file's permissions
+    // or owner couldn't be kept.
+    fn write_file(
+        &mut self,
+        mut file: File,
+        before: Option<std::fs::Metadata>,
+        bytes: &[u8],
+    ) -> io::Result<(usize, Option<String>)> {
+        file.write_all(bytes)?;
+        self.finish_save();
+        let warning = before.and_then(|before| Editor::restore_metadata(&file, &before).err());
+        Ok((bytes.len(), warning))
+    }
+
+    // Put back the owner and mode a file had before it was written. Writing
+    // can clear the setuid and setgid bits, and changing the owner does too.
+    fn restore_metadata(file: &File, before: &std::fs::Metadata) -> Result<(), String> {
+        let metadata = || {
+            file.metadata()
+                .map_err(|error| format!("can't check its permissions: {}", error))
+        };
+        let after = metadata()?;
+        if (after.uid(), after.gid()) != (before.uid(), before.gid()) {
+            std::os::unix::fs::fchown(file, Some(before.uid()), Some(before.gid()))
+                .map_err(|error| format!("couldn't keep its owner: {}", error))?;
+        }
+        if metadata()?.mode() != before.mode() {
+            file.set_permissions(before.permissions())
+                .map_err(|error| format!("couldn't keep its permissions: {}", error))?;
+            // The setgid bit is quietly dropped if we aren't in the group.
+            if metadata()?.mode() != before.mode() {
+                return Err(format!(
+                    "its mode changed from {:o} to {:o}",
+                    before.mode() & 0o7777,
+                    metadata()?.mode() & 0o7777
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Save without asking anything, for buffers that already have a file.
+    fn auto_save(&mut self) {
+        if !self.dirty || self.read_only || self.filename.is_none() {
+            return;
+        }
+        let bytes = match self.file_bytes() {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                self.set_status_message(&format!("Auto-save failed: {}", error));
+                return;
+            }
+        };
+        let result = Editor::create_file(self.filename.as_ref().unwrap())
+            .and_then(|(file, before)| self.write_file(file, before, &bytes));
+        match result {
+            Ok((_, None)) => {}
+            Ok((_, Some(warning))) => {
+                self.set_status_message(&format!("Auto-saved, but {}", warning))
+            }
+            Err(error) => self.set_status_message(&format!("Auto-save failed: {}", error)),
+        }
+    }
+
+    // How long until the buffer should be auto-saved, if it should be.
+    fn auto_save_timeout(&self) -> Option<Duration> {
+        if self.config.auto_save_secs == 0 || !self.dirty || self.filename.is_none() {
+            return None;
+        }
+        let deadline = self.clean_at + Duration::from_secs(self.config.auto_save_secs);
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn backup_path(&self) -> Option<String> {
+        let filename = self.filename.as_ref()?;
+        Some(format!("{}{}", filename, self.config.backup_suffix))
+    }
+
+    // How long until the unsaved changes should be backed up, if they should
+    // be.
+    fn backup_timeout(&self) -> Option<Duration> {
+        if self.config.backup_idle_secs == 0
+            || !self.dirty
+            || self.backed_up
+            || self.read_only
+            || self.filename.is_none()
+        {
+            return None;
+        }
+        let deadline = self.last_key_at + Duration::from_secs(self.config.backup_idle_secs);
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // Copy the buffer next to the file, so that the changes survive a crash.
+    // It's only readable by us, since the file might not be.
+    fn write_backup(&mut self) {
+        let path = match self.backup_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(self.rows_to_string().as_bytes()));
+        match result {
+            Ok(()) => self.backed_up = true,
+            Err(error) => {
+                self.set_status_message(&format!("Backup failed: {}", error));
+                // Don't keep trying until there are more changes.
+                self.backed_up = true;
+            }
+        }
+    }
+
+    // The backup isn't needed once the changes are saved.
+    fn remove_backup(&mut self) {
+        if self.config.backup_idle_secs == 0 {
+            return;
+        }
+        if let Some(path) = self.backup_path() {
+            let _ = std::fs::remove_file(path);
+        }
+        self.backed_up = false;
+    }
+
+    // Write the file through `sudo tee`, like `:w !sudo tee %` in Vim. sudo
+    // can't ask for a password itself since the input thread owns the
+    // terminal, so we ask for it and have sudo check it first. The write then
+    // only works if sudo remembers it, so the contents are never read as a
+    // password.
+    fn save_with_sudo(&mut self, bytes: Vec<u8>) {
+        let filename = self.filename.clone().unwrap();
+        let cached = Command::new("sudo")
+            .args(["-n", "true"])
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !cached {
+            let password = match self.prompt_password("[sudo] password (ESC to cancel):") {
+                Some(password) => password,
+                None => {
+                    self.set_status_message("Save aborted");
+                    return;
+                }
+            };
+            if let Err(error) = Editor::sudo_validate(&password) {
+                self.set_status_message(&format!("Save failed: {}", error));
+                return;
+            }
+        }
+
+        let child = Command::new("sudo")
+            .args(["-n", "tee", "--", &filename])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(error) => {
+                self.set_status_message(&format!("Save failed: can't run sudo: {}", error));
+                return;
+            }
+        };
+        // tee may exit early if sudo fails, so a write error is reported
+        // through its exit status below.
+        let _ = child.stdin.take().unwrap().write_all(&bytes);
+        match child.wait_with_output() {
+            Ok(output) if output.status.success() => {
+                self.set_status_message(&format!("{} bytes written with sudo", bytes.len()));
+                self.finish_save();
+            }
+            Ok(output) => {
+                let error = String::from_utf8_lossy(&output.stderr);
+                self.set_status_message(&format!("Save failed: {}", error.trim()));
+            }
+            Err(error) => self.set_status_message(&format!("Save failed: {}", error)),
+        }
+    }
+
+    // Have sudo check a password and remember it for a while. It's all that
+    // sudo is given to read, so it only gets one try.
+    fn sudo_validate(password: &str) -> Result<(), String> {
+        let mut child = Command::new("sudo")
+            .args(["-S", "-p", "", "-v"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("can't run sudo: {}", error))?;
+        let _ = child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(format!("{}\n", password).as_bytes());
+        let output = child
+            .wait_with_output()
+            .map_err(|error| error.to_string())?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let error = String::from_utf8_lossy(&output.stderr);
+        match error.lines().rfind(|line| !line.trim().is_empty()) {
+            Some(line) => Err(line.trim().to_string()),
+            None => Err("sudo didn't accept the password".to_string()),
+        }
+    }
+
+    // *** Find ***
+
+    fn find_callback(&mut self, query: &str, key: Key) -> String {
+        if !self.saved_highlight.is_empty() {
+            std::mem::swap(
+                &mut self.saved_highlight,
+                &mut self.rows[self.saved_highlight_index].highlight,
+            );
+            self.saved_highlight.clear();
+            self.saved_highlight_index = 0;
+        }
+
+        if query.is_empty() {
+            return String::new();
+        }
+
+        // Only compile the query when it changes, not when moving between
+        // matches.
+        if self
+            .find_regex
+            .as_ref()
+            .is_none_or(|(cached_query, _)| cached_query != query)
+        {
+            match self.search_regex(query) {
+                Ok(re) => self.find_regex = Some((query.to_string(), re)),
+                _ => {
+                    self.find_regex = None;
+                    return ": Invalid regex".to_string();
+                }
+            }
+        }
+        let regex = self.find_regex.as_ref().unwrap().1.clone();
+
+        match key {
+            Key::Esc | Key::Enter => {
+                self.matches.clear();
+                self.match_index = 0;
+                return String::new();
+            }
+            Key::Arrow(Arrow::Left) | Key::Arrow(Arrow::Up) => {
+                self.match_index = if self.match_index == 0 {
+                    self.matches.len() - 1
+                } else {
+                    self.match_index - 1
+                };
+            }
+            Key::Arrow(Arrow::Right) | Key::Arrow(Arrow::Down) => {
+                self.match_index = if self.match_index == self.matches.len() - 1 {
+                    0
+                } else {
+                    self.match_index + 1
+                };
+            }
+            _ => {
+                self.matches.clear();
+                self.match_index = 0;
+                for (i, row) in self.rows.iter().enumerate() {
+                    if regex.is_match(&row.chars) {
+                        self.matches.push(i);
+                    }
+                }
+            }
+        }
+
+        if self.matches.is_empty() {
+            return ": No results".to_string();
+        }
+
+        // The match might be somewhere that hasn't been highlighted yet.
+        self.update_stale_highlights(self.matches[self.match_index] + 1);
+        let row = &self.rows[self.matches[self.match_index]];
+        // TODO: Only finds the first match in each line.
+        let row_index = regex.find(&row.chars).unwrap();
+        self.cursor_position.y = self.matches[self.match_index];
+        // Translate the byte offsets into char offsets.
+        let mut start = 0;
+        let mut end = 0;
+        for (i, (byte_offset, _)) in row.chars.char_indices().enumerate() {
+            if byte_offset == row_index.start() {
+                start = i;
+            }
+            if byte_offset == row_index.end() - 1 {
+                end = i;
+                break;
+            }
+        }
+
+        self.cursor_position.x = start;
+
+        // Highlight the match.
+        let render_start = self.get_render_index(start, self.cursor_position.y);
+        let render_end = self.get_render_index(end, self.cursor_position.y);
+        let row = &mut self.rows[self.matches[self.match_index]];
+        if row.highlight.len() <= render_end {
+            row.highlight.resize(render_end + 1, Highlight::Normal);
+        }
+        self.saved_highlight_index = self.matches[self.match_index];
+        self.saved_highlight = row.highlight.clone();
+
+        if self.config.center_on_jump {
+            self.center_view();
+        } else {
+            self.text_offset.y = self.cursor_position.y;
+        }
+
+        let row = &mut self.rows[self.matches[self.match_index]];
+
+        for i in render_start..render_end + 1 {
+            row.highlight[i] = Highlight::Match;
+        }
+
+        format!(
+            ": {} out of {} results",
+            self.match_index + 1,
+            self.matches.len()
+        )
+    }
+
+    fn search_regex(&self, query: &str) -> Result<Regex, regex::Error> {
+        if !self.config.smart_case || query.contains("(?i") || query.contains("(?-i") {
+            return Regex::new(query);
+        }
+        // Escapes like \S and \W aren't uppercase letters to search for.
+        let mut chars = query.chars();
+        let mut has_uppercase = false;
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                chars.next();
+            } else if c.is_uppercase() {
+                has_uppercase = true;
+                break;
+            }
+        }
+        if has_uppercase {
+            Regex::new(query)
+        } else {
+            Regex::new(&format!("(?i){}", query))
+        }
+    }
+
+    fn find(&mut self) {
+        let saved_cursor_position = self.cursor_position;
+        let saved_text_offset = self.text_offset;
+
+        match self.prompt("Search: {} (Use ESC/Arrows/Enter)", Editor::find_callback) {
+            Some(query) => {
+                if self.cursor_position != saved_cursor_position {
+                    self.record_jump(saved_cursor_position);
+                }
+                if self.config.highlight_search {
+                    self.search_highlight = self
+                        .find_regex
+                        .as_ref()
+                        .filter(|(cached_query, _)| *cached_query == query)
+                        .map(|(_, regex)| regex.clone());
+                }
+            }
+            None => {
+                self.cursor_position = saved_cursor_position;
+                self.text_offset = saved_text_offset;
+            }
+        }
+    }
+
+    // Replace every match of a regex, after showing what would change. The
+    // replacement can refer to groups with $1 or ${name}.
+    fn replace_all(&mut self) {
+        if !self.ensure_writable() {
+            return;
+        }
+        let query = match self.prompt("Replace: {} (ESC to cancel)", |_, _, _| String::new()) {
+            Some(query) if !query.is_empty() => query,
+            _ => return,
+        };
+        let regex = match self.search_regex(&query) {
+            Ok(regex) => regex,
+            Err(_) => {
+                self.set_status_message("Invalid regex");
+                return;
+            }
+        };
+        let replacement =
+            match self.prompt("Replace with: {} (ESC to cancel)", |_, _, _| String::new()) {
+                Some(replacement) => replacement,
+                None => return,
+            };
+
+        let (changes, count) = self.replacements(&regex, &replacement);
+        if changes.is_empty() {
+            self.set_status_message("No changes to make");
+            return;
+        }
+
+        let preview = self.replacement_preview(&changes);
+        let key = self.page(
+            "replace",
+            preview,
+            "Arrows/PageUp/PageDown to scroll, y to replace, any other key to cancel",
+        );
+        if key != Key::Char('y') {
+            self.set_status_message("Replace cancelled");
+            return;
+        }
+
+        let lines = changes.len();
+        self.apply_replacements(changes);
+        self.set_status_message(&format!(
+            "Replaced {} match{} on {} line{}",
+            count,
+            if count == 1 { "" } else { "es" },
+            lines,
+            if lines == 1 { "" } else { "s" }
+        ));
+    }
+
+    // The rows that replacing every match would change, with what they'd
+    // become, and how many matches there are.
+    fn replacements(&self, regex: &Regex, replacement: &str) -> (Vec<(usize, String)>, usize) {
+        let mut changes = Vec::new();
+        let mut count = 0;
+        for (y, row) in self.rows.iter().enumerate() {
+            let replaced = regex.replace_all(&row.chars, replacement);
+            if replaced != row.chars {
+                count += regex.find_iter(&row.chars).count();
+                changes.push((y, replaced.into_owned()));
+            }
+        }
+        (changes, count)
+    }
+
+    // Each changed row numbered as it is now and then as it would be.
+    fn replacement_preview(&self, changes: &[(usize, String)]) -> Vec<String> {
+        let width = changes.last().map_or(1, |(y, _)| (y + 1).to_string().len());
+        changes
+            .iter()
+            .flat_map(|(y, after)| {
+                [
+                    format!("{:>width$} - {}", y + 1, self.rows[*y].chars, width = width),
+                    format!("{:>width$} + {}", "", after, width = width),
+                ]
+            })
+            .collect()
+    }
+
+    fn apply_replacements(&mut self, changes: Vec<(usize, String)>) {
+        for (y, after) in changes {
+            self.replace_row_chars(y, after);
+        }
+        if let Some(row) = self.get_current_row() {
+            self.cursor_position.x = cmp::min(self.cursor_position.x, row.char_len);
+        }
+        self.dirty = true;
+    }
+
+    // Show how many times a query matches on each row without moving.
+    fn count_matches(&mut self) {
+        let query = match self.prompt("Count: {} (ESC to cancel)", |_, _, _| String::new()) {
+            Some(query) if !query.is_empty() => query,
+            _ => return,
+        };
+        let regex = match self.search_regex(&query) {
+            Ok(regex) => regex,
+            Err(_) => {
+                self.set_status_message("Invalid regex");
+                return;
+            }
+        };
+        self.match_counts = self
+            .rows
+            .iter()
+            .map(|row| {
+                regex
+                    .find_iter(&row.chars)
+                    .filter(|m| !m.is_empty())
+                    .count()
+            })
+            .collect();
+        let total: usize = self.match_counts.iter().sum();
+        let lines = self.match_counts.iter().filter(|&&count| count > 0).count();
+        self.set_status_message(&format!(
+            "{} match{} on {} line{}",
+            total,
+            if total == 1 { "" } else { "es" },
+            lines,
+            if lines == 1 { "" } else { "s" }
+        ));
+    }
+
+    // The char ranges of a row that match the last search.
+    fn search_highlight_ranges(&self, y: usize, window: (usize, usize)) -> Vec<(usize, usize)> {
+        let regex = match &self.search_highlight {
+            Some(regex) => regex,
+            None => return Vec::new(),
+        };
+        let (chars, offset) = self.window_chars(y, window);
+        regex
+            .find_iter(chars)
+            .filter(|m| !m.is_empty())
+            .map(|m| {
+                let start = offset + chars[..m.start()].chars().count();
+                (start, start + m.as_str().chars().count())
+            })
+            .collect()
+    }
+
+    // The chars of a row that could be drawn when the screen starts at
+    // start_index and is width columns wide. That's the whole row unless
+    // it's too long to highlight.
+    fn drawn_window(&self, y: usize, start_index: usize, width: usize) -> (usize, usize) {
+        let row = &self.rows[y];
+        if !self.is_unhighlighted(y) {
+            return (0, row.char_len);
+        }
+        let char_index = |screen_index| {
+            Editor::screen_index_to_char_index(screen_index, Some(row), self.layout())
+        };
+        (
+            char_index(start_index.saturating_sub(LONG_LINE_MARGIN)),
+            char_index(start_index + width + LONG_LINE_MARGIN),
+        )
+    }
+
+    // A window of a row's chars and the index of its first char.
+    fn window_chars(&self, y: usize, (start, end): (usize, usize)) -> (&str, usize) {
+        let row = &self.rows[y];
+        if start == 0 && end == row.char_len {
+            return (&row.chars, 0);
+        }
+        (
+            &row.chars[row.byte_index(start)..row.byte_index(end)],
+            start,
+        )
+    }
+
+    // The identifier the cursor is on, if any.
+    fn word_under_cursor(&self) -> Option<String> {
+        let chars: Vec<char> = self.get_current_row()?.chars.chars().collect();
+        let x = self.cursor_position.x;
+        let mut start = x;
+        while start > 0 && !is_separator(chars[start - 1]) {
+            start -= 1;
+        }
+        let mut end = x;
+        while end < chars.len() && !is_separator(chars[end]) {
+            end += 1;
+        }
+        let word: String = chars[start..end].iter().collect();
+        // Numbers aren't worth highlighting.
+        if word.chars().next()?.is_ascii_digit() {
+            return None;
+        }
+        Some(word)
+    }
+
+    // The char ranges of whole-word occurrences of the word under the cursor
+    // in a row, other than the one the cursor is on.
+    fn cursor_word_ranges(&self, y: usize, window: (usize, usize)) -> Vec<(usize, usize)> {
+        let word = match &self.cursor_word {
+            Some(word) => word,
+            None => return Vec::new(),
+        };
+        let word_length = word.chars().count();
+        let (chars, offset) = self.window_chars(y, window);
+
+        let mut ranges = Vec::new();
+        let mut word_start = None;
+        // Add a separator on the end so that the last word is finished.
+        for (i, c) in chars.chars().chain(std::iter::once(' ')).enumerate() {
+            match (word_start, is_separator(c)) {
+                (None, false) => word_start = Some(i),
+                (Some(start), true) => {
+                    let is_cursor_word = y == self.cursor_position.y
+                        && (offset + start..=offset + i).contains(&self.cursor_position.x);
+                    if i - start == word_length
+                        && !is_cursor_word
+                        && chars.chars().skip(start).take(word_length).eq(word.chars())
+                    {
+                        ranges.push((offset + start, offset + i));
+                    }
+                    word_start = None;
+                }
+                _ => {}
+            }
+        }
+        ranges
+    }
+
+    // *** Commands ***
+
+    fn command_line(&mut self) {
+        if let Some(line) = self.prompt(":{}", |_, _, _| String::new()) {
+            if let Err(error) = self.run_command(&line) {
+                self.set_status_message(&error);
+            }
+        }
+    }
+
+    fn run_command(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        let (command, argument) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        match command {
+            "set" => self.set_option(argument, false),
+            "setglobal" => self.set_option(argument, true),
+            "retab" => self.retab(false),
+            "retab!" => self.retab(true),
+            "reveal" => self.set_option("list_mode", false),
+            "strip" => self.strip_deceptive_chars(),
+            "sort" => self.sort_lines(argument),
+            "writable" => self.make_writable(),
+            "filename" => self.insert_filename(argument),
+            "encoding" => self.reopen_with_encoding(argument),
+            "fileencoding" => self.set_file_encoding(argument),
+            "unique" => {
+                self.remove_duplicate_lines(false);
+                Ok(())
+            }
+            _ => Err(format!("Unknown command: {}", command)),
+        }
+    }
+
+    // Run a line of a --script. On top of the commands there are ones for
+    // what would otherwise need a prompt:
+    //
+    //   goto N, goto +N, goto -N    move to the start of a line
+    //   insert TEXT                 type TEXT, with \n, \t and \\ escapes
+    //   replace /REGEX/REPLACEMENT/ replace every match, with any delimiter
+    //   save                        write the file
+    //   quit, quit!                 stop, even with unsaved changes for quit!
+    //
+    // Returns whether the script should stop.
+    fn run_script_line(&mut self, line: &str) -> Result<bool, String> {
+        // Only one space is taken off, so that inserted text can be indented.
+        let (command, argument) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        match command {
+            "goto" => {
+                let y = self.goto_target(argument.trim())?;
+                self.cursor_position = Position { x: 0, y };
+            }
+            "insert" => {
+                if self.read_only {
+                    return Err("Buffer is read-only".to_string());
+                }
+                for c in unescape_script_text(argument)?.chars() {
+                    if c == '\n' {
+                        self.insert_newline();
+                    } else {
+                        self.insert_char(c);
+                    }
+                }
+            }
+            "replace" => {
+                if self.read_only {
+                    return Err("Buffer is read-only".to_string());
+                }
+                let argument = argument.trim();
+                let delimiter = argument
+                    .chars()
+                    .next()
+                    .ok_or("replace needs /REGEX/REPLACEMENT/")?;
+                let parts: Vec<&str> = argument[delimiter.len_utf8()..].split(delimiter).collect();
+                let (query, replacement) = match parts.as_slice() {
+                    [query, replacement] | [query, replacement, ""] => (*query, *replacement),
+                    _ => return Err(format!("Expected {0}REGEX{0}REPLACEMENT{0}", delimiter)),
+                };
+                let regex = self
+                    .search_regex(query)
+                    .map_err(|_| format!("Invalid regex: {}", query))?;
+                let (changes, _) = self.replacements(&regex, replacement);
+                if !changes.is_empty() {
+                    self.apply_replacements(changes);
+                }
+            }
+            "save" => self.save_without_prompting()?,
+            "quit" if self.dirty => {
+                return Err("There are unsaved changes (use quit! to drop them)".to_string())
+            }
+            "quit" | "quit!" => return Ok(true),
+            // Without an option it lists them all, which needs a terminal.
+            "set" | "setglobal" if argument.trim().is_empty() => {
+                return Err(format!("{} needs an OPTION=VALUE in a script", command))
+            }
+            _ => self.run_command(line)?,
+        }
+        Ok(false)
+    }
+
+    // Save for a script, where there's nobody to ask for a filename or about
+    // sudo.
+    fn save_without_prompting(&mut self) -> Result<(), String> {
+        if self.read_only {
+            return Err("Buffer is read-only".to_string());
+        }
+        let filename = self.filename.clone().ok_or("No filename")?;
+        if self.config.trim_trailing_whitespace {
+            self.trim_trailing_whitespace();
+        }
+        let bytes = self.file_bytes()?;
+        let (_, warning) = Editor::create_file(&filename)
+            .and_then(|(file, before)| self.write_file(file, before, &bytes))
+            .map_err(|error| format!("Save failed: {}", error))?;
+        match warning {
+            Some(warning) => Err(format!("Saved, but {}", warning)),
+            None => Ok(()),
+        }
+    }
+
+    // Edit a file we can't write to anyway, to save it with sudo.
+    fn make_writable(&mut self) -> Result<(), String> {
+        if self.no_write_permission {
+            self.read_only = false;
+            self.no_write_permission = false;
+            self.set_status_message("Saving will need sudo");
+            Ok(())
+        } else if self.read_only {
+            Err("This buffer can't be made writable".to_string())
+        } else {
+            self.set_status_message("Buffer is already writable");
+            Ok(())
+        }
+    }
+
+    // Insert the filename as it was opened, or with "name" just its last
+    // part or with "absolute" its absolute path.
+    fn insert_filename(&mut self, argument: &str) -> Result<(), String> {
+        let filename = match &self.filename {
+            Some(filename) => filename.clone(),
+            None => {
+                self.set_status_message("No filename");
+                return Ok(());
+            }
+        };
+        let text = match argument {
+            "" => filename,
+            "name" => Path::new(&filename)
+                .file_name()
+                .map_or(filename.clone(), |name| name.to_string_lossy().into_owned()),
+            "absolute" => std::path::absolute(&filename)
+                .map_err(|error| format!("Can't get the absolute path: {}", error))?
+                .to_string_lossy()
+                .into_owned(),
+            _ => return Err(format!("Expected name or absolute, not {}", argument)),
+        };
+        for c in text.chars() {
+            self.insert_char(c);
+        }
+        Ok(())
+    }
+
+    // Read the file again as another encoding, for when it isn't UTF-8.
+    fn reopen_with_encoding(&mut self, argument: &str) -> Result<(), String> {
+        let encoding = Encoding::from_name(argument)
+            .ok_or_else(|| format!("Unknown encoding: {}", argument))?;
+        let filename = self.filename.clone().ok_or("No filename")?;
+        if self.dirty {
+            return Err("There are unsaved changes".to_string());
+        }
+        // Check it can be read first, so that the buffer isn't lost.
+        let bytes = std::fs::read(&filename).map_err(|error| error.to_string())?;
+        let decoded = encoding.decode(bytes)?;
+        if std::str::from_utf8(&decoded).is_err() {
+            return Err("It isn't valid utf-8".to_string());
+        }
+
+        let position = self.cursor_position;
+        self.clear_buffer();
+        self.encoding = encoding;
+        self.open(&filename)?;
+        self.cursor_position.y = cmp::min(position.y, self.rows.len());
+        self.cursor_position.x = self
+            .get_current_row()
+            .map_or(0, |row| cmp::min(position.x, row.char_len));
+        Ok(())
+    }
+
+    // Save the file as another encoding from now on.
+    fn set_file_encoding(&mut self, argument: &str) -> Result<(), String> {
+        let encoding = Encoding::from_name(argument)
+            .ok_or_else(|| format!("Unknown encoding: {}", argument))?;
+        if encoding != self.encoding {
+            self.encoding = encoding;
+            self.dirty = true;
+        }
+        self.set_status_message(&format!("Saving as {}", encoding.name()));
+        Ok(())
+    }
+
+    fn count_deceptive_chars(&self) -> usize {
+        self.rows
+            .iter()
+            .map(|row| row.chars.chars().filter(|&c| is_deceptive(c)).count())
+            .sum()
+    }
+
+    fn strip_deceptive_chars(&mut self) -> Result<(), String> {
+        if !self.ensure_writable() {
+            return Ok(());
+        }
+        let count = self.count_deceptive_chars();
+        for y in 0..self.rows.len() {
+            if self.rows[y].chars.contains(is_deceptive) {
+                self.rows[y].chars.retain(|c| !is_deceptive(c));
+                self.update_row(y);
+            }
+        }
+        if count > 0 {
+            self.dirty = true;
+            if let Some(row) = self.get_current_row() {
+                self.cursor_position.x = cmp::min(self.cursor_position.x, row.char_len);
+            }
+        }
+        self.set_status_message(&format!(
+            "Stripped {} character{}",
+            count,
+            if count == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    // Sort every row. The flags are like Vim's: i ignores case, n sorts by
+    // the first number in each line, and u removes duplicates afterwards.
+    fn sort_lines(&mut self, flags: &str) -> Result<(), String> {
+        let (mut ignore_case, mut numeric, mut unique) = (false, false, false);
+        for flag in flags.chars().filter(|c| !c.is_whitespace()) {
+            match flag {
+                'i' => ignore_case = true,
+                'n' => numeric = true,
+                'u' => unique = true,
+                _ => return Err(format!("Unknown sort flag: {}", flag)),
+            }
+        }
+        if !self.ensure_writable() {
+            return Ok(());
+        }
+
+        let mut lines: Vec<String> = self.rows.iter().map(|row| row.chars.clone()).collect();
+        if numeric {
+            // Lines without a number go first, like Vim.
+            lines.sort_by_key(|line| Editor::first_number(line));
+        } else if ignore_case {
+            lines.sort_by_cached_key(|line| line.to_lowercase());
+        } else {
+            lines.sort();
+        }
+
+        let mut changed = 0;
+        for (y, line) in lines.into_iter().enumerate() {
+            if self.rows[y].chars != line {
+                self.rows[y].chars = line;
+                self.update_row(y);
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            self.dirty = true;
+        }
+
+        let removed = if unique {
+            self.remove_duplicate_lines(ignore_case)
+        } else {
+            0
+        };
+        let mut message = format!(
+            "Sorted {} lines, {} moved",
+            self.rows.len() + removed,
+            changed
+        );
+        if unique {
+            message.push_str(&format!(
+                ", {} duplicate{} removed",
+                removed,
+                if removed == 1 { "" } else { "s" }
+            ));
+        }
+        self.set_status_message(&message);
+        Ok(())
+    }
+
+    fn first_number(line: &str) -> Option<i64> {
+        let start = line.find(|c: char| c.is_ascii_digit())?;
+        let end = line[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(line.len(), |end| start + end);
+        let start = if line[..start].ends_with('-') {
+            start - 1
+        } else {
+            start
+        };
+        line[start..end].parse().ok()
+    }
+
+    // Remove rows that are the same as the row before them, returning how
+    // many were removed.
+    fn remove_duplicate_lines(&mut self, ignore_case: bool) -> usize {
+        if !self.ensure_writable() {
+            return 0;
+        }
+        let same = |a: &str, b: &str| {
+            if ignore_case {
+                a.to_lowercase() == b.to_lowercase()
+            } else {
+                a == b
+            }
+        };
+
+        let mut removed = 0;
+        // Go backwards so that deleting doesn't move the rows still to check.
+        for y in (1..self.rows.len()).rev() {
+            if same(&self.rows[y].chars, &self.rows[y - 1].chars) {
+                self.delete_row(y);
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            self.cursor_position.y = cmp::min(self.cursor_position.y, self.rows.len() - 1);
+            if let Some(row) = self.get_current_row() {
+                self.cursor_position.x = cmp::min(self.cursor_position.x, row.char_len);
+            }
+        }
+        self.set_status_message(&format!(
+            "Removed {} duplicate line{}",
+            removed,
+            if removed == 1 { "" } else { "s" }
+        ));
+        removed
+    }
+
+    // Redo the indentation of every row with tabs or spaces to match
+    // expand_tabs, keeping it the same width. With all, tabs after the
+    // indentation are turned into spaces too when expanding tabs.
+    fn retab(&mut self, all: bool) -> Result<(), String> {
+        if !self.ensure_writable() {
+            return Ok(());
+        }
+        let tab_stop = self.config.tab_stop;
+        let expand_tabs = self.config.expand_tabs;
+        let screen_index = self.get_current_screen_index();
+
+        let mut changed = 0;
+        for y in 0..self.rows.len() {
+            let chars = &self.rows[y].chars;
+            let indent_end = chars
+                .find(|c: char| c != ' ' && c != '\t')
+                .unwrap_or(chars.len());
+            let width = display_width(&chars[..indent_end], tab_stop);
+            let mut retabbed = if expand_tabs {
+                " ".repeat(width)
+            } else {
+                "\t".repeat(width / tab_stop) + &" ".repeat(width % tab_stop)
+            };
+
+            let rest = &chars[indent_end..];
+            if all && expand_tabs {
+                for c in rest.chars() {
+                    if c == '\t' {
+                        let column = display_width(&retabbed, tab_stop);
+                        retabbed.push_str(&" ".repeat(tab_stop - column % tab_stop));
+                    } else {
+                        retabbed.push(c);
+                    }
+                }
+            } else {
+                retabbed.push_str(rest);
+            }
+
+            if retabbed != *chars {
+                self.rows[y].chars = retabbed;
+                self.update_row(y);
+                changed += 1;
+            }
+        }
+
+        if changed > 0 {
+            self.dirty = true;
+            // Stay in the same place on the screen.
+            self.cursor_position.x = Editor::screen_index_to_char_index(
+                screen_index,
+                self.get_current_row(),
+                self.layout(),
+            );
+        }
+        self.set_status_message(&format!(
+            "Retabbed {} line{}",
+            changed,
+            if changed == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    // Show all the options with no argument, or change one with name=value or
+    // toggle it with name!. Global options apply to every buffer, unless a
+    // filetype or EditorConfig overrides them.
+    fn set_option(&mut self, argument: &str, global: bool) -> Result<(), String> {
+        if argument.is_empty() {
+            let config = if global {
+                &self.global_config
+            } else {
+                &self.config
+            };
+            let lines = OPTIONS
+                .iter()
+                .map(|option| format!("{} = {}", option.name, (option.get)(config)))
+                .collect();
+            self.show_pager(if global { "setglobal" } else { "set" }, lines);
+            return Ok(());
+        }
+
+        let config = if global {
+            &mut self.global_config
+        } else {
+            &mut self.config
+        };
+        let option = set_config_option(config, argument)?;
+
+        if global {
+            self.update_config();
+        } else {
+            // Options like the tab stop change how rows are rendered.
+            self.update_all_rows();
+        }
+        self.set_status_message(&format!("{} = {}", option.name, (option.get)(&self.config)));
+        Ok(())
+    }
+
+    // *** Output ***
+
+    fn clear_screen(contents: &mut String) {
+        // Clear the whole screen.
+        contents.push_str("\x1b[2J");
+    }
+
+    fn clear_row(contents: &mut String) {
+        // Clear the current row from the cursor to the end.
+        contents.push_str("\x1b[K");
+    }
+
+    fn draw_cursor(contents: &mut String, cursor_position: &Position) {
+        // Move the displayed cursor to a certain position.
+        let s = format!("\x1b[{};{}H", cursor_position.y + 1, cursor_position.x + 1);
+        contents.push_str(&s);
+    }
+
+    fn reset_cursor(contents: &mut String) {
+        // Move the cursor to the top left.
+        Editor::draw_cursor(contents, &Position { x: 0, y: 0 });
+    }
+
+    fn hide_cursor(contents: &mut String) {
+        // Make the cursor invisible.
+        contents.push_str("\x1b[?25l");
+    }
+
+    fn show_cursor(contents: &mut String) {
+        // Make the cursor visible.
+        contents.push_str("\x1b[?25h");
+    }
+
+    fn set_color(contents: &mut String, color: Color) {
+        if NO_COLOR.load(Ordering::Relaxed) {
+            return;
+        }
+        let color_code = match color {
+            Color::Black => "0;30",
+            Color::Red => "0;31",
+            Color::Green => "0;32",
+            Color::Yellow => "0;33",
+            Color::Blue => "0;34",
+            Color::Magenta => "0;35",
+            Color::Cyan => "0;36",
+            Color::White => "0;37",
+            Color::BrightBlack => "1;30",
+            Color::BrightRed => "1;31",
+            Color::BrightGreen => "1;32",
+            Color::BrightYellow => "1;33",
+            Color::BrightBlue => "1;34",
+            Color::BrightMagenta => "1;35",
+            Color::BrightCyan => "1;36",
+            Color::BrightWhite => "1;37",
+            Color::Default => "0;39",
+        };
+        contents.push_str(&format!("\x1b[{}m", color_code));
+    }
+
+    fn set_background(contents: &mut String, color_code: Option<u8>) {
+        if NO_COLOR.load(Ordering::Relaxed) {
+            match color_code {
+                Some(SELECTION_BACKGROUND) | Some(SEARCH_HIGHLIGHT_BACKGROUND) => {
+                    Editor::invert_colors(contents)
+                }
+                _ => Editor::clear_formatting(contents),
+            }
+            return;
+        }
+        match color_code {
+            // Use one of the 256 colors so it can be subtle.
+            Some(color_code) => contents.push_str(&format!("\x1b[48;5;{}m", color_code)),
+            None => contents.push_str("\x1b[49m"),
+        }
+    }
+
+    // Setting a foreground color resets the background, so set it again.
+    fn set_colors(contents: &mut String, color: Color, background: Option<u8>) {
+        Editor::set_color(contents, color);
+        if background.is_some() {
+            Editor::set_background(contents, background);
+        }
+    }
+
+    fn invert_colors(contents: &mut String) {
+        contents.push_str("\x1b[7m");
+    }
+
+    fn clear_formatting(contents: &mut String) {
+        contents.push_str("\x1b[m");
+    }
+
+    fn draw_empty_line_marker(&self, contents: &mut String) {
+        Editor::set_color(contents, self.config.empty_line_marker_color);
+        contents.push_str(&self.config.empty_line_marker);
+        Editor::set_color(contents, Color::Default);
+    }
+
+    // Draw the line number gutter, with a marker instead of the number on
+    // continuation lines of wrapped rows.
+    // link is the file:// URL of the file, for making line numbers clickable.
+    fn draw_gutter(
+        &self,
+        contents: &mut String,
+        line_number: Option<usize>,
+        wrapped: bool,
+        link: Option<&str>,
+    ) {
+        if self.line_number_space() == 0 {
+            return;
+        }
+        contents.push_str(&" ".repeat(self.config.gutter_padding_left));
+        let current_line = self.cursor_position.y + 1;
+        if line_number == Some(current_line) {
+            Editor::set_color(contents, Color::BrightWhite);
+        } else {
+            Editor::set_color(contents, Color::BrightBlack);
+        }
+        match line_number {
+            Some(line_number) => {
+                // Relative numbers are never wider than absolute ones, so the
+                // gutter is the same width in every mode.
+                let shown = match self.config.line_number_mode {
+                    LineNumberMode::Absolute => line_number,
+                    LineNumberMode::Hybrid if line_number == current_line => line_number,
+                    LineNumberMode::Relative | LineNumberMode::Hybrid => {
+                        line_number.abs_diff(current_line)
+                    }
+                };
+                // An OSC 8 hyperlink takes up no space on the screen, and
+                // terminals that don't support it ignore it.
+                if let Some(link) = link {
+                    contents.push_str(&format!("\x1b]8;;{}#L{}\x1b\\", link, line_number));
+                }
+                contents.push_str(&format!(
+                    "{:>width$}",
+                    shown,
+                    width = self.line_number_width()
+                ));
+                if link.is_some() {
+                    contents.push_str("\x1b]8;;\x1b\\");
+                }
+            }
+            None if wrapped => contents.push_str(&format!(
+                "{:>width$}",
+                WRAP_MARKER,
+                width = self.line_number_width()
+            )),
+            None => contents.push_str(&" ".repeat(self.line_number_width())),
+        }
+        contents.push_str(&" ".repeat(self.config.gutter_padding_right));
+        contents.push_str(&self.config.gutter_separator);
+        Editor::set_color(contents, Color::Default);
+    }
+
+    // The file:// URL for the file, if line numbers should link to it.
+    fn gutter_link(&self) -> Option<String> {
+        if !self.config.gutter_links {
+            return None;
+        }
+        let path = std::path::absolute(self.filename.as_ref()?).ok()?;
+        let mut url = "file://".to_string();
+        for byte in path.to_string_lossy().bytes() {
+            if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+                url.push(byte as char);
+            } else {
+                url.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        Some(url)
+    }
+
+    // The lines shown in the middle of the screen when there's nothing in the
+    // buffer.
+    fn welcome_lines(&self) -> Vec<(String, Color)> {
+        if self.filename.is_some() {
+            // An empty file we've opened shouldn't look like a new buffer.
+            return vec![("(empty file)".to_string(), Color::BrightBlack)];
+        }
+
+        let mut lines = vec![
+            (
+                format!("Kilo editor -- version {}", VERSION),
+                Color::Default,
+            ),
+            (String::new(), Color::Default),
+        ];
+        for &action in WELCOME_ACTIONS.iter() {
+            let info = action_info(action);
+            let key = info.key.map_or(String::new(), key_name);
+            lines.push((format!("{:<16}{:>8}", info.name, key), Color::BrightBlack));
+        }
+        lines
+    }
+
+    fn draw_rows(&self, contents: &mut String) {
+        let text_cols = self.text_cols();
+        let welcome_lines = self.welcome_lines();
+        let gutter_link = self.gutter_link();
+
+        // With soft wrap on, a row can take up several lines of the screen.
+        let mut file_row = self.text_offset.y;
+        let mut segments = self.wrap_segments(file_row);
+        let mut segment_index = 0;
+
+        for y in 0..self.text_rows() {
+            let mut filled_line = false;
+            let (segment_start, segment_length, first_segment) = if self.config.soft_wrap {
+                if segment_index == segments.len() {
+                    file_row += 1;
+                    segments = self.wrap_segments(file_row);
+                    segment_index = 0;
+                }
+                let (start, end) = segments[segment_index];
+                segment_index += 1;
+                (start, end - start, segment_index == 1)
+            } else {
+                file_row = y + self.text_offset.y;
+                (self.text_offset.x, text_cols, true)
+            };
+
+            if file_row == self.rows.len() && file_row == self.cursor_position.y {
+                // The cursor can sit on the line after the last row, so give
+                // that line a gutter too, ready for the text that goes there.
+                self.draw_gutter(contents, None, false, None);
+            } else if file_row >= self.rows.len() {
+                let welcome_start = self.text_rows() / 3;
+                if self.rows.is_empty()
+                    && y >= welcome_start
+                    && y - welcome_start < welcome_lines.len()
+                {
+                    let (welcome_message, color) = &welcome_lines[y - welcome_start];
+                    let message_length = cmp::min(
+                        welcome_message.len(),
+                        self.screen_dimensions.cols.saturating_sub(1),
+                    );
+
+                    let mut padding = (self.screen_dimensions.cols - message_length) / 2;
+                    let marker_width =
+                        display_width(&self.config.empty_line_marker, self.config.tab_stop);
+                    if padding >= marker_width {
+                        self.draw_empty_line_marker(contents);
+                        padding -= marker_width;
+                    }
+
+                    for _ in 0..padding {
+                        contents.push(' ');
+                    }
+
+                    Editor::set_color(contents, *color);
+                    contents.push_str(&welcome_message[..message_length]);
+                    Editor::set_color(contents, Color::Default);
+                } else {
+                    self.draw_empty_line_marker(contents);
+                }
+            } else {
+                let line_number = if first_segment {
+                    Some(file_row + 1)
+                } else {
+                    None
+                };
+                self.draw_gutter(
+                    contents,
+                    line_number,
+                    !first_segment,
+                    gutter_link.as_deref(),
+                );
+
+                // Only a line that reaches the edge of the screen is full.
+                filled_line = self.draw_row(contents, file_row, segment_start, segment_length)
+                    && segment_length == text_cols;
+
+                if !filled_line {
+                    let line_length = self.get_screen_index(self.rows[file_row].char_len, file_row);
+                    let used = cmp::min(line_length.saturating_sub(segment_start), segment_length);
+                    let count = self.match_counts.get(file_row).copied().unwrap_or(0);
+                    let note = format!("  [{}]", count);
+                    // Only after the end of the row, if it fits.
+                    if count > 0 && used < segment_length && used + note.len() <= text_cols {
+                        Editor::set_color(contents, Color::BrightBlack);
+                        contents.push_str(&note);
+                        Editor::set_color(contents, Color::Default);
+                    } else if !self.config.soft_wrap {
+                        // Wrapped lines don't line up with the screen columns.
+                        filled_line = self.draw_color_column_after(contents, used);
+                    }
+                }
+            }
+            if !filled_line {
+                Editor::clear_row(contents);
+            }
+
+            contents.push_str("\r\n");
+        }
+    }
+
+    fn in_color_column(&self, screen_index: usize) -> bool {
+        let column = self.config.color_column;
+        column > 0
+            && (screen_index + 1 == column
+                || (self.config.color_column_fill && screen_index + 1 >= column))
+    }
+
+    // Carry the color column on past the end of a row that takes up used
+    // columns of the screen. Returns whether the line was filled.
+    fn draw_color_column_after(&self, contents: &mut String, used: usize) -> bool {
+        let column = self.config.color_column;
+        let fill = self.config.color_column_fill;
+        if column == 0 || (column <= self.text_offset.x && !fill) {
+            return false;
+        }
+        let screen_column = (column - 1).saturating_sub(self.text_offset.x);
+        let start = if fill {
+            cmp::max(screen_column, used)
+        } else {
+            screen_column
+        };
+        let text_cols = self.text_cols();
+        if start < used || start >= text_cols {
+            return false;
+        }
+        contents.push_str(&" ".repeat(start - used));
+        Editor::set_background(contents, Some(COLOR_COLUMN_BACKGROUND));
+        contents.push_str(&" ".repeat(if fill { text_cols - start } else { 1 }));
+        Editor::set_background(contents, None);
+        fill
+    }
+
+    // Draw the part of a row that starts at screen column start_index and is
+    // at most max_length columns long. Returns whether the line was filled.
+    fn draw_row(
+        &self,
+        contents: &mut String,
+        file_row: usize,
+        start_index: usize,
+        max_length: usize,
+    ) -> bool {
+        let mut filled_line = false;
+        // Only as much of the row as reaches the right of the screen is
+        // measured, since it could be huge.
+        let line_length = self.rows[file_row]
+            .render
+            .chars()
+            .map(|c| {
+                if c.is_control() {
+                    1
+                } else {
+                    UnicodeWidthChar::width(c).unwrap_or(0)
+                }
+            })
+            .scan(0, |length, width| {
+                if *length > start_index + max_length {
+                    return None;
+                }
+                *length += width;
+                Some(*length)
+            })
+            .last()
+            .unwrap_or(0);
+        // Check if any of this line is visible.
+        if start_index < line_length {
+            let mut displayed_length = line_length - start_index;
+            // Cap the displayed length to the length of the screen.
+            if displayed_length >= max_length {
+                displayed_length = max_length;
+                filled_line = true;
+            }
+            let row = &self.rows[file_row];
+            let mut current_color = Color::Default;
+            let mut screen_index = 0;
+            let mut prev_width = 0;
+
+            // The cursor line gets a background color, and so do matches of
+            // the last search. They have to be set again after each change of
+            // foreground color.
+            let row_background = if self.config.cursor_line && file_row == self.cursor_position.y {
+                Some(CURSOR_LINE_BACKGROUND)
+            } else {
+                None
+            };
+            let mut background = row_background;
+            if let Some(background) = background {
+                Editor::set_background(contents, Some(background));
+            }
+            let bad_indent = row.bad_indent;
+            let window = self.drawn_window(file_row, start_index, max_length);
+            let search_matches = self.search_highlight_ranges(file_row, window);
+            let word_matches = self.cursor_word_ranges(file_row, window);
+            let selection: Vec<(usize, usize)> =
+                self.selection_range(file_row).into_iter().collect();
+
+            let layout = self.layout();
+            let mut zip = row.zip(layout).peekable();
+
+            loop {
+                // Stop once the part of the row that's on screen is drawn.
+                if screen_index >= start_index + displayed_length {
+                    break;
+                }
+                let next = zip.next();
+                if let Some((char, char_index, render, highlight)) = next {
+                    // Chars drawn over several cells come through once per
+                    // cell.
+                    let curr_width = if char.is_control() || layout.expands(char) {
+                        1
+                    } else {
+                        UnicodeWidthChar::width(char).unwrap_or(0)
+                    };
+                    if screen_index >= start_index && screen_index < start_index + displayed_length
+                    {
+                        let in_range = |ranges: &[(usize, usize)]| {
+                            ranges
+                                .iter()
+                                .any(|&(start, end)| start <= char_index && char_index < end)
+                        };
+                        let cell_background = if in_range(&selection) {
+                            Some(SELECTION_BACKGROUND)
+                        } else if in_range(&search_matches)
+                            || (highlight == Highlight::Match && NO_COLOR.load(Ordering::Relaxed))
+                        {
+                            // Without colors the current find match can't
+                            // stand out by its color.
+                            Some(SEARCH_HIGHLIGHT_BACKGROUND)
+                        } else if in_range(&word_matches) {
+                            Some(WORD_HIGHLIGHT_BACKGROUND)
+                        } else if char_index < bad_indent {
+                            Some(MIXED_INDENT_BACKGROUND)
+                        } else if self.config.max_line_length > 0
+                            && screen_index >= self.config.max_line_length
+                        {
+                            Some(LONG_LINE_BACKGROUND)
+                        } else if self.in_color_column(screen_index) {
+                            Some(COLOR_COLUMN_BACKGROUND)
+                        } else {
+                            row_background
+                        };
+                        if cell_background != background {
+                            background = cell_background;
+                            Editor::set_background(contents, background);
+                        }
+
+                        if prev_width > 1
+                            && screen_index > start_index
+                            && screen_index - prev_width < start_index
+                        {
+                            // There's a cut off wide character at the start
+                            // of the row.
+                            Editor::set_colors(contents, Color::Blue, background);
+                            contents.push('<');
+                            Editor::set_colors(contents, current_color, background);
+                        } else if curr_width > 1
+                            && screen_index + curr_width > start_index + displayed_length
+                        {
+                            // There's a cut off wide character at the end
+                            // of the row.
+                            Editor::set_colors(contents, Color::Blue, background);
+                            contents.push('>');
+                            Editor::set_colors(contents, current_color, background);
+                            prev_width = curr_width;
+                            screen_index += curr_width;
+                            continue;
+                        }
+
+                        let jump_label = self
+                            .jump_labels
+                            .iter()
+                            .find(|(position, _)| {
+                                position.y == file_row && position.x == char_index
+                            })
+                            .map(|&(_, label)| label);
+                        if let Some(label) = jump_label {
+                            Editor::set_colors(
+                                contents,
+                                Color::BrightWhite,
+                                Some(JUMP_LABEL_BACKGROUND),
+                            );
+                            contents.push(label);
+                            // Cover the rest of a wide char.
+                            for _ in 1..curr_width {
+                                contents.push(' ');
+                            }
+                            Editor::set_colors(contents, current_color, background);
+                        } else if self.config.render_whitespace && char == ' ' {
+                            Editor::set_colors(contents, Color::BrightBlack, background);
+                            contents.push('∙');
+                            Editor::set_colors(contents, current_color, background);
+                        } else if self.config.render_whitespace && char == '\t' {
+                            Editor::set_colors(contents, Color::BrightBlack, background);
+                            contents.push('⇀');
+                            while zip.peek().is_some() && zip.peek().unwrap().1 == char_index {
+                                zip.next();
+                                contents.push(' ');
+                            }
+                            Editor::set_colors(contents, current_color, background);
+                        } else if layout.escapes(char) {
+                            Editor::invert_colors(contents);
+                            contents.push(render);
+                            Editor::clear_formatting(contents);
+                            Editor::set_colors(contents, current_color, background);
+                        } else if render.is_control() {
+                            Editor::invert_colors(contents);
+                            contents.push(if char as u8 <= 26 {
+                                (char as u8 | !0b10111111) as char
+                            } else {
+                                '?'
+                            });
+                            Editor::clear_formatting(contents);
+                            Editor::set_colors(contents, current_color, background);
+                        } else if let Highlight::Normal = highlight {
+                            if current_color != Color::Default {
+                                Editor::set_colors(contents, Color::Default, background);
+                                current_color = Color::Default;
+                            }
+                            contents.push(render);
+                        } else {
+                            let color = Editor::highlight_to_color(highlight);
+                            if current_color != color {
+                                Editor::set_colors(contents, color, background);
+                                current_color = color;
+                            }
+                            contents.push(render);
+                        }
+                    }
+                    prev_width = curr_width;
+                    screen_index += curr_width;
+                } else {
+                    break;
+                }
+            }
+            Editor::set_color(contents, Color::Default);
+        }
+        filled_line
+    }
+
+    fn draw_status_bar(&self, contents: &mut String) {
+        Editor::invert_colors(contents);
+
+        let filename = match &self.filename {
+            Some(filename) => truncate_to_width(filename, MAX_STATUS_FILENAME_LENGTH),
+            None => "[No name]",
+        };
+
+        let contents_note = if self.hex_view {
+            "[hex] [read-only] "
+        } else if self.no_write_permission {
+            "[RO: no write permission] "
+        } else if self.read_only {
+            "[read-only] "
+        } else if self.rows.is_empty() {
+            "[empty file] "
+        } else if self.rows.iter().all(|row| row.chars.trim().is_empty()) {
+            "[blank file] "
+        } else {
+            ""
+        };
+
+        let unhighlighted =
+            if self.config.syntax_highlight && self.is_unhighlighted(self.cursor_position.y) {
+                "[long line: not highlighted] "
+            } else {
+                ""
+            };
+
+        let left_status = format!(
+            "{} - {} lines {}{}{}{}{}",
+            filename,
+            self.rows.len(),
+            contents_note,
+            unhighlighted,
+            if self.long_lines > 0 {
+                format!("[{} long] ", self.long_lines)
+            } else {
+                String::new()
+            },
+            if self.bad_indents > 0 {
+                format!("[{} mixed indent] ", self.bad_indents)
+            } else {
+                String::new()
+            },
+            if self.dirty { "(modified)" } else { "" }
+        );
+
+        let indent = if self.config.expand_tabs {
+            format!("spaces:{}", self.config.indent_size)
+        } else {
+            format!("tabs:{}", self.config.tab_stop)
+        };
+        let char_column = self.cursor_position.x + 1;
+        let screen_column = self.get_current_screen_index() + 1;
+        let column = match self.config.status_column {
+            StatusColumn::Char => char_column.to_string(),
+            StatusColumn::Screen => screen_column.to_string(),
+            StatusColumn::Both if screen_column == char_column => screen_column.to_string(),
+            StatusColumn::Both => format!("{} (char {})", screen_column, char_column),
+        };
+        let right_status = format!(
+            "{} | {}{} | {} | {} | {} | {}:{} ",
+            if self.overwrite { "OVR" } else { "INS" },
+            self.encoding.name(),
+            if self.had_bom { " [BOM]" } else { "" },
+            self.config.line_ending.name(),
+            indent,
+            match self.filetype {
+                Some(filetype) => filetype.name,
+                None => "no ft",
+            },
+            self.cursor_position.y + 1,
+            column
+        );
+
+        let mut status: String = format!(
+            "{:width$}",
+            left_status,
+            width = self
+                .screen_dimensions
+                .cols
+                .saturating_sub(right_status.len())
+        )
+        .to_string();
+
+        status.push_str(&right_status);
+
+        contents.push_str(truncate_to_width(&status, self.screen_dimensions.cols));
+
+        Editor::clear_formatting(contents);
+        contents.push_str("\r\n");
+    }
+
+    fn draw_message_bar(&self, contents: &mut String) {
+        Editor::clear_row(contents);
+        let message = truncate_to_width(&self.status_message, self.screen_dimensions.cols);
+
+        if !message.is_empty() && self.status_message_time.elapsed().as_secs() < 5 {
+            contents.push_str(message);
+        }
+    }
+
+    // Show how many lines have been loaded so far, without drawing the
+    // rest of the screen.
+    fn draw_load_progress(&mut self) {
+        if self.orig_termios.is_none() {
+            return;
+        }
+        self.set_status_message(&format!("Loading… {} lines", self.rows.len()));
+        let mut contents = String::new();
+        Editor::hide_cursor(&mut contents);
+        Editor::draw_cursor(
+            &mut contents,
+            &Position {
+                x: 0,
+                y: self.screen_dimensions.rows + 1,
+            },
+        );
+        self.draw_message_bar(&mut contents);
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(contents.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    fn set_status_message(&mut self, message: &str) {
+        // Control characters, say from a filename, would mess up the screen.
+        self.status_message = message
+            .chars()
+            .map(|c| if c.is_control() { '?' } else { c })
+            .collect();
+        self.status_message_time = Instant::now();
+    }
+
+    fn refresh_screen(&mut self) {
+        self.draw_frame();
+        // Write the whole frame at once.
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(self.output.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    // Put everything on the screen into output.
+    fn draw_frame(&mut self) {
+        self.scroll();
+        self.cursor_word = if self.config.highlight_word {
+            self.word_under_cursor()
+        } else {
+            None
+        };
+        // Only the rows on screen need to be up to date.
+        self.update_stale_highlights(self.text_offset.y + self.text_rows());
+
+        // Reuse the last frame's buffer to avoid reallocating it.
+        let mut contents = mem::take(&mut self.output);
+        contents.clear();
+
+        Editor::hide_cursor(&mut contents);
+        Editor::reset_cursor(&mut contents);
+        self.update_title(&mut contents);
+
+        if self.other_pane.is_none() {
+            self.draw_rows(&mut contents);
+        } else {
+            // Draw the top pane, then the bottom one, by switching to
+            // whichever isn't active and back.
+            if !self.top_pane_active {
+                self.draw_other_pane(&mut contents);
+            } else {
+                self.draw_rows(&mut contents);
+            }
+            Editor::set_color(&mut contents, Color::BrightBlack);
+            contents.push_str(&"─".repeat(self.screen_dimensions.cols));
+            Editor::set_color(&mut contents, Color::Default);
+            contents.push_str("\r\n");
+            if self.top_pane_active {
+                self.draw_other_pane(&mut contents);
+            } else {
+                self.draw_rows(&mut contents);
+            }
+        }
+        self.draw_status_bar(&mut contents);
+        self.draw_message_bar(&mut contents);
+
+        let mut cursor_screen_position = self.get_cursor_screen_position();
+        if !self.top_pane_active {
+            // Below the top pane and the divider.
+            cursor_screen_position.y += self.screen_dimensions.rows - self.text_rows();
+        }
+        Editor::draw_cursor(&mut contents, &cursor_screen_position);
+
+        Editor::show_cursor(&mut contents);
+
+        self.output = contents;
+    }
+
+    fn draw_other_pane(&mut self, contents: &mut String) {
+        // Only the active pane has jump labels.
+        let jump_labels = mem::take(&mut self.jump_labels);
+        self.switch_pane();
+        self.scroll();
+        self.update_stale_highlights(self.text_offset.y + self.text_rows());
+        self.draw_rows(contents);
+        self.switch_pane();
+        self.jump_labels = jump_labels;
+    }
+
+    fn toggle_split(&mut self) {
+        if self.other_pane.take().is_none() {
+            self.other_pane = Some(Pane {
+                cursor_position: self.cursor_position,
+                text_offset: self.text_offset,
+                goal_column: self.goal_column,
+            });
+        }
+        self.top_pane_active = true;
+    }
+
+    fn switch_pane(&mut self) {
+        if let Some(pane) = &mut self.other_pane {
+            mem::swap(&mut self.cursor_position, &mut pane.cursor_position);
+            mem::swap(&mut self.text_offset, &mut pane.text_offset);
+            mem::swap(&mut self.goal_column, &mut pane.goal_column);
+            self.top_pane_active = !self.top_pane_active;
+            // The other pane may have deleted the rows this one was on.
+            let cursor_position = self.cursor_position;
+            self.cursor_position.y = cmp::min(cursor_position.y, self.rows.len());
+            self.cursor_position.x = match self.get_current_row() {
+                Some(row) => cmp::min(cursor_position.x, row.char_len),
+                None => 0,
+            };
+        }
+    }
+
+    fn get_cursor_screen_position(&self) -> Position {
+        let screen_index = self.get_current_screen_index();
+        if !self.config.soft_wrap {
+            return Position {
+                x: screen_index - self.text_offset.x + self.line_number_space(),
+                y: self.cursor_position.y - self.text_offset.y,
+            };
+        }
+
+        let mut y = 0;
+        for file_row in self.text_offset.y..self.cursor_position.y {
+            y += self.wrap_segments(file_row).len();
+        }
+        let segments = self.wrap_segments(self.cursor_position.y);
+        let (segment_index, (segment_start, _)) = segments
+            .iter()
+            .enumerate()
+            .find(|(_, (_, end))| screen_index < *end)
+            .unwrap_or((segments.len() - 1, segments.last().unwrap()));
+        Position {
+            x: screen_index - segment_start + self.line_number_space(),
+            y: y + segment_index,
+        }
+    }
+
+    // Terminals that don't understand these sequences ignore them.
+    fn update_title(&mut self, contents: &mut String) {
+        if !self.config.set_title {
+            if self.title.take().is_some() {
+                contents.push_str("\x1b[23;0t");
+            }
+            return;
+        }
+        let filename = self.filename.as_deref().unwrap_or("[No name]");
+        // Control characters from the filename could end the sequence early.
+        let title: String = format!(
+            "kilo - {}{}",
+            filename,
+            if self.dirty { " (modified)" } else { "" }
+        )
+        .chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect();
+        if self.title.as_ref() != Some(&title) {
+            if self.title.is_none() {
+                // Save the terminal's own title to put back later.
+                contents.push_str("\x1b[22;0t");
+            }
+            contents.push_str(&format!("\x1b]0;{}\x07", title));
+            self.title = Some(title);
+        }
+    }
+
+    fn restore_title(&mut self) {
+        if self.title.take().is_some() {
+            print!("\x1b[23;0t");
+            io::stdout().flush().unwrap();
+        }
+    }
+
+    fn reset_screen(&self) {
+        let mut contents = String::new();
+
+        Editor::clear_screen(&mut contents);
+        Editor::reset_cursor(&mut contents);
+
+        print!("{}", contents);
+        io::stdout().flush().unwrap();
+    }
+
+    // Show some lines over the whole screen until a key other than the
+    // scrolling keys is pressed.
+    fn show_pager(&mut self, title: &str, lines: Vec<String>) {
+        self.page(
+            title,
+            lines,
+            "Arrows/PageUp/PageDown to scroll, any other key to close",
+        );
+    }
+
+    // Show lines until a key other than one for scrolling is pressed, and
+    // return that key.
+    fn page(&mut self, title: &str, lines: Vec<String>, help: &str) -> Key {
+        let mut offset = 0;
+        loop {
+            let rows = self.screen_dimensions.rows;
+            let max_offset = lines.len().saturating_sub(rows);
+            offset = cmp::min(offset, max_offset);
+
+            let mut contents = String::new();
+            Editor::hide_cursor(&mut contents);
+            Editor::reset_cursor(&mut contents);
+            for y in 0..rows {
+                if let Some(line) = lines.get(offset + y) {
+                    contents.push_str(truncate_to_width(line, self.screen_dimensions.cols));
+                }
+                Editor::clear_row(&mut contents);
+                contents.push_str("\r\n");
+            }
+            Editor::invert_colors(&mut contents);
+            let status = format!(
+                "{} - lines {}-{} of {}",
+                title,
+                cmp::min(offset + 1, lines.len()),
+                cmp::min(offset + rows, lines.len()),
+                lines.len()
+            );
+            contents.push_str(&format!(
+                "{:width$}",
+                truncate_to_width(&status, self.screen_dimensions.cols),
+                width = self.screen_dimensions.cols
+            ));
+            Editor::clear_formatting(&mut contents);
+            contents.push_str("\r\n");
+            Editor::clear_row(&mut contents);
+            contents.push_str(truncate_to_width(help, self.screen_dimensions.cols));
+            print!("{}", contents);
+            io::stdout().flush().unwrap();
+
+            match self.read_key() {
+                Key::Arrow(Arrow::Up) => offset = offset.saturating_sub(1),
+                Key::Arrow(Arrow::Down) => offset += 1,
+                Key::PageUp => offset = offset.saturating_sub(rows),
+                Key::PageDown => offset += rows,
+                Key::Home => offset = 0,
+                Key::End => offset = max_offset,
+                key => return key,
+            }
+        }
+    }
+
+    // *** Input ***
+
+    fn prompt<F>(&mut self, prompt: &str, callback: F) -> Option<String>
+    where
+        F: Fn(&mut Editor, &str, Key) -> String,
+    {
+        self.prompt_with_completion(prompt, callback, None)
+    }
+
+    // A prompt where Tab completes the input using complete, which returns
+    // the possible completions of what's been typed so far.
+    fn prompt_with_completion<F>(
+        &mut self,
+        prompt: &str,
+        callback: F,
+        complete: Option<fn(&str) -> Vec<String>>,
+    ) -> Option<String>
+    where
+        F: Fn(&mut Editor, &str, Key) -> String,
+    {
+        let mut input = String::new();
+        let mut message = String::new();
+        // The candidates being cycled through by pressing Tab repeatedly.
+        let mut cycle: Option<(Vec<String>, usize)> = None;
+        loop {
+            self.set_status_message(&format!("{} {}", prompt.replace("{}", &input), &message));
+            self.refresh_screen();
+
+            let key = self.read_key();
+            if let (Key::Tab, Some(complete)) = (&key, complete) {
+                if let Some((candidates, index)) = &mut cycle {
+                    *index = (*index + 1) % candidates.len();
+                    input = candidates[*index].clone();
+                    continue;
+                }
+
+                let candidates = complete(&input);
+                // Only show the part of each candidate that's being completed.
+                let start = input.rfind('/').map_or(0, |i| i + 1);
+                message = match candidates.len() {
+                    0 => ": No matches".to_string(),
+                    1 => String::new(),
+                    _ => format!(
+                        ": {}",
+                        candidates
+                            .iter()
+                            .map(|candidate| &candidate[start..])
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    ),
+                };
+
+                let prefix = common_prefix(&candidates);
+                if candidates.len() > 1 && prefix.len() <= input.len() {
+                    // There's nothing more in common, so start cycling.
+                    input = candidates[0].clone();
+                    cycle = Some((candidates, 0));
+                } else if !candidates.is_empty() {
+                    input = prefix.to_string();
+                }
+                continue;
+            }
+            cycle = None;
+
+            match key {
+                Key::Backspace | Key::Delete => {
+                    input.pop();
+                }
+                // Delete the last word, and any whitespace after it, like
+                // readline.
+                Key::Ctrl('w') => {
+                    let word_end = input.trim_end().len();
+                    let word_start = input[..word_end]
+                        .rfind(char::is_whitespace)
+                        .map_or(0, |i| i + input[i..].chars().next().unwrap().len_utf8());
+                    input.truncate(word_start);
+                }
+                Key::Ctrl('u') => input.clear(),
+                Key::Esc => {
+                    self.set_status_message("");
+                    callback(self, &input, key);
+                    return None;
+                }
+                Key::Enter if !input.is_empty() => {
+                    self.set_status_message("");
+                    callback(self, &input, key);
+                    return Some(input);
+                }
+                Key::Char(c) if !c.is_control() => {
+                    input.push(c);
+                }
+                // Tabs and other control characters don't belong in a
+                // one-line prompt.
+                _ => {}
+            }
+            message = callback(self, &input, key);
+        }
+    }
+
+    // Read a line in the status bar without showing it, for passwords. ESC
+    // cancels.
+    fn prompt_password(&mut self, prompt: &str) -> Option<String> {
+        let mut input = String::new();
+        self.set_status_message(prompt);
+        self.refresh_screen();
+        loop {
+            match self.read_key() {
+                Key::Backspace | Key::Delete => {
+                    input.pop();
+                }
+                Key::Esc => {
+                    self.set_status_message("");
+                    return None;
+                }
+                Key::Enter => {
+                    self.set_status_message("");
+                    return Some(input);
+                }
+                Key::Char(c) if !c.is_control() => input.push(c),
+                _ => {}
+            }
+        }
+    }
+
+    // Ask a yes/no question in the status bar. Any key other than y counts as
+    // no.
+    fn confirm(&mut self, question: &str) -> bool {
+        self.set_status_message(question);
+        self.refresh_screen();
+        let key = self.read_key();
+        self.set_status_message("");
+        matches!(key, Key::Char('y') | Key::Char('Y'))
+    }
+
+    fn read_char(&self) -> char {
+        match self.pending_input.take() {
+            Some(c) => c,
+            None => self.input.recv().expect("Error reading from input channel"),
+        }
+    }
+
+    // Wait up to timeout for input, returning whether any came.
+    fn wait_for_input(&self, timeout: Duration) -> bool {
+        if self.pending_input.get().is_some() {
+            return true;
+        }
+        match self.input.recv_timeout(timeout) {
+            Ok(c) => {
+                self.pending_input.set(Some(c));
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => panic!("Input channel disconnected"),
+        }
+    }
+
+    // Whether there's input waiting to be read, as when pasting.
+    fn input_pending(&self) -> bool {
+        if self.pending_input.get().is_some() {
+            return true;
+        }
+        match self.input.try_recv() {
+            Ok(c) => {
+                self.pending_input.set(Some(c));
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => panic!("Input channel disconnected"),
+        }
+    }
+
+    // Read a key, ignoring focus changes, which only matter between keypresses.
+    fn read_key(&self) -> Key {
+        loop {
+            match self.read_key_or_focus() {
+                Key::FocusIn | Key::FocusOut => {}
+                key => return key,
+            }
+        }
+    }
+
+    fn read_key_or_focus(&self) -> Key {
+        let c = self.read_char();
+        if c == '\x08' || c == '\x7f' {
+            Key::Backspace
+        } else if c == '\r' {
+            Key::Enter
+        } else if c == '\t' {
+            Key::Tab
+        } else if c == '\x1b' {
+            self.read_escape_sequence()
+        } else if c.is_control() {
+            Key::Ctrl((c as u8 | 0b01100000) as char)
+        } else {
+            Key::Char(c)
+        }
+    }
+
+    fn read_escape_sequence(&self) -> Key {
+        match self.input.recv_timeout(Duration::from_millis(100)) {
+            Ok('[') => match self.input.try_recv() {
+                Ok('A') => Key::Arrow(Arrow::Up),    // <esc>[A
+                Ok('B') => Key::Arrow(Arrow::Down),  // <esc>[B
+                Ok('C') => Key::Arrow(Arrow::Right), // <esc>[C
+                Ok('D') => Key::Arrow(Arrow::Left),  // <esc>[D
+                Ok('H') => Key::Home,                // <esc>[H
+                Ok('F') => Key::End,                 // <esc>[F
+                Ok('I') => Key::FocusIn,             // <esc>[I
+                Ok('O') => Key::FocusOut,            // <esc>[O
+                Ok(n @ '0'..='9') => {
+                    match self.input.recv_timeout(Duration::from_millis(100)) {
+                        Ok('~') => match n {
+                            // Match on the number before the tilde.
+                            '1' | '7' => Key::Home, // <esc>[1~ or <esc>[7~
+                            '4' | '8' => Key::End,  // <esc>[4~ or <esc>[8~
+                            '2' => Key::Insert,     // <esc>[2~
+                            '3' => Key::Delete,     // <esc>[3~
+                            '5' => Key::PageUp,     // <esc>[5~
+                            '6' => Key::PageDown,   // <esc>[6~
+                            _ => Key::Esc,
+                        },
+                        // Ignore all bytes after the esc.
+                        Ok(_) | Err(RecvTimeoutError::Timeout) => Key::Esc,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            panic!("Input channel disconnected")
+                        }
+                    }
+                }
+                // Ignore all bytes after the esc.
+                Ok(_) | Err(TryRecvError::Empty) => Key::Esc,
+                Err(TryRecvError::Disconnected) => {
+                    panic!("Input channel disconnected")
+                }
+            },
+            Ok('O') => {
+                match self.input.recv_timeout(Duration::from_millis(100)) {
+                    Ok('H') => Key::Home, // <esc>OH
+                    Ok('F') => Key::End,  // <esc>OF
+                    // Nothing came after, so it was Alt-O.
+                    Err(RecvTimeoutError::Timeout) => Key::Alt('O'),
+                    // Ignore all bytes after the esc.
+                    Ok(_) => Key::Esc,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        panic!("Input channel disconnected")
+                    }
+                }
+            }
+            Ok(c) if !c.is_control() => Key::Alt(c),
+            // Ignore the byte after the esc if there is one.
+            Ok(_) | Err(RecvTimeoutError::Timeout) => Key::Esc,
+            Err(RecvTimeoutError::Disconnected) => {
+                panic!("Input channel disconnected")
+            }
+        }
+    }
+
+    // Remember the current screen column as the goal column if there isn't
+    // one already.
+    fn goal_column(&mut self) -> usize {
+        match self.goal_column {
+            Some(goal_column) => goal_column,
+            None => {
+                let screen_index = self.get_current_screen_index();
+                self.goal_column = Some(screen_index);
+                screen_index
+            }
+        }
+    }
+
+    // Move to the screen line above or below, which may be part of the same
+    // row when soft wrapping.
+    fn move_visual_line(&mut self, up: bool) {
+        let screen_index = self.get_current_screen_index();
+        // Lines are split every text_cols columns unless a wide char is in
+        // the way, so this is where the goal column is on any line.
+        let column = self.goal_column() % cmp::max(self.text_cols(), 1);
+        let y = self.cursor_position.y;
+        let segments = self.wrap_segments(y);
+        let current = segments
+            .iter()
+            .position(|&(_, end)| screen_index < end)
+            .unwrap_or(segments.len() - 1);
+
+        let (y, segments, index) = if up && current > 0 {
+            (y, segments, current - 1)
+        } else if up && y > 0 {
+            let segments = self.wrap_segments(y - 1);
+            let last = segments.len() - 1;
+            (y - 1, segments, last)
+        } else if !up && current + 1 < segments.len() {
+            (y, segments, current + 1)
+        } else if !up && y < self.rows.len() {
+            (y + 1, self.wrap_segments(y + 1), 0)
+        } else {
+            return;
+        };
+
+        let (start, end) = segments[index];
+        self.cursor_position.y = y;
+        let row = self.get_current_row();
+        let mut x = Editor::screen_index_to_char_index(start + column, row, self.layout());
+        // Stay on this line rather than landing at the start of the next.
+        if index + 1 < segments.len() && x > 0 && self.get_screen_index(x, y) >= end {
+            x -= 1;
+        }
+        self.cursor_position.x = x;
+    }
+
+    fn move_cursor(&mut self, arrow: Arrow) {
+        let visual_lines = self.config.soft_wrap && self.config.visual_line_movement;
+        match arrow {
+            Arrow::Up if visual_lines => self.move_visual_line(true),
+            Arrow::Down if visual_lines => self.move_visual_line(false),
+            Arrow::Up => {
+                if self.cursor_position.y > 0 {
+                    let screen_index = self.goal_column();
+                    self.cursor_position.y -= 1;
+                    self.cursor_position.x = Editor::screen_index_to_char_index(
+                        screen_index,
+                        self.get_current_row(),
+                        self.layout(),
+                    );
+                }
+            }
+            Arrow::Left => {
+                if self.cursor_position.x > 0 {
+                    self.cursor_position.x -= 1
+                } else if self.cursor_position.y > 0 {
+                    self.cursor_position.y -= 1;
+                    self.cursor_position.x = self.get_current_row().unwrap().char_len;
+                }
+            }
+            Arrow::Down => {
+                if self.cursor_position.y < self.rows.len() {
+                    let screen_index = self.goal_column();
+                    self.cursor_position.y += 1;
+                    self.cursor_position.x = Editor::screen_index_to_char_index(
+                        screen_index,
+                        self.get_current_row(),
+                        self.layout(),
+                    );
+                }
+            }
+            Arrow::Right => {
+                if let Some(row) = self.get_current_row() {
+                    #[allow(clippy::comparison_chain)]
+                    if self.cursor_position.x < row.char_len {
+                        self.cursor_position.x += 1
+                    } else if self.cursor_position.x == row.char_len {
+                        self.cursor_position.y += 1;
+                        self.cursor_position.x = 0;
+                    }
+                }
+            }
+        };
+
+        let row_length = if let Some(row) = self.get_current_row() {
+            row.char_len
+        } else {
+            0
+        };
+
+        debug_assert!(self
+            .get_current_row()
+            .is_none_or(|row| row.char_len == row.chars.chars().count()));
+
+        // Move the cursor to the end of the line if it is past the end.
+        if self.cursor_position.x > row_length {
+            self.cursor_position.x = row_length;
+        }
+    }
+
+    // Move the view and the cursor up or down together.
+    fn scroll_lines(&mut self, arrow: Arrow, lines: usize) {
+        let lines = cmp::max(lines, 1);
+        let screen_index = self.goal_column();
+        match arrow {
+            Arrow::Up => {
+                self.text_offset.y = self.text_offset.y.saturating_sub(lines);
+                self.cursor_position.y = self.cursor_position.y.saturating_sub(lines);
+            }
+            Arrow::Down => {
+                let last_row = self.rows.len().saturating_sub(1);
+                self.text_offset.y = cmp::min(self.text_offset.y + lines, last_row);
+                self.cursor_position.y = cmp::min(self.cursor_position.y + lines, self.rows.len());
+            }
+            Arrow::Left | Arrow::Right => return,
+        }
+        self.cursor_position.x =
+            Editor::screen_index_to_char_index(screen_index, self.get_current_row(), self.layout());
+    }
+
+    fn is_long_line(&self, y: usize) -> bool {
+        self.config.max_line_length > 0
+            && self.get_screen_index(self.rows[y].char_len, y) > self.config.max_line_length
+    }
+
+    // Work out how many chars of indentation at the start of a row should be
+    // flagged: all of it if it mixes tabs and spaces, or the spaces starting
+    // a recipe in a file that needs tabs. Whether the rows after it can be
+    // recipes is passed down until it stops changing.
+    fn update_bad_indent(&mut self, mut y: usize) {
+        let require_tabs = self
+            .filetype
+            .is_some_and(|filetype| filetype.options.require_tabs);
+        while y < self.rows.len() {
+            let (previous, row) = self.rows.with_previous_mut(y);
+            let follows_rule = previous.is_some_and(|row| row.continue_recipe);
+            let chars = &row.chars;
+            let indent_end = chars
+                .find(|c: char| c != ' ' && c != '\t')
+                .unwrap_or(chars.len());
+            let indent = &chars[..indent_end];
+
+            // Blank lines don't matter.
+            let (bad_indent, continue_recipe) = if indent_end == chars.len() {
+                (0, follows_rule)
+            } else {
+                // A recipe follows a rule or another recipe.
+                let continue_recipe = chars.starts_with('\t')
+                    || (!chars.starts_with(char::is_whitespace)
+                        && chars.contains(':')
+                        && !chars.contains('='));
+                let mixed = indent.contains(' ') && indent.contains('\t');
+                let spaced_recipe = require_tabs && follows_rule && indent.starts_with(' ');
+                if self.config.warn_mixed_indent && (mixed || spaced_recipe) {
+                    (indent_end, continue_recipe)
+                } else {
+                    (0, continue_recipe)
+                }
+            };
+
+            if (bad_indent > 0) != (row.bad_indent > 0) {
+                if bad_indent > 0 {
+                    self.bad_indents += 1;
+                } else {
+                    self.bad_indents -= 1;
+                }
+            }
+            row.bad_indent = bad_indent;
+            if row.continue_recipe == continue_recipe {
+                break;
+            }
+            row.continue_recipe = continue_recipe;
+            y += 1;
+        }
+    }
+
+    // Go to a line number, or to a line above or below this one.
+    fn goto_line(&mut self) {
+        let input = match self.prompt(
+            "Go to line: {} (+N or -N to move from here, ESC to cancel)",
+            |_, _, _| String::new(),
+        ) {
+            Some(input) => input,
+            None => return,
+        };
+        match self.goto_target(input.trim()) {
+            Ok(y) => {
+                self.record_jump(self.cursor_position);
+                self.jump_to(Position { x: 0, y });
+            }
+            Err(error) => self.set_status_message(&error),
+        }
+    }
+
+    // The row for a line number, or for +N or -N lines from the cursor's,
+    // kept within the file.
+    fn goto_target(&self, input: &str) -> Result<usize, String> {
+        let invalid = || format!("Not a line number: {}", input);
+        let last = self.rows.len().saturating_sub(1);
+        let y = if let Some(lines) = input.strip_prefix('+') {
+            let lines: usize = lines.parse().map_err(|_| invalid())?;
+            self.cursor_position.y.saturating_add(lines)
+        } else if let Some(lines) = input.strip_prefix('-') {
+            let lines: usize = lines.parse().map_err(|_| invalid())?;
+            self.cursor_position.y.saturating_sub(lines)
+        } else {
+            let line: usize = input.parse().map_err(|_| invalid())?;
+            line.saturating_sub(1)
+        };
+        Ok(cmp::min(y, last))
+    }
+
+    // Go to where the identifier under the cursor is defined, going by the
+    // filetype's definition patterns. From a definition, go to the next one,
+    // so that going again cycles through them.
+    fn goto_definition(&mut self) {
+        let word = match self.word_under_cursor() {
+            Some(word) => word,
+            None => {
+                self.set_status_message("Not on an identifier");
+                return;
+            }
+        };
+        let patterns = match self.filetype {
+            Some(filetype) if !filetype.definition_patterns.is_empty() => {
+                filetype.definition_patterns
+            }
+            _ => {
+                self.set_status_message("No definition patterns for this filetype");
+                return;
+            }
+        };
+        let name = format!("(?P<name>{})", regex::escape(&word));
+        let mut regexes = Vec::new();
+        for pattern in patterns {
+            match Regex::new(&pattern.replace("{name}", &name)) {
+                Ok(regex) => regexes.push(regex),
+                Err(_) => {
+                    self.set_status_message(&format!("Invalid definition pattern: {}", pattern));
+                    return;
+                }
+            }
+        }
+
+        let definitions: Vec<Position> = self
+            .rows
+            .iter()
+            .enumerate()
+            .filter_map(|(y, row)| {
+                let found = regexes
+                    .iter()
+                    .find_map(|regex| regex.captures(&row.chars)?.name("name"))?;
+                Some(Position {
+                    x: row.chars[..found.start()].chars().count(),
+                    y,
+                })
+            })
+            .collect();
+        if definitions.is_empty() {
+            self.set_status_message(&format!("No definition of {} found", word));
+            return;
+        }
+        let y = self.cursor_position.y;
+        let index = match definitions.iter().position(|definition| definition.y == y) {
+            Some(current) => (current + 1) % definitions.len(),
+            None => 0,
+        };
+        self.record_jump(self.cursor_position);
+        self.jump_to(definitions[index]);
+        if definitions.len() > 1 {
+            self.set_status_message(&format!(
+                "Definition {} of {}",
+                index + 1,
+                definitions.len()
+            ));
+        }
+    }
+
+    // Go to the next line that's too long, wrapping around to the start.
+    fn next_long_line(&mut self) {
+        if self.config.max_line_length == 0 {
+            self.set_status_message("max_line_length isn't set");
+            return;
+        }
+        let start = self.cursor_position.y + 1;
+        let found = (start..self.rows.len())
+            .chain(0..cmp::min(start, self.rows.len()))
+            .find(|&y| self.rows[y].too_long);
+        match found {
+            Some(y) => {
+                self.record_jump(self.cursor_position);
+                self.jump_to(Position {
+                    x: Editor::screen_index_to_char_index(
+                        self.config.max_line_length,
+                        self.rows.get(y),
+                        self.layout(),
+                    ),
+                    y,
+                });
+            }
+            None => self.set_status_message(&format!(
+                "No lines longer than {}",
+                self.config.max_line_length
+            )),
+        }
+    }
+
+    // Scroll sideways by a screenful, taking the cursor along so it stays in
+    // view.
+    fn scroll_columns(&mut self, arrow: Arrow) {
+        if self.config.soft_wrap {
+            self.set_status_message("There's no scrolling sideways with soft wrap on");
+            return;
+        }
+        let cols = self.text_cols();
+        let screen_index = self.get_current_screen_index();
+        let goal = match arrow {
+            Arrow::Left => {
+                self.text_offset.x = self.text_offset.x.saturating_sub(cols);
+                cmp::min(screen_index, self.text_offset.x + cols - 1)
+            }
+            Arrow::Right => {
+                self.text_offset.x += cols;
+                cmp::max(screen_index, self.text_offset.x)
+            }
+            Arrow::Up | Arrow::Down => return,
+        };
+        // Past the end of a short line, where the view will follow.
+        self.cursor_position.x =
+            Editor::screen_index_to_char_index(goal, self.get_current_row(), self.layout());
+    }
+
+    // The screen columns of the row the cursor is on that are on the screen.
+    fn visible_columns(&self) -> (usize, usize) {
+        if !self.config.soft_wrap {
+            return (self.text_offset.x, self.text_offset.x + self.text_cols());
+        }
+        let screen_index = self.get_current_screen_index();
+        let segments = self.wrap_segments(self.cursor_position.y);
+        segments
+            .iter()
+            .copied()
+            .find(|&(_, end)| screen_index < end)
+            .unwrap_or(*segments.last().unwrap())
+    }
+
+    // The starts of the words that are on the screen, from the top.
+    fn visible_word_starts(&self) -> Vec<Position> {
+        let mut starts = Vec::new();
+        let mut lines = 0;
+        let mut y = self.text_offset.y;
+        while y < self.rows.len() && lines < self.text_rows() {
+            let (start, end) = if self.config.soft_wrap {
+                // The last row might only be partly on the screen.
+                let segments = self.wrap_segments(y);
+                let visible = cmp::min(segments.len(), self.text_rows() - lines);
+                lines += segments.len();
+                (0, segments[visible - 1].1)
+            } else {
+                lines += 1;
+                (self.text_offset.x, self.text_offset.x + self.text_cols())
+            };
+
+            let mut prev = None;
+            for (x, c) in self.rows[y].chars.chars().enumerate() {
+                if !is_separator(c) && prev.is_none_or(is_separator) {
+                    let screen_index = self.get_screen_index(x, y);
+                    if start <= screen_index && screen_index < end {
+                        starts.push(Position { x, y });
+                    }
+                }
+                prev = Some(c);
+            }
+            y += 1;
+        }
+        starts
+    }
+
+    // Put a label on each word on the screen and jump to the one whose label
+    // is typed next.
+    fn jump_to_word(&mut self) {
+        self.jump_labels = self
+            .visible_word_starts()
+            .into_iter()
+            .zip(JUMP_LABELS.chars())
+            .collect();
+        if self.jump_labels.is_empty() {
+            self.set_status_message("No words to jump to");
+            return;
+        }
+        self.set_status_message("Jump to: type a label");
+        self.refresh_screen();
+
+        let labels = mem::take(&mut self.jump_labels);
+        self.set_status_message("");
+        if let Key::Char(c) = self.read_key() {
+            if let Some(&(position, _)) = labels.iter().find(|&&(_, label)| label == c) {
+                self.record_jump(self.cursor_position);
+                self.cursor_position = position;
+            }
+        }
+    }
+
+    // Move to the first or last non-blank char of the part of the row that's
+    // on the screen.
+    fn move_to_non_blank(&mut self, last: bool) {
+        let (start, end) = self.visible_columns();
+        let row = match self.get_current_row() {
+            Some(row) => row,
+            None => return,
+        };
+        let layout = self.layout();
+
+        let mut found = None;
+        let mut screen_index = 0;
+        for (x, c) in row.chars.chars().enumerate() {
+            if screen_index >= end {
+                break;
+            }
+            if screen_index >= start && !c.is_whitespace() {
+                found = Some(x);
+                if !last {
+                    break;
+                }
+            }
+            screen_index += if layout.expands(c) {
+                layout.width(c, screen_index)
+            } else if c.is_control() {
+                1
+            } else {
+                UnicodeWidthChar::width(c).unwrap_or(0)
+            };
+        }
+        if let Some(x) = found {
+            self.cursor_position.x = x;
+        }
+    }
+
+    // Scroll so that the cursor's line is in the middle of the screen.
+    fn center_view(&mut self) {
+        self.text_offset.y = self.cursor_position.y.saturating_sub(self.text_rows() / 2);
+    }
+
+    // Remember where a jump, like a search, started from. Jumping somewhere
+    // new forgets the positions that were jumped back from.
+    fn record_jump(&mut self, from: Position) {
+        self.marks.insert(LAST_JUMP_MARK, from);
+        self.jump_list.truncate(self.jump_index);
+        self.push_jump(from);
+        self.jump_index = self.jump_list.len();
+    }
+
+    fn push_jump(&mut self, position: Position) {
+        if self.jump_list.last() != Some(&position) {
+            self.jump_list.push(position);
+            if self.jump_list.len() > JUMP_LIST_SIZE {
+                self.jump_list.remove(0);
+            }
+        }
+    }
+
+    fn jump_back(&mut self) {
+        if self.jump_index == self.jump_list.len() {
+            // Remember where we are so we can come forward again.
+            self.push_jump(self.cursor_position);
+            self.jump_index = self.jump_list.len() - 1;
+        }
+        if self.jump_index == 0 {
+            self.set_status_message("Already at the oldest jump");
+            return;
+        }
+        self.jump_index -= 1;
+        self.jump_to(self.jump_list[self.jump_index]);
+    }
+
+    fn jump_forward(&mut self) {
+        if self.jump_index + 1 >= self.jump_list.len() {
+            self.set_status_message("Already at the newest jump");
+            return;
         }
+        self.jump_index += 1;
+        self.jump_to(self.jump_list[self.jump_index]);
     }
 
-    fn highlight_to_color(highlight: Highlight) -> Color {
-        match highlight {
-            Highlight::Number => Color::Magenta,
-            Highlight::String => Color::Yellow,
-            Highlight::Comment | Highlight::MultilineComment => Color::BrightBlack,
-            Highlight::Keyword1 => Color::Red,
-            Highlight::Keyword2 => Color::Cyan,
-            Highlight::Match => Color::Blue,
-            _ => Color::White,
+    fn toggle_selection(&mut self) {
+        if self.selection_anchor.take().is_none() {
+            self.selection_anchor = Some(self.cursor_position);
+            self.set_status_message("Selection started (Ctrl-Space or ESC to clear)");
+        }
+    }
+
+    // The start and end of the selection, in order.
+    fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.selection_anchor?;
+        let cursor = self.cursor_position;
+        if (anchor.y, anchor.x) <= (cursor.y, cursor.x) {
+            Some((anchor, cursor))
+        } else {
+            Some((cursor, anchor))
+        }
+    }
+
+    // The chars of a row that are selected.
+    fn selection_range(&self, y: usize) -> Option<(usize, usize)> {
+        let (start, end) = self.selection()?;
+        if y < start.y || y > end.y {
+            return None;
+        }
+        let from = if y == start.y { start.x } else { 0 };
+        let to = if y == end.y { end.x } else { usize::MAX };
+        Some((from, to))
+    }
+
+    // The rows that a line command works on: the ones with some of the
+    // selection on them, or else the cursor's.
+    fn selected_rows(&self) -> Option<(usize, usize)> {
+        let (first, last) = match self.selection() {
+            Some((start, end)) => (start.y, end.y),
+            None => (self.cursor_position.y, self.cursor_position.y),
+        };
+        let last = cmp::min(last, self.rows.len().checked_sub(1)?);
+        (first <= last).then_some((first, last))
+    }
+
+    fn set_mark(&mut self) {
+        self.set_status_message("Set mark: press a letter (ESC to cancel)");
+        self.refresh_screen();
+        match self.read_key() {
+            Key::Char(c) if c.is_ascii_alphabetic() => {
+                self.marks.insert(c, self.cursor_position);
+                self.set_status_message(&format!("Set mark {}", c));
+            }
+            _ => self.set_status_message(""),
+        }
+    }
+
+    fn jump_to_mark(&mut self) {
+        self.set_status_message(&format!(
+            "Jump to mark: press a letter, {} for the last edit or {} for the last jump",
+            LAST_EDIT_MARK, LAST_JUMP_MARK
+        ));
+        self.refresh_screen();
+        let mark = match self.read_key() {
+            Key::Char('`') => LAST_JUMP_MARK,
+            Key::Char(c) => c,
+            _ => {
+                self.set_status_message("");
+                return;
+            }
+        };
+        match self.marks.get(&mark) {
+            Some(&position) => {
+                self.set_status_message("");
+                self.record_jump(self.cursor_position);
+                self.jump_to(position);
+            }
+            None => self.set_status_message(&format!("Mark {} isn't set", mark)),
+        }
+    }
+
+    // Keep marks on the same lines when rows are inserted or deleted above
+    // them.
+    fn shift_marks(&mut self, from: usize, inserted: bool) {
+        for position in self.marks.values_mut().chain(&mut self.selection_anchor) {
+            if inserted && position.y >= from {
+                position.y += 1;
+            } else if !inserted && position.y > from {
+                position.y -= 1;
+            }
+        }
+    }
+
+    // Move the cursor to a remembered position, which may be past the end of
+    // the buffer if it's been edited since.
+    fn jump_to(&mut self, position: Position) {
+        self.cursor_position.y = cmp::min(position.y, self.rows.len());
+        self.cursor_position.x = match self.get_current_row() {
+            Some(row) => cmp::min(position.x, row.char_len),
+            None => 0,
+        };
+        if self.config.center_on_jump {
+            self.center_view();
+        }
+    }
+
+    fn scroll(&mut self) {
+        // Update which part of the file we're looking at based on the new
+        // position of the cursor.
+        let screen_x = self.get_current_screen_index();
+
+        if self.cursor_position.y < self.text_offset.y {
+            self.text_offset.y = self.cursor_position.y;
+        }
+
+        if self.cursor_position.y >= self.text_offset.y + self.text_rows() {
+            self.text_offset.y = self.cursor_position.y - self.text_rows() + 1;
+        }
+
+        if self.config.soft_wrap {
+            // Every row is visible from its start so there's no horizontal
+            // scrolling, but wrapped rows may push the cursor off the bottom.
+            self.text_offset.x = 0;
+            while self.text_offset.y < self.cursor_position.y
+                && self.get_cursor_screen_position().y >= self.text_rows()
+            {
+                self.text_offset.y += 1;
+            }
+            return;
+        }
+
+        if screen_x < self.text_offset.x {
+            self.text_offset.x = screen_x;
+        }
+
+        if screen_x >= self.text_offset.x + self.text_cols() {
+            self.text_offset.x = screen_x - self.text_cols() + 1;
+        }
+    }
+
+    fn process_keypress(&mut self) -> KeypressResult {
+        let key = self.read_key_or_focus();
+        // Focus changes aren't keypresses, so they don't cancel anything.
+        if matches!(key, Key::FocusIn | Key::FocusOut) {
+            if key == Key::FocusOut && self.config.auto_save_on_focus_loss {
+                self.auto_save();
+            }
+            return KeypressResult::Continue;
+        }
+        self.last_key_at = Instant::now();
+        self.backed_up = false;
+        self.match_counts.clear();
+        if action_info(Action::CompleteWord).key != Some(key) {
+            self.completion = None;
+        }
+        self.record_change(key);
+        self.process_key(key)
+    }
+
+    fn record_change(&mut self, key: Key) {
+        let typing = matches!(
+            key,
+            Key::Char(_) | Key::Enter | Key::Tab | Key::Backspace | Key::Delete
+        ) && !ACTIONS.iter().any(|info| info.key == Some(key));
+        if !typing {
+            // Editing actions are recorded when they run.
+            self.typing_change = false;
+            return;
+        }
+        if !self.typing_change {
+            self.last_change = Some(Change::Typing(Vec::new()));
+            self.typing_change = true;
+        }
+        if let Some(Change::Typing(keys)) = &mut self.last_change {
+            keys.push(key);
+        }
+    }
+
+    fn repeat_change(&mut self) {
+        match self.last_change.clone() {
+            Some(Change::Typing(keys)) => {
+                for key in keys {
+                    self.process_key(key);
+                }
+            }
+            Some(Change::Action(action)) => {
+                self.run_action(action);
+            }
+            None => self.set_status_message("No change to repeat"),
+        }
+    }
+
+    fn process_key(&mut self, key: Key) -> KeypressResult {
+        let action = ACTIONS
+            .iter()
+            .find(|info| info.key == Some(key))
+            .map(|info| info.action);
+
+        // Only vertical movement keeps the goal column.
+        if !matches!(
+            key,
+            Key::Arrow(Arrow::Up) | Key::Arrow(Arrow::Down) | Key::PageUp | Key::PageDown
+        ) && !matches!(
+            action,
+            Some(Action::HalfPageUp) | Some(Action::HalfPageDown)
+        ) {
+            self.goal_column = None;
+        }
+
+        if let Some(action) = action {
+            return self.run_action(action);
+        }
+
+        match key {
+            Key::Enter => {
+                self.insert_newline();
+            }
+            Key::Tab => {
+                self.insert_tab();
+            }
+            Key::Arrow(arrow) => {
+                self.move_cursor(arrow);
+            }
+            Key::PageUp => {
+                let lines = self.text_rows().saturating_sub(PAGE_OVERLAP);
+                self.scroll_lines(Arrow::Up, lines);
+            }
+            Key::PageDown => {
+                let lines = self.text_rows().saturating_sub(PAGE_OVERLAP);
+                self.scroll_lines(Arrow::Down, lines);
+            }
+            Key::Home => {
+                return self.run_action(Action::LineStart);
+            }
+            Key::End => {
+                return self.run_action(Action::LineEnd);
+            }
+            Key::Backspace => {
+                self.delete_char();
+            }
+            Key::Delete => {
+                self.delete_char_forward();
+            }
+            Key::Esc => self.selection_anchor = None,
+            // Ignore these keys.
+            Key::Insert | Key::FocusIn | Key::FocusOut => {}
+            Key::Char(c) => {
+                self.insert_char(c);
+            }
+            Key::Alt(c) => {
+                self.set_status_message(&format!("Unbound: Alt-{}", c));
+            }
+            Key::Ctrl(c) => {
+                // Don't insert a control character by accident.
+                self.set_status_message(&format!(
+                    "Unbound: ^{}",
+                    (c as u8 & 0b00011111 | 0b01000000) as char
+                ));
+            }
+        };
+
+        self.quit_times = QUIT_TIMES;
+        KeypressResult::Continue
+    }
+
+    fn run_action(&mut self, action: Action) -> KeypressResult {
+        if action.is_change() {
+            self.last_change = Some(Change::Action(action));
+        }
+        match action {
+            Action::Quit => {
+                if self.dirty && self.quit_times > 0 {
+                    let key = action_info(Action::Quit)
+                        .key
+                        .map_or("quit".to_string(), key_name);
+                    self.set_status_message(&format!(
+                        "WARNING!!! File has unsaved changes. \
+                         Press {} {} more times to quit.",
+                        key, self.quit_times
+                    ));
+                    self.quit_times -= 1;
+                    return KeypressResult::Continue;
+                }
+                // Only remember marks that match what's in the file.
+                if !self.dirty {
+                    self.persist_marks();
+                }
+                return KeypressResult::Terminate;
+            }
+            Action::Save => self.save(),
+            Action::Find => self.find(),
+            Action::CommandPalette => return self.command_palette(),
+            Action::CommandLine => self.command_line(),
+            Action::Reflow => self.reflow_paragraph(),
+            Action::QuotedInsert => self.quoted_insert(),
+            Action::ClearSearchHighlight => self.search_highlight = None,
+            Action::LineStart => self.cursor_position.x = 0,
+            Action::LineEnd => {
+                if let Some(row) = self.get_current_row() {
+                    self.cursor_position.x = row.char_len;
+                }
+            }
+            Action::HalfPageUp => self.scroll_lines(Arrow::Up, self.text_rows() / 2),
+            Action::HalfPageDown => self.scroll_lines(Arrow::Down, self.text_rows() / 2),
+            Action::CenterView => self.center_view(),
+            Action::Suspend => self.suspend(),
+            Action::ToggleSoftWrap => self.config.soft_wrap = !self.config.soft_wrap,
+            Action::ToggleRenderWhitespace => {
+                self.config.render_whitespace = !self.config.render_whitespace
+            }
+            Action::ToggleCursorLine => self.config.cursor_line = !self.config.cursor_line,
+            Action::ToggleWordHighlight => self.config.highlight_word = !self.config.highlight_word,
+            Action::CycleLineNumberMode => {
+                self.config.line_number_mode = self.config.line_number_mode.next();
+                self.set_status_message(&format!(
+                    "Line numbers: {}",
+                    self.config.line_number_mode.name()
+                ));
+            }
+            Action::JumpBack => self.jump_back(),
+            Action::JumpForward => self.jump_forward(),
+            Action::SetMark => self.set_mark(),
+            Action::JumpToMark => self.jump_to_mark(),
+            Action::ToggleSplit => self.toggle_split(),
+            Action::ScrollPageLeft => self.scroll_columns(Arrow::Left),
+            Action::ScrollPageRight => self.scroll_columns(Arrow::Right),
+            Action::FirstNonBlank => self.move_to_non_blank(false),
+            Action::LastNonBlank => self.move_to_non_blank(true),
+            Action::ToggleCsvView => self.toggle_csv_view(),
+            Action::NextLongLine => self.next_long_line(),
+            Action::ToggleOverwrite => self.overwrite = !self.overwrite,
+            Action::TransposeChars => self.transpose_chars(),
+            Action::TransposeWords => self.transpose_words(),
+            Action::UppercaseWord => self.change_word_case(Case::Upper),
+            Action::LowercaseWord => self.change_word_case(Case::Lower),
+            Action::TitlecaseWord => self.change_word_case(Case::Title),
+            Action::RepeatChange => self.repeat_change(),
+            Action::JumpToWord => self.jump_to_word(),
+            Action::OpenRecentFile => self.open_recent_file(),
+            Action::CompleteWord => self.complete_word(),
+            Action::OpenLineBelow => self.open_line(true),
+            Action::OpenLineAbove => self.open_line(false),
+            Action::ToggleSelection => self.toggle_selection(),
+            Action::DuplicateLines => self.duplicate_lines(),
+            Action::ToggleComment => self.toggle_comment(),
+            Action::GotoLine => self.goto_line(),
+            Action::Replace => self.replace_all(),
+            Action::GotoDefinition => self.goto_definition(),
+            Action::CountMatches => self.count_matches(),
+            Action::ToggleSyntaxHighlight => {
+                self.config.syntax_highlight = !self.config.syntax_highlight;
+                self.update_all_rows();
+            }
+            Action::ToggleListMode => {
+                self.config.list_mode = !self.config.list_mode;
+                self.update_all_rows();
+            }
+            Action::SwitchPane => {
+                if self.other_pane.is_none() {
+                    self.set_status_message("The screen isn't split");
+                }
+                self.switch_pane();
+            }
+        }
+
+        self.quit_times = QUIT_TIMES;
+        KeypressResult::Continue
+    }
+
+    // Pick an action by name, with fuzzy matching.
+    fn command_palette(&mut self) -> KeypressResult {
+        self.palette_selection = 0;
+        let query = self.prompt(
+            "Command: {} (Use ESC/Arrows/Enter)",
+            Editor::command_palette_callback,
+        );
+        let selected = query.and_then(|query| {
+            palette_matches(&query)
+                .get(self.palette_selection)
+                .map(|info| info.action)
+        });
+        match selected {
+            // The palette shouldn't be able to open itself.
+            Some(Action::CommandPalette) | None => {
+                self.quit_times = QUIT_TIMES;
+                KeypressResult::Continue
+            }
+            Some(action) => self.run_action(action),
+        }
+    }
+
+    fn command_palette_callback(&mut self, query: &str, key: Key) -> String {
+        let matches = palette_matches(query);
+        match key {
+            Key::Arrow(Arrow::Up) | Key::Arrow(Arrow::Left) => {
+                self.palette_selection = self.palette_selection.saturating_sub(1);
+            }
+            Key::Arrow(Arrow::Down) | Key::Arrow(Arrow::Right) => {
+                if self.palette_selection + 1 < matches.len() {
+                    self.palette_selection += 1;
+                }
+            }
+            Key::Enter | Key::Esc => return String::new(),
+            _ => self.palette_selection = 0,
+        }
+
+        if matches.is_empty() {
+            return ": No matching commands".to_string();
+        }
+        let shown = matches
+            .iter()
+            .enumerate()
+            .skip(self.palette_selection)
+            .take(PALETTE_MATCHES_SHOWN)
+            .map(|(i, info)| {
+                let name = match info.key {
+                    Some(key) => format!("{} ({})", info.name, key_name(key)),
+                    None => info.name.to_string(),
+                };
+                if i == self.palette_selection {
+                    format!("[{}]", name)
+                } else {
+                    name
+                }
+            })
+            .collect::<Vec<_>>();
+        format!(": {}", shown.join(" | "))
+    }
+
+    fn suspend(&mut self) {
+        self.reset_screen();
+        self.restore_title();
+        set_focus_reporting(false);
+        if let Some(orig_termios) = &mut self.orig_termios {
+            disable_raw_mode(orig_termios);
+        }
+        // This stops the process. We carry on from here once we're resumed
+        // (or straight away if the signal is ignored).
+        signal::raise(Signal::SIGTSTP).expect("Error raising SIGTSTP");
+        RESUMED.store(false, Ordering::SeqCst);
+        self.resume();
+    }
+
+    fn resume(&mut self) {
+        // Without a terminal there's no screen to put back.
+        if self.orig_termios.is_none() {
+            return;
+        }
+        // There's nothing better to do than carry on if these fail.
+        enable_raw_mode().ok();
+        set_focus_reporting(true);
+        // The terminal may have been resized while we were stopped.
+        if let Some(screen_dimensions) = get_window_size() {
+            self.screen_dimensions = screen_dimensions;
+            self.screen_dimensions.rows = self.screen_dimensions.rows.saturating_sub(2);
+        }
+        self.reset_screen();
+    }
+
+    // Wait for a key, returning whether one came. Until it does, auto-save
+    // and back up when it's time to, and put the screen back if we were
+    // stopped and continued by someone else. Either of those means the
+    // screen needs drawing again first.
+    fn wait_for_key(&mut self) -> bool {
+        loop {
+            if RESUMED.swap(false, Ordering::SeqCst) {
+                self.resume();
+                return false;
+            }
+            // Prompts read keys themselves, so this never happens in one.
+            let poll = Duration::from_millis(RESUME_POLL_MS);
+            let timeout = [self.auto_save_timeout(), self.backup_timeout()]
+                .iter()
+                .flatten()
+                .min()
+                .map_or(poll, |&timeout| cmp::min(timeout, poll));
+            if self.wait_for_input(timeout) {
+                return true;
+            }
+            let mut saved = false;
+            if self
+                .auto_save_timeout()
+                .is_some_and(|timeout| timeout.is_zero())
+            {
+                self.auto_save();
+                saved = true;
+            }
+            if self
+                .backup_timeout()
+                .is_some_and(|timeout| timeout.is_zero())
+            {
+                self.write_backup();
+                saved = true;
+            }
+            if saved {
+                return false;
+            }
+        }
+    }
+
+    fn render_loop(&mut self) {
+        loop {
+            // Handle all the keys that are already waiting, like a paste,
+            // before drawing and highlighting again.
+            if !(self.config.defer_highlight && self.input_pending()) {
+                self.refresh_screen();
+            }
+            if !self.dirty {
+                self.clean_at = Instant::now();
+            }
+            if !self.wait_for_key() {
+                continue;
+            }
+            if let KeypressResult::Terminate = self.process_keypress() {
+                break;
+            }
+        }
+
+        self.reset_screen();
+        self.restore_title();
+    }
+}
+
+// The text of a script's insert, with its escapes turned into the
+// characters they stand for.
+fn unescape_script_text(text: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('\\') => unescaped.push('\\'),
+            Some(c) => return Err(format!("Unknown escape: \\{}", c)),
+            None => return Err("Nothing to escape after \\".to_string()),
+        }
+    }
+    Ok(unescaped)
+}
+
+/*** init ***/
+
+// Why the editor couldn't start. Each kind exits with its own code so that
+// scripts can tell them apart.
+enum StartupError {
+    NotATerminal(String),
+    CantOpen(String),
+    BadConfig(String),
+    CheckFailed(String),
+    ScriptFailed(String),
+}
+
+impl StartupError {
+    fn message(&self) -> &str {
+        match self {
+            StartupError::NotATerminal(message)
+            | StartupError::CantOpen(message)
+            | StartupError::BadConfig(message)
+            | StartupError::CheckFailed(message)
+            | StartupError::ScriptFailed(message) => message,
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::NotATerminal(_) => 2,
+            StartupError::CantOpen(_) => 3,
+            StartupError::BadConfig(_) => 4,
+            // What a check in CI is expected to fail with.
+            StartupError::CheckFailed(_) => 1,
+            StartupError::ScriptFailed(_) => 5,
+        }
+    }
+}
+
+fn enable_raw_mode() -> Result<Termios, StartupError> {
+    let stdin = io::stdin();
+    let not_a_terminal = |error: nix::Error| {
+        StartupError::NotATerminal(format!("stdin isn't a terminal: {}", error.desc()))
+    };
+    let orig_termios = termios::tcgetattr(stdin.as_fd()).map_err(not_a_terminal)?;
+
+    let mut termios = orig_termios.clone();
+    termios.input_flags &= !(InputFlags::BRKINT
+        | InputFlags::ICRNL
+        | InputFlags::INPCK
+        | InputFlags::ISTRIP
+        | InputFlags::IXON);
+    termios.output_flags &= !(OutputFlags::OPOST);
+    termios.control_flags |= ControlFlags::CS8;
+    termios.local_flags &=
+        !(LocalFlags::ECHO | LocalFlags::ICANON | LocalFlags::IEXTEN | LocalFlags::ISIG);
+    // Rust always blocks when reading from stdin.
+    // termios.c_cc[VMIN] = 0;
+    // termios.c_cc[VTIME] = 1;
+    termios::tcsetattr(stdin.as_fd(), SetArg::TCSAFLUSH, &termios).map_err(not_a_terminal)?;
+
+    Ok(orig_termios)
+}
+
+fn disable_raw_mode(orig_termios: &mut Termios) {
+    termios::tcsetattr(io::stdin(), SetArg::TCSAFLUSH, orig_termios).expect("Error in tcsetattr");
+}
+
+// Ask the terminal to tell us when it gains or loses focus.
+fn set_focus_reporting(enabled: bool) {
+    let mut stdout = io::stdout();
+    stdout
+        .write_all(if enabled {
+            b"\x1b[?1004h"
+        } else {
+            b"\x1b[?1004l"
+        })
+        .expect("Error writing to stdout");
+    stdout.flush().expect("Error flushing stdout");
+}
+
+extern "C" fn handle_sigcont(_: libc::c_int) {
+    RESUMED.store(true, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigcont),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safe because the handler only touches an atomic.
+    unsafe { signal::sigaction(Signal::SIGCONT, &action) }.expect("Error in sigaction");
+}
+
+struct TerminalRestorer {
+    orig_termios: Termios,
+}
+
+impl Drop for TerminalRestorer {
+    fn drop(&mut self) {
+        set_focus_reporting(false);
+        disable_raw_mode(&mut self.orig_termios);
+    }
+}
+
+fn main() {
+    if let Err(error) = run() {
+        // The terminal has been restored by now.
+        eprintln!("kilo: {}", error.message());
+        std::process::exit(error.exit_code());
+    }
+}
+
+// Load the config and the project filetypes that would apply to a file (or
+// to the current directory) without starting the editor, and say what's
+// wrong with them.
+fn check_config(config_path: Option<String>, filename: Option<&str>) -> Result<(), StartupError> {
+    let mut errors = Vec::new();
+    if let Err(error) = load_config(config_path) {
+        errors.push(error);
+    }
+    let directory = env::current_dir().map_err(|error| {
+        StartupError::CheckFailed(format!("can't get the current directory: {}", error))
+    })?;
+    let directory = match filename {
+        Some(filename) => directory
+            .join(filename)
+            .parent()
+            .map_or(directory.clone(), Path::to_path_buf),
+        None => directory,
+    };
+    let filetypes = project_filetypes(&directory).unwrap_or_else(|error| {
+        errors.push(error);
+        Vec::new()
+    });
+
+    // main prints the last error.
+    if let Some(last) = errors.pop() {
+        for error in errors {
+            eprintln!("kilo: {}", error);
         }
+        return Err(StartupError::CheckFailed(last));
+    }
+    let names: Vec<&str> = filetypes.iter().map(|filetype| filetype.name).collect();
+    match names.len() {
+        0 => println!("config ok, no project filetypes"),
+        1 => println!("config ok, 1 project filetype: {}", names[0]),
+        n => println!("config ok, {} project filetypes: {}", n, names.join(", ")),
     }
+    Ok(())
+}
 
-    fn detect_filetype(&mut self) {
-        match &self.filename {
-            Some(name) => {
-                for filetype in &FILETYPES {
-                    for pattern in filetype.filename_patterns {
-                        if (pattern.starts_with('.') && name.ends_with(pattern))
-                            || (!pattern.starts_with('.') && name.contains(pattern))
-                        {
-                            self.filetype = Some(filetype);
-                            for y in 0..self.rows.len() {
-                                self.update_row_highlight(y);
-                            }
-                            return;
-                        }
-                    }
-                }
+// Apply the commands in a script to a file without a terminal, stopping at
+// the first one that fails. Reaching the end is like quit, so the changes
+// have to have been saved.
+fn run_script(
+    script: &str,
+    config: Config,
+    encoding: Encoding,
+    filename: Option<&str>,
+) -> Result<(), StartupError> {
+    let filename = filename
+        .ok_or_else(|| StartupError::ScriptFailed("--script needs a file to edit".to_string()))?;
+    let contents = std::fs::read_to_string(script)
+        .map_err(|error| StartupError::CantOpen(format!("can't read {}: {}", script, error)))?;
+
+    let mut editor = Editor::headless(config);
+    editor.encoding = encoding;
+    editor.open(filename).map_err(StartupError::CantOpen)?;
+    // Files that can't be edited are refused with a message instead.
+    if editor.filename.is_none() {
+        return Err(StartupError::CantOpen(editor.status_message));
+    }
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim_start();
+        if line.trim_end().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match editor.run_script_line(line) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(error) => {
+                return Err(StartupError::ScriptFailed(format!(
+                    "{}:{}: {}",
+                    script,
+                    i + 1,
+                    error
+                )))
             }
-            None => {}
         }
     }
+    if editor.dirty {
+        return Err(StartupError::ScriptFailed(format!(
+            "{}: the changes weren't saved (end with save, or quit! to drop them)",
+            script
+        )));
+    }
+    Ok(())
+}
 
-    // *** Row Operations ***
-
-    fn update_row_highlight(&mut self, y: usize) {
-        if y >= self.rows.len() {
-            return;
+fn run() -> Result<(), StartupError> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut config_path = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--config") {
+        if i + 1 >= args.len() {
+            return Err(StartupError::BadConfig("--config needs a path".to_string()));
+        }
+        config_path = Some(args.remove(i + 1));
+        args.remove(i);
+    }
+    let mut encoding = Encoding::Utf8;
+    if let Some(i) = args.iter().position(|arg| arg == "--encoding") {
+        let name = args
+            .get(i + 1)
+            .ok_or_else(|| StartupError::BadConfig("--encoding needs a name".to_string()))?;
+        encoding = Encoding::from_name(name)
+            .ok_or_else(|| StartupError::BadConfig(format!("unknown encoding: {}", name)))?;
+        args.drain(i..i + 2);
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--no-color") {
+        args.remove(i);
+        NO_COLOR.store(true, Ordering::Relaxed);
+    }
+    // Any value but an empty one counts, as https://no-color.org says.
+    if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        NO_COLOR.store(true, Ordering::Relaxed);
+    }
+    let mut script = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--script") {
+        if i + 1 >= args.len() {
+            return Err(StartupError::ScriptFailed(
+                "--script needs a path".to_string(),
+            ));
         }
-        let (first, last) = self.rows.split_at_mut(y);
-        let row = &mut last[0];
+        script = Some(args.remove(i + 1));
+        args.remove(i);
+    }
+    if args.first().is_some_and(|arg| arg == "--check-config") {
+        return check_config(config_path, args.get(1).map(String::as_str));
+    }
+    // Before raw mode, so that a bad config doesn't flash up the editor.
+    let config = load_config(config_path).map_err(StartupError::BadConfig)?;
+    if let Some(script) = script {
+        return run_script(&script, config, encoding, args.first().map(String::as_str));
+    }
 
-        row.highlight.clear();
-        let mut chars = row.render.char_indices().enumerate();
-        let line_length = row.render.chars().count();
+    // Enabling raw mode and saving current terminal options.
+    let orig_termios = enable_raw_mode()?;
+    // Restore the original terminal options when this struct is dropped.
+    // This ensures the original options are restored even if we panic.
+    let _terminal_restorer = TerminalRestorer {
+        orig_termios: orig_termios.clone(),
+    };
 
-        let singleline_comment_start = if let Some(f) = self.filetype {
-            f.singleline_comment_start
-        } else {
-            ""
-        };
-        let multiline_comment_start = if let Some(f) = self.filetype {
-            f.multiline_comment_start
-        } else {
-            ""
-        };
-        let multiline_comment_end = if let Some(f) = self.filetype {
-            f.multiline_comment_end
-        } else {
-            ""
-        };
+    install_signal_handlers();
+    set_focus_reporting(true);
 
-        let mut prev_separator = true;
-        let mut in_singleline_comment = false;
-        let mut in_multiline_comment = y != 0 && first[first.len() - 1].continue_multiline_comment;
+    let mut editor = Editor::new(orig_termios, config)?;
+    editor.encoding = encoding;
 
-        let mut quote = if y == 0 || in_multiline_comment {
-            None
-        } else {
-            first[first.len() - 1].continue_multiline_string
-        };
+    match args.as_slice() {
+        [flag, filename] if flag == "--hex" => editor.open_hex(filename),
+        #[cfg(feature = "url")]
+        [url, ..] if url.starts_with("http://") || url.starts_with("https://") => {
+            editor.open_url(url).map_err(StartupError::CantOpen)?
+        }
+        [filename, ..] => editor.open(filename).map_err(StartupError::CantOpen)?,
+        [] => {}
+    }
 
-        loop {
-            let prev_highlight = *row.highlight.last().unwrap_or(&Highlight::Normal);
-            let next = chars.next();
-            if let Some((i, (byte_index, c))) = next {
-                // No syntax highlighting.
-                if self.filetype.is_none() {
-                    row.highlight.push(Highlight::Normal);
-                    continue;
-                }
+    // Don't hide any error from opening the file.
+    if editor.status_message.is_empty() {
+        editor.set_status_message("HELP: Ctrl-S = Save | Ctrl-R = Find | Ctrl-Q = Quit");
+    }
 
-                // Continuing a single-line comment.
-                if in_singleline_comment {
-                    row.highlight.push(Highlight::Comment);
-                    continue;
-                }
+    editor.render_loop();
+    Ok(())
+}
 
-                // Starting a single-line comment.
-                if !singleline_comment_start.is_empty()
-                    && quote.is_none()
-                    && !in_multiline_comment
-                    && row.render[byte_index..].starts_with(singleline_comment_start)
-                {
-                    in_singleline_comment = true;
-                    row.highlight.push(Highlight::Comment);
-                    continue;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An editor with some text loaded as if from a file, without a terminal
+    // or the file itself.
+    fn editor_with(filename: &str, text: &str, config: Config) -> Editor {
+        let mut editor = Editor::headless(config);
+        assert!(editor.load_text(filename, text.as_bytes().to_vec()));
+        editor.detect_filetype();
+        editor.update_stale_highlights(usize::MAX);
+        editor
+    }
 
-                // Multi-line comments.
-                if !multiline_comment_start.is_empty() && quote.is_none() {
-                    if in_multiline_comment {
-                        if row.render[byte_index..].starts_with(multiline_comment_end) {
-                            row.highlight.push(Highlight::MultilineComment);
-                            for _ in 0..multiline_comment_end.len() - 1 {
-                                chars.next();
-                                row.highlight.push(Highlight::MultilineComment);
-                            }
-                            in_multiline_comment = false;
-                        } else {
-                            row.highlight.push(Highlight::MultilineComment);
-                        }
-                        continue;
-                    } else if row.render[byte_index..].starts_with(multiline_comment_start) {
-                        row.highlight.push(Highlight::MultilineComment);
-                        for _ in 0..multiline_comment_start.len() - 1 {
-                            chars.next();
-                            row.highlight.push(Highlight::MultilineComment);
-                        }
-                        in_multiline_comment = true;
-                        continue;
-                    }
-                }
+    fn rainbow_config() -> Config {
+        Config {
+            rainbow_brackets: true,
+            ..Config::default()
+        }
+    }
 
-                // Strings.
-                if self.filetype.unwrap().flags & HIGHLIGHT_STRINGS != 0 {
-                    match quote {
-                        Some(q) => {
-                            // In a string.
-                            row.highlight.push(Highlight::String);
-                            if c == '\\' {
-                                if i < line_length - 1 {
-                                    row.highlight.push(Highlight::String);
-                                    chars.next();
-                                    if i == line_length - 2 {
-                                        quote = None;
-                                    }
-                                }
-                            } else if c == q {
-                                // String ends.
-                                quote = None;
-                                prev_separator = true;
-                            } else if i == line_length - 1 {
-                                quote = None;
-                            }
-                            continue;
-                        }
-                        None => {
-                            // Not in a string.
-                            if c == '"' || c == '\'' {
-                                // String starts.
-                                quote = Some(c);
-                                row.highlight.push(Highlight::String);
-                                if i == line_length - 1 {
-                                    quote = None;
-                                }
-                                continue;
-                            }
-                        }
-                    }
-                }
+    // The highlight of each char in a row, for rows without tabs.
+    fn highlights(editor: &Editor, y: usize) -> Vec<Highlight> {
+        editor.rows[y].highlight.clone()
+    }
 
-                // Numbers.
-                if self.filetype.unwrap().flags & HIGHLIGHT_NUMBERS != 0
-                    && ((c.is_digit(10) && (prev_separator || prev_highlight == Highlight::Number))
-                        || (c == '.' && prev_highlight == Highlight::Number))
-                {
-                    row.highlight.push(Highlight::Number);
-                    prev_separator = false;
-                    continue;
-                }
+    #[test]
+    fn brackets_are_colored_by_depth() {
+        let editor = editor_with("x.rs", "(([{}]))", rainbow_config());
+        let expected: Vec<Highlight> = [0, 1, 2, 3, 3, 2, 1, 0]
+            .iter()
+            .map(|&depth| Highlight::Bracket(depth))
+            .collect();
+        assert_eq!(highlights(&editor, 0), expected);
+    }
 
-                // Keywords.
-                if prev_separator {
-                    let mut found_keyword = false;
-                    'outer: for (keywords, highlight) in [
-                        (self.filetype.unwrap().keywords1, Highlight::Keyword1),
-                        (self.filetype.unwrap().keywords2, Highlight::Keyword2),
-                    ] {
-                        for keyword in keywords {
-                            if row.render[byte_index..].starts_with(keyword)
-                                && is_separator(
-                                    row.render[byte_index + keyword.len()..]
-                                        .chars()
-                                        .next()
-                                        .unwrap_or(' '),
-                                )
-                            {
-                                row.highlight.push(highlight);
-                                for _ in 0..keyword.len() - 1 {
-                                    chars.next();
-                                    row.highlight.push(highlight);
-                                }
-                                found_keyword = true;
-                                break 'outer;
-                            }
-                        }
-                    }
-                    if found_keyword {
-                        prev_separator = false;
-                        continue;
-                    }
-                }
+    #[test]
+    fn bracket_colors_cycle_when_deeply_nested() {
+        let depth = BRACKET_COLORS.len() + 2;
+        let text = format!("{}{}", "(".repeat(depth), ")".repeat(depth));
+        let editor = editor_with("x.rs", &text, rainbow_config());
+        let row = highlights(&editor, 0);
+        assert_eq!(row[BRACKET_COLORS.len()], Highlight::Bracket(0));
+        assert_eq!(row[depth - 1], Highlight::Bracket(1));
+        assert_eq!(row[depth], Highlight::Bracket(1));
+        assert_eq!(row[2 * depth - 1], Highlight::Bracket(0));
+    }
 
-                row.highlight.push(Highlight::Normal);
-                prev_separator = is_separator(c);
-            } else {
-                break;
-            }
-        }
+    #[test]
+    fn bracket_depth_carries_across_rows() {
+        let editor = editor_with("x.rs", "(\n  [\n  ]\n)", rainbow_config());
+        assert_eq!(highlights(&editor, 1)[2], Highlight::Bracket(1));
+        assert_eq!(highlights(&editor, 2)[2], Highlight::Bracket(1));
+        assert_eq!(highlights(&editor, 3)[0], Highlight::Bracket(0));
+    }
 
-        // Check whether we need to update the syntax of following lines.
-        // eg. we could start a multiline comment on this line which could
-        // comment out the rest of the file.
-        let changed = row.continue_multiline_string != quote
-            || row.continue_multiline_comment != in_multiline_comment;
-        row.continue_multiline_comment = in_multiline_comment;
-        row.continue_multiline_string = quote;
-        if changed {
-            self.update_row_highlight(y + 1);
-        }
+    #[test]
+    fn unmatched_closing_brackets_are_left_alone() {
+        let editor = editor_with("x.rs", ")]\n(\n)\n)", rainbow_config());
+        assert_eq!(highlights(&editor, 0), vec![Highlight::Normal; 2]);
+        assert_eq!(highlights(&editor, 1)[0], Highlight::Bracket(0));
+        assert_eq!(highlights(&editor, 2)[0], Highlight::Bracket(0));
+        assert_eq!(highlights(&editor, 3)[0], Highlight::Normal);
     }
 
-    fn update_row_render(&mut self, y: usize) {
-        if y >= self.rows.len() {
-            return;
-        }
-        let row = &mut self.rows[y];
-        row.render.clear();
+    #[test]
+    fn brackets_in_strings_and_comments_are_skipped() {
+        let editor = editor_with("x.rs", "\"(\" // (\n)", rainbow_config());
+        assert!(!highlights(&editor, 0)
+            .iter()
+            .any(|highlight| matches!(highlight, Highlight::Bracket(_))));
+        assert_eq!(highlights(&editor, 1)[0], Highlight::Normal);
+    }
 
-        let mut render_length = 0;
+    #[test]
+    fn typing_into_a_fresh_buffer_makes_the_first_row() {
+        let mut editor = Editor::headless(Config::default());
+        assert!(editor.rows.is_empty());
+        editor.insert_char('a');
+        editor.insert_char('b');
+        assert_eq!(editor.rows.len(), 1);
+        assert_eq!(editor.rows[0].chars, "ab");
+        assert_eq!(editor.cursor_position, Position { x: 2, y: 0 });
+        assert!(editor.dirty);
+    }
 
-        for c in row.chars.chars() {
-            if c == '\t' {
-                let mut tab_size = TAB_STOP - (render_length % TAB_STOP);
-                while tab_size > 0 {
-                    row.render.push(' ');
-                    render_length += 1;
-                    tab_size -= 1;
-                }
-            } else if c.is_control() {
-                row.render.push(c);
-                render_length += 1;
-            } else {
-                row.render.push(c);
-                render_length += 1;
-                for _ in 0..UnicodeWidthChar::width(c).unwrap_or(1) - 1 {
-                    render_length += 1;
-                }
-            }
-        }
+    #[test]
+    fn enter_in_a_fresh_buffer_moves_to_the_next_row() {
+        let mut editor = Editor::headless(Config::default());
+        editor.insert_newline();
+        editor.insert_char('x');
+        assert_eq!(editor.rows.len(), 2);
+        assert_eq!(editor.rows[0].chars, "");
+        assert_eq!(editor.rows[1].chars, "x");
+        assert_eq!(editor.cursor_position, Position { x: 1, y: 1 });
     }
 
-    fn update_row(&mut self, y: usize) {
-        self.update_row_render(y);
-        self.update_row_highlight(y);
+    fn rows(editor: &Editor) -> Vec<&str> {
+        editor.rows.iter().map(|row| row.chars.as_str()).collect()
     }
 
-    fn insert_char_in_row(&mut self, y: usize, mut index: usize, c: char) {
-        if y >= self.rows.len() {
-            return;
-        }
-        let row = &mut self.rows[y];
-        let count = row.chars.chars().count();
-        if index > count {
-            index = count;
-        }
+    #[test]
+    fn lf_files_keep_lf() {
+        let editor = editor_with("x.txt", "a\nb\n", Config::default());
+        assert_eq!(rows(&editor), ["a", "b"]);
+        assert_eq!(editor.detected_line_ending, Some(LineEnding::Lf));
+        assert_eq!(editor.rows_to_string(), "a\nb\n");
+    }
 
-        let mut new_chars = String::new();
+    #[test]
+    fn crlf_files_keep_crlf() {
+        let editor = editor_with("x.txt", "a\r\nb", Config::default());
+        assert_eq!(rows(&editor), ["a", "b"]);
+        assert_eq!(editor.detected_line_ending, Some(LineEnding::CrLf));
+        assert_eq!(editor.detected_final_newline, Some(false));
+        assert_eq!(editor.rows_to_string(), "a\r\nb");
+    }
 
-        if index == count {
-            row.chars.push(c);
-        } else {
-            for (i, char) in row.chars.chars().enumerate() {
-                if i == index {
-                    new_chars.push(c);
-                }
-                new_chars.push(char);
-            }
+    #[test]
+    fn mixed_line_endings_use_the_most_common_and_warn() {
+        let editor = editor_with("x.txt", "a\r\nb\nc\nd\r\ne\n", Config::default());
+        assert_eq!(rows(&editor), ["a", "b", "c", "d", "e"]);
+        assert_eq!(editor.detected_line_ending, Some(LineEnding::Lf));
+        assert!(editor.status_message.contains("mixed line endings"));
+        assert_eq!(editor.rows_to_string(), "a\nb\nc\nd\ne\n");
+    }
 
-            row.chars = new_chars;
-        }
+    #[test]
+    fn cr_only_files_are_split_on_cr() {
+        let (lines, endings) = split_lines("a\rb\r");
+        assert_eq!(lines, ["a", "b"]);
+        assert_eq!(endings, [LineEnding::Cr, LineEnding::Cr]);
+    }
 
-        self.update_row(y);
+    #[test]
+    fn delete_removes_the_char_under_the_cursor() {
+        let mut editor = editor_with("x.txt", "abc\n", Config::default());
+        editor.cursor_position = Position { x: 1, y: 0 };
+        editor.delete_char_forward();
+        assert_eq!(rows(&editor), ["ac"]);
+        assert_eq!(editor.cursor_position, Position { x: 1, y: 0 });
+        assert!(editor.dirty);
     }
 
-    fn append_string_to_row(&mut self, y: usize, s: &str) {
-        if y >= self.rows.len() {
-            return;
-        }
-        let row = &mut self.rows[y];
-        row.chars.push_str(s);
-        self.update_row(y);
+    #[test]
+    fn delete_at_the_end_of_a_line_joins_the_next_one() {
+        let mut editor = editor_with("x.txt", "ab\ncd\n", Config::default());
+        editor.cursor_position = Position { x: 2, y: 0 };
+        editor.delete_char_forward();
+        assert_eq!(rows(&editor), ["abcd"]);
+        assert_eq!(editor.cursor_position, Position { x: 2, y: 0 });
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn delete_at_the_end_of_the_file_does_nothing() {
+        let mut editor = editor_with("x.txt", "ab\n", Config::default());
+        editor.cursor_position = Position { x: 2, y: 0 };
+        editor.delete_char_forward();
+        editor.cursor_position = Position { x: 0, y: 1 };
+        editor.delete_char_forward();
+        assert_eq!(rows(&editor), ["ab"]);
+        assert!(!editor.dirty);
+    }
+
+    fn tab_inserted(filename: &str, config: Config) -> String {
+        let mut editor = editor_with(filename, "", config);
+        editor.insert_tab();
+        editor.rows[0].chars.clone()
     }
 
-    fn delete_char_in_row(&mut self, y: usize, index: usize) {
-        if y >= self.rows.len() {
-            return;
-        }
-        let row = &mut self.rows[y];
-        let count = row.chars.chars().count();
-        if index >= count {
-            return;
-        }
+    #[test]
+    fn tab_inserts_the_filetypes_indent() {
+        assert_eq!(tab_inserted("x.py", Config::default()), "    ");
+        assert_eq!(tab_inserted("x.go", Config::default()), "\t");
+        assert_eq!(tab_inserted("x.txt", Config::default()), "\t");
+    }
 
-        let mut new_chars = String::new();
+    #[test]
+    fn makefiles_get_tabs_even_when_spaces_are_set() {
+        let config = Config {
+            expand_tabs: true,
+            ..Config::default()
+        };
+        assert_eq!(tab_inserted("Makefile", config.clone()), "\t");
+        assert_eq!(tab_inserted("x.txt", config), "    ");
+    }
 
-        for (i, char) in row.chars.chars().enumerate() {
-            if i != index {
-                new_chars.push(char);
-            }
-        }
+    #[test]
+    fn nul_bytes_look_binary() {
+        assert!(Editor::looks_binary(b"ELF\x00\x01\x02"));
+        assert!(!Editor::looks_binary(b"plain text\twith a tab\r\n"));
+    }
 
-        row.chars = new_chars;
-        self.update_row(y);
+    #[test]
+    fn lots_of_control_bytes_look_binary() {
+        let mut bytes = b"mostly text but".to_vec();
+        bytes.extend([1, 2, 3, 4, 5]);
+        assert!(Editor::looks_binary(&bytes));
+        // Escapes from colored output are fine.
+        assert!(!Editor::looks_binary(b"\x1b[31mred\x1b[m\n"));
     }
 
-    fn insert_row(&mut self, index: usize, chars: &str) {
-        if index > self.rows.len() {
-            return;
+    #[test]
+    fn binary_files_are_refused() {
+        let mut editor = Editor::headless(Config::default());
+        assert!(!editor.load_text("x.bin", b"\x7fELF\x00\x00".to_vec()));
+        assert!(editor.rows.is_empty());
+        assert!(editor.filename.is_none());
+        assert!(editor.status_message.contains("binary"));
+    }
+
+    fn assert_char_lens(editor: &Editor) {
+        for row in &editor.rows {
+            assert_eq!(row.char_len, row.chars.chars().count(), "{:?}", row.chars);
         }
+    }
 
-        let row = Row {
+    #[test]
+    fn cached_char_lengths_stay_right_after_edits() {
+        let mut editor = editor_with("x.txt", "héllo wörld\nçà\n", Config::default());
+        assert_char_lens(&editor);
+        editor.cursor_position = Position { x: 2, y: 0 };
+        editor.insert_char('€');
+        assert_char_lens(&editor);
+        editor.delete_char();
+        editor.delete_char();
+        assert_char_lens(&editor);
+        // Split the row, then join it back up from both ends.
+        editor.insert_newline();
+        assert_char_lens(&editor);
+        editor.delete_char();
+        assert_char_lens(&editor);
+        editor.cursor_position = Position { x: 10, y: 0 };
+        editor.delete_char_forward();
+        assert_char_lens(&editor);
+        assert_eq!(rows(&editor), ["hllo wörldçà"]);
+    }
+
+    fn row(chars: &str) -> Row {
+        Row {
             chars: chars.to_string(),
+            char_len: chars.chars().count(),
             render: String::new(),
             highlight: Vec::new(),
             continue_multiline_comment: false,
             continue_multiline_string: None,
-        };
-        self.rows.insert(index, row);
-        self.update_row(index);
-        self.dirty = true;
+            continue_code_block: false,
+            continue_bracket_depth: 0,
+            highlight_stale: false,
+            csv_widths: Vec::new(),
+            too_long: false,
+            bad_indent: 0,
+            continue_recipe: false,
+        }
     }
 
-    // TODO: get_screen_index, screen_index_to_char_index and get_render_index
-    // are pretty similar.
-    fn get_screen_index(&self, x: usize, y: usize) -> usize {
-        if y >= self.rows.len() || x == 0 {
-            return 0;
-        }
+    fn row_texts(rows: &Rows) -> Vec<&str> {
+        rows.iter().map(|row| row.chars.as_str()).collect()
+    }
 
-        let mut screen_index = 0;
+    #[test]
+    fn rows_stay_in_order_as_the_gap_moves() {
+        let mut rows = Rows::new();
+        for (i, chars) in ["a", "c", "e"].iter().enumerate() {
+            rows.insert(i, row(chars));
+        }
+        rows.insert(1, row("b"));
+        rows.insert(3, row("d"));
+        assert_eq!(row_texts(&rows), ["a", "b", "c", "d", "e"]);
+        assert_eq!(rows.remove(0).chars, "a");
+        assert_eq!(rows.remove(3).chars, "e");
+        assert_eq!(row_texts(&rows), ["b", "c", "d"]);
+        assert_eq!(rows.len(), 3);
+        // With the gap at the start, and then in the middle.
+        rows.insert(0, row("a"));
+        assert_eq!(rows[3].chars, "d");
+        assert!(rows.get(4).is_none());
+        rows.move_gap(2);
+        assert_eq!(rows[1].chars, "b");
+        assert_eq!(rows[2].chars, "c");
+        rows[3].chars.push('!');
+        assert_eq!(rows.first().unwrap().chars, "a");
+        assert_eq!(row_texts(&rows), ["a", "b", "c", "d!"]);
+    }
 
-        let row = &self.rows[y];
+    #[test]
+    fn row_ranges_span_the_gap() {
+        let mut rows = Rows::new();
+        for (i, chars) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            rows.insert(i, row(chars));
+        }
+        rows.move_gap(2);
+        let range = |start, end| -> Vec<String> {
+            rows.range(start, end)
+                .map(|row| row.chars.clone())
+                .collect()
+        };
+        assert_eq!(range(0, 5), ["a", "b", "c", "d", "e"]);
+        assert_eq!(range(1, 4), ["b", "c", "d"]);
+        assert_eq!(range(3, 5), ["d", "e"]);
+        assert_eq!(range(0, 2), ["a", "b"]);
+        assert_eq!(range(4, 10), ["e"]);
+        assert!(range(3, 3).is_empty());
+        let backwards: Vec<&str> = rows
+            .range(0, 4)
+            .rev()
+            .map(|row| row.chars.as_str())
+            .collect();
+        assert_eq!(backwards, ["d", "c", "b", "a"]);
+    }
 
-        for c in row.chars.chars().take(x) {
-            if c == '\t' {
-                screen_index += (TAB_STOP - 1) - (screen_index % TAB_STOP) + 1;
-            } else if c.is_control() {
-                screen_index += 1;
-            } else {
-                screen_index += UnicodeWidthChar::width(c).unwrap_or(0);
-            }
+    #[test]
+    fn the_previous_row_is_found_on_either_side_of_the_gap() {
+        let mut rows = Rows::new();
+        for (i, chars) in ["a", "b", "c", "d"].iter().enumerate() {
+            rows.insert(i, row(chars));
         }
-        screen_index
+        rows.move_gap(2);
+        // Each row has been changed by the time it's the previous one.
+        for (y, expected) in [(0, None), (1, Some("a!")), (2, Some("b!")), (3, Some("c!"))] {
+            let (previous, row) = rows.with_previous_mut(y);
+            assert_eq!(previous.map(|row| row.chars.as_str()), expected);
+            row.chars.push('!');
+        }
+        assert_eq!(row_texts(&rows), ["a!", "b!", "c!", "d!"]);
     }
 
-    fn get_current_screen_index(&self) -> usize {
-        self.get_screen_index(self.cursor_position.x, self.cursor_position.y)
+    // Not run by default, since it's about timing. Run it with
+    // cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_inserting_and_deleting_lines_in_a_large_file() {
+        const LINES: usize = 100_000;
+        const EDITS: usize = 2_000;
+        let text: String = (0..LINES).map(|i| format!("line {}\n", i)).collect();
+
+        let mut rows = Rows::new();
+        let mut vec = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            rows.insert(i, row(line));
+            vec.push(row(line));
+        }
+        // Typing lines in the middle of the file, then deleting them.
+        let start = Instant::now();
+        for i in 0..EDITS {
+            rows.insert(LINES / 2 + i, row("new"));
+        }
+        for _ in 0..EDITS {
+            rows.remove(LINES / 2);
+        }
+        let gap_buffer = start.elapsed();
+        let start = Instant::now();
+        for i in 0..EDITS {
+            vec.insert(LINES / 2 + i, row("new"));
+        }
+        for _ in 0..EDITS {
+            vec.remove(LINES / 2);
+        }
+        let shifting = start.elapsed();
+        println!(
+            "{} edits in {} lines: gap buffer {:?}, Vec {:?}",
+            EDITS, LINES, gap_buffer, shifting
+        );
+        assert!(gap_buffer < shifting);
+
+        // And through the editor, with everything else that happens on Enter.
+        let mut editor = editor_with("x.txt", &text, Config::default());
+        editor.cursor_position = Position { x: 2, y: LINES / 2 };
+        let start = Instant::now();
+        for _ in 0..EDITS {
+            editor.insert_newline();
+        }
+        for _ in 0..EDITS {
+            editor.delete_char();
+        }
+        println!(
+            "{} Enters and Backspaces in the editor: {:?}",
+            EDITS,
+            start.elapsed()
+        );
+        assert_eq!(editor.rows.len(), LINES);
     }
 
-    fn screen_index_to_char_index(screen_index: usize, row: Option<&Row>) -> usize {
-        if row.is_none() || screen_index == 0 {
-            return 0;
+    // Not run by default, since it's about timing. Run it with
+    // cargo test --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_full_screen_redraws() {
+        const FRAMES: u32 = 2_000;
+        let text: String = (0..1_000)
+            .map(|i| {
+                format!(
+                    "fn line_{}() {{ let x = \"{}\"; }} // {}\n",
+                    i,
+                    i,
+                    "x".repeat(40)
+                )
+            })
+            .collect();
+        let mut editor = editor_with("x.rs", &text, Config::default());
+        let mut null = File::create("/dev/null").unwrap();
+
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            editor.draw_frame();
+            null.write_all(editor.output.as_bytes()).unwrap();
+        }
+        let reused = start.elapsed();
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            // As if every frame started with a new buffer.
+            editor.output = String::new();
+            editor.draw_frame();
+            null.write_all(editor.output.as_bytes()).unwrap();
         }
+        let fresh = start.elapsed();
+        let fps = |elapsed: Duration| f64::from(FRAMES) / elapsed.as_secs_f64();
+        println!(
+            "{}x{} redraws: {:.0} fps reusing the buffer, {:.0} fps with a new one",
+            editor.screen_dimensions.cols,
+            editor.screen_dimensions.rows,
+            fps(reused),
+            fps(fresh)
+        );
+    }
 
-        let mut char_index = 0;
-        let mut i = 0;
+    fn numbered_lines(count: usize) -> String {
+        (0..count).map(|i| format!("{}\n", i)).collect()
+    }
 
-        for c in row.unwrap().chars.chars() {
-            if c == '\t' {
-                i += (TAB_STOP - 1) - (i % TAB_STOP) + 1;
-            } else if c.is_control() {
-                i += 1;
-            } else {
-                i += UnicodeWidthChar::width(c).unwrap_or(0);
-            }
+    fn page(editor: &mut Editor, arrow: Arrow) {
+        let lines = editor.text_rows() - PAGE_OVERLAP;
+        editor.scroll_lines(arrow, lines);
+    }
 
-            char_index += 1;
-            if i >= screen_index {
-                return char_index;
-            }
-        }
-        char_index
+    #[test]
+    fn paging_keeps_some_lines_of_context() {
+        let mut editor = editor_with("x.txt", &numbered_lines(100), Config::default());
+        page(&mut editor, Arrow::Down);
+        let lines = editor.text_rows() - PAGE_OVERLAP;
+        assert_eq!(editor.text_offset.y, lines);
+        assert_eq!(editor.cursor_position.y, lines);
+        page(&mut editor, Arrow::Up);
+        assert_eq!(editor.text_offset.y, 0);
+        assert_eq!(editor.cursor_position.y, 0);
     }
 
-    fn get_render_index(&self, x: usize, y: usize) -> usize {
-        if y >= self.rows.len() || x == 0 {
-            return 0;
-        }
+    #[test]
+    fn paging_up_at_the_top_stops_there() {
+        let mut editor = editor_with("x.txt", &numbered_lines(100), Config::default());
+        editor.cursor_position.y = 5;
+        page(&mut editor, Arrow::Up);
+        assert_eq!(editor.text_offset.y, 0);
+        assert_eq!(editor.cursor_position.y, 0);
+    }
 
-        let mut render_index = 0;
+    #[test]
+    fn paging_down_at_the_end_stops_there() {
+        let mut editor = editor_with("x.txt", &numbered_lines(30), Config::default());
+        editor.text_offset.y = 15;
+        editor.cursor_position.y = 25;
+        page(&mut editor, Arrow::Down);
+        assert_eq!(editor.text_offset.y, 29);
+        assert_eq!(editor.cursor_position.y, 30);
+    }
 
-        let row = &self.rows[y];
+    #[test]
+    fn half_pages_move_half_the_screen() {
+        let mut editor = editor_with("x.txt", &numbered_lines(100), Config::default());
+        editor.run_action(Action::HalfPageDown);
+        let half = editor.text_rows() / 2;
+        assert_eq!(editor.text_offset.y, half);
+        assert_eq!(editor.cursor_position.y, half);
+        editor.run_action(Action::HalfPageUp);
+        assert_eq!(editor.cursor_position.y, 0);
+    }
 
-        for c in row.chars.chars().take(x) {
-            if c == '\t' {
-                render_index += (TAB_STOP - 1) - (render_index % TAB_STOP) + 1;
-            } else {
-                render_index += 1;
-            }
-        }
-        render_index
+    #[test]
+    fn paging_keeps_the_goal_column() {
+        let mut editor = editor_with("x.txt", "long line\n\nlong line\n", Config::default());
+        editor.cursor_position.x = 6;
+        editor.scroll_lines(Arrow::Down, 1);
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 1 });
+        editor.scroll_lines(Arrow::Down, 1);
+        assert_eq!(editor.cursor_position, Position { x: 6, y: 2 });
     }
 
-    fn get_current_row(&self) -> Option<&Row> {
-        if self.cursor_position.y >= self.rows.len() {
-            None
-        } else {
-            Some(&self.rows[self.cursor_position.y])
-        }
+    fn saved(filename: &str, text: &str) -> String {
+        editor_with(filename, text, Config::default()).rows_to_string()
     }
 
-    // *** Editor Operations ***
+    #[test]
+    fn files_with_a_final_newline_keep_it() {
+        assert_eq!(saved("x.txt", "one\ntwo\n"), "one\ntwo\n");
+    }
 
-    fn insert_char(&mut self, c: char) {
-        if self.cursor_position.y == self.rows.len() {
-            self.insert_row(self.rows.len(), "");
-        }
+    #[test]
+    fn files_without_a_final_newline_dont_gain_one() {
+        assert_eq!(saved("x.txt", "one\ntwo"), "one\ntwo");
+    }
 
-        self.insert_char_in_row(self.cursor_position.y, self.cursor_position.x, c);
-        self.cursor_position.x += 1;
-        self.dirty = true;
+    #[test]
+    fn new_files_get_a_final_newline_when_configured() {
+        let mut editor = Editor::headless(Config {
+            insert_final_newline: true,
+            ..Config::default()
+        });
+        editor.insert_char('a');
+        assert_eq!(editor.rows_to_string(), "a\n");
     }
 
-    fn delete_char(&mut self) {
-        if self.cursor_position.x == 0 && self.cursor_position.y == 0 {
-            return;
-        }
-        if self.cursor_position.y == self.rows.len() {
-            return;
-        }
+    #[test]
+    fn empty_buffers_save_as_empty_files() {
+        assert_eq!(saved("x.txt", ""), "");
+        let config = Config {
+            insert_final_newline: true,
+            ..Config::default()
+        };
+        assert_eq!(Editor::headless(config).rows_to_string(), "");
+    }
 
-        if self.cursor_position.x > 0 {
-            self.delete_char_in_row(self.cursor_position.y, self.cursor_position.x - 1);
-            self.cursor_position.x -= 1;
-            self.dirty = true;
-        } else {
-            self.cursor_position.x = self.rows[self.cursor_position.y - 1].chars.chars().count();
-            let chars = mem::take(&mut self.rows[self.cursor_position.y].chars);
-            self.append_string_to_row(self.cursor_position.y - 1, &chars);
-            self.delete_row(self.cursor_position.y);
-            self.cursor_position.y -= 1;
-        }
+    #[test]
+    fn a_bom_is_hidden_and_saved_again() {
+        let editor = editor_with("x.txt", "\u{feff}one\ntwo\n", Config::default());
+        assert!(editor.had_bom);
+        assert_eq!(rows(&editor), ["one", "two"]);
+        assert_eq!(editor.rows_to_string(), "\u{feff}one\ntwo\n");
     }
 
-    fn insert_newline(&mut self) {
-        if self.cursor_position.x == 0 {
-            self.insert_row(self.cursor_position.y, "");
-        } else {
-            let row = &mut self.rows[self.cursor_position.y];
-            let split_at = row
-                .chars
-                .char_indices()
-                .nth(self.cursor_position.x)
-                .unwrap_or((row.chars.len(), 'a'))
-                .0;
-            let new_row_contents = row.chars.split_at(split_at).1.to_string();
+    #[test]
+    fn files_without_a_bom_dont_gain_one() {
+        let editor = editor_with("x.txt", "one\n", Config::default());
+        assert!(!editor.had_bom);
+        assert_eq!(editor.rows_to_string(), "one\n");
+    }
 
-            row.chars.truncate(split_at);
+    #[test]
+    fn only_a_leading_bom_is_taken_off() {
+        let editor = editor_with("x.txt", "one\u{feff}\n", Config::default());
+        assert!(!editor.had_bom);
+        assert_eq!(rows(&editor), ["one\u{feff}"]);
+    }
 
-            self.insert_row(self.cursor_position.y + 1, &new_row_contents);
-            self.update_row(self.cursor_position.y);
-        }
-        self.cursor_position.y += 1;
-        self.cursor_position.x = 0;
+    fn retabbed(text: &str, expand_tabs: bool, all: bool) -> Editor {
+        let config = Config {
+            tab_stop: 4,
+            expand_tabs,
+            ..Config::default()
+        };
+        let mut editor = editor_with("x.txt", text, config);
+        editor.retab(all).unwrap();
+        editor
     }
 
-    fn delete_row(&mut self, index: usize) {
-        if index >= self.rows.len() {
-            return;
-        }
+    #[test]
+    fn retab_expands_indentation_to_spaces() {
+        let editor = retabbed("\tone\n  \ttwo\nthree\n", true, false);
+        assert_eq!(rows(&editor), ["    one", "    two", "three"]);
+        assert!(editor.dirty);
+        assert_eq!(editor.status_message, "Retabbed 2 lines");
+    }
 
-        self.rows.remove(index);
-        self.dirty = true;
+    #[test]
+    fn retab_turns_indentation_into_tabs_keeping_the_width() {
+        let editor = retabbed("      one\n    two\n\t three\n", false, false);
+        assert_eq!(rows(&editor), ["\t  one", "\ttwo", "\t three"]);
+        assert_eq!(editor.status_message, "Retabbed 2 lines");
     }
 
-    // *** File I/O ***
+    #[test]
+    fn retab_only_touches_indentation_unless_asked() {
+        let editor = retabbed("\ta\tb\n", true, false);
+        assert_eq!(rows(&editor), ["    a\tb"]);
+        let editor = retabbed("\ta\tb\n", true, true);
+        assert_eq!(rows(&editor), ["    a   b"]);
+    }
 
-    fn open(&mut self, filename: &str) {
-        let f = match File::open(filename) {
-            Ok(f) => f,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    File::create(filename).expect("Unable to create file.");
-                    File::open(filename).expect("Unable to open file.")
-                } else {
-                    panic!("Unable to open file.")
-                }
-            }
-        };
-        let reader = BufReader::new(f);
-        let lines = reader.lines();
+    #[test]
+    fn retab_leaves_the_buffer_clean_when_nothing_changes() {
+        let editor = retabbed("    one\n", true, false);
+        assert!(!editor.dirty);
+        assert_eq!(editor.status_message, "Retabbed 0 lines");
+    }
 
-        for l in lines {
-            self.insert_row(self.rows.len(), &l.expect("Error reading file"));
+    fn typed(text: &str, x: usize, keys: &str) -> Editor {
+        let mut editor = editor_with("x.txt", text, Config::default());
+        editor.overwrite = true;
+        editor.cursor_position.x = x;
+        for c in keys.chars() {
+            editor.insert_char(c);
         }
+        editor
+    }
 
-        self.filename = Some(filename.to_string());
-        self.detect_filetype();
-        self.dirty = false;
+    #[test]
+    fn overwrite_replaces_the_char_under_the_cursor() {
+        let editor = typed("abcd\n", 1, "xy");
+        assert_eq!(rows(&editor), ["axyd"]);
+        assert_eq!(editor.cursor_position.x, 3);
     }
 
-    fn rows_to_string(&self) -> String {
-        let mut file_contents = String::new();
+    #[test]
+    fn overwrite_at_the_end_of_a_line_appends() {
+        let editor = typed("ab\n", 1, "xyz");
+        assert_eq!(rows(&editor), ["axyz"]);
+    }
 
-        for row in &self.rows {
-            file_contents.push_str(&row.chars);
-            file_contents.push('\n');
-        }
-        file_contents
+    #[test]
+    fn overwrite_replaces_a_wide_char_with_one_char() {
+        let editor = typed("a好b\n", 1, "x");
+        assert_eq!(rows(&editor), ["axb"]);
+        assert_char_lens(&editor);
     }
 
-    fn save(&mut self) {
-        if self.filename.is_none() {
-            self.filename = self.prompt("Save as: {} (ESC to cancel)", |_, _, _| String::new());
-            if self.filename.is_none() {
-                self.set_status_message("Save aborted");
-                return;
-            }
-            self.detect_filetype();
-        }
+    #[test]
+    fn overwrite_still_inserts_newlines() {
+        let mut editor = typed("abcd\n", 2, "");
+        editor.insert_newline();
+        assert_eq!(rows(&editor), ["ab", "cd"]);
+    }
 
-        match File::create(self.filename.as_ref().unwrap()) {
-            Ok(mut file) => {
-                let file_contents = self.rows_to_string();
-                match file.write_all(file_contents.as_bytes()) {
-                    Ok(_) => {
-                        self.set_status_message(&format!("{} bytes written", file_contents.len()));
-                        self.dirty = false;
-                    }
-                    // An error here means the file contents are lost. Oh well.
-                    Err(error) => self.set_status_message(&format!("Save failed: {}", error)),
-                }
-            }
-            Err(error) => self.set_status_message(&format!("Save failed: {:?}", error)),
-        }
+    fn cased(text: &str, x: usize, case: Case) -> Editor {
+        let mut editor = editor_with("x.txt", text, Config::default());
+        editor.cursor_position.x = x;
+        editor.change_word_case(case);
+        editor
     }
 
-    // *** Find ***
+    #[test]
+    fn the_word_under_the_cursor_changes_case() {
+        assert_eq!(
+            rows(&cased("one two three\n", 5, Case::Upper)),
+            ["one TWO three"]
+        );
+        assert_eq!(rows(&cased("ONE TWO\n", 0, Case::Lower)), ["one TWO"]);
+        assert_eq!(rows(&cased("one tWO\n", 6, Case::Title)), ["one Two"]);
+    }
 
-    fn find_callback(&mut self, query: &str, key: Key) -> String {
-        if !self.saved_highlight.is_empty() {
-            std::mem::swap(
-                &mut self.saved_highlight,
-                &mut self.rows[self.saved_highlight_index].highlight,
-            );
-            self.saved_highlight.clear();
-            self.saved_highlight_index = 0;
-        }
+    #[test]
+    fn case_changes_on_a_separator_apply_to_the_next_word() {
+        let editor = cased("one  two\n", 3, Case::Upper);
+        assert_eq!(rows(&editor), ["one  TWO"]);
+        assert_eq!(editor.cursor_position.x, 8);
+    }
 
-        if query.is_empty() {
-            return String::new();
-        }
+    #[test]
+    fn case_changes_can_change_the_length() {
+        let editor = cased("straße x\n", 0, Case::Upper);
+        assert_eq!(rows(&editor), ["STRASSE x"]);
+        assert_eq!(editor.cursor_position.x, 7);
+        assert!(editor.dirty);
+        assert_char_lens(&editor);
+    }
 
-        let regex = match Regex::new(query) {
-            Ok(re) => re,
-            _ => return ": Invalid regex".to_string(),
-        };
+    #[test]
+    fn case_changes_with_no_word_do_nothing() {
+        let editor = cased("one  \n", 4, Case::Upper);
+        assert_eq!(rows(&editor), ["one  "]);
+        assert!(!editor.dirty);
+    }
 
-        match key {
-            Key::Esc | Key::Enter => {
-                self.matches.clear();
-                self.match_index = 0;
-                return String::new();
-            }
-            Key::Arrow(Arrow::Left) | Key::Arrow(Arrow::Up) => {
-                self.match_index = if self.match_index == 0 {
-                    self.matches.len() - 1
-                } else {
-                    self.match_index - 1
-                };
-            }
-            Key::Arrow(Arrow::Right) | Key::Arrow(Arrow::Down) => {
-                self.match_index = if self.match_index == self.matches.len() - 1 {
-                    0
-                } else {
-                    self.match_index + 1
-                };
-            }
-            _ => {
-                self.matches.clear();
-                self.match_index = 0;
-                for (i, row) in self.rows.iter().enumerate() {
-                    if regex.is_match(&row.chars) {
-                        self.matches.push(i);
-                    }
-                }
-            }
-        }
+    // A path for a test's own file in a directory for this test run. The
+    // recent files and marks go there too rather than in the real state
+    // directory.
+    fn temp_path(name: &str) -> String {
+        static STATE_HOME: std::sync::Once = std::sync::Once::new();
+        let directory = env::temp_dir().join(format!("kilo-test-{}", std::process::id()));
+        STATE_HOME.call_once(|| env::set_var("XDG_STATE_HOME", directory.join("state")));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory.join(name).to_string_lossy().into_owned()
+    }
 
-        if self.matches.is_empty() {
-            return ": No results".to_string();
-        }
+    fn opened(filename: &str) -> Editor {
+        let mut editor = Editor::headless(Config::default());
+        editor.open(filename).unwrap();
+        editor
+    }
 
-        let row = &self.rows[self.matches[self.match_index]];
-        // TODO: Only finds the first match in each line.
-        let row_index = regex.find(&row.chars).unwrap();
-        self.cursor_position.y = self.matches[self.match_index];
-        self.text_offset.y = self.matches[self.match_index];
-        // Translate the byte offsets into char offsets.
-        let mut start = 0;
-        let mut end = 0;
-        for (i, (byte_offset, _)) in row.chars.char_indices().enumerate() {
-            if byte_offset == row_index.start() {
-                start = i;
-            }
-            if byte_offset == row_index.end() - 1 {
-                end = i;
-                break;
-            }
+    #[test]
+    fn writable_files_open_for_editing() {
+        let filename = temp_path("writable.txt");
+        std::fs::write(&filename, "text\n").unwrap();
+        let editor = opened(&filename);
+        assert!(!editor.read_only);
+        assert!(!editor.no_write_permission);
+    }
+
+    #[test]
+    fn unwritable_files_open_read_only() {
+        // root can write to anything.
+        if unistd::geteuid().is_root() {
+            return;
         }
+        let filename = temp_path("unwritable.txt");
+        std::fs::write(&filename, "text\n").unwrap();
+        std::fs::set_permissions(
+            &filename,
+            std::os::unix::fs::PermissionsExt::from_mode(0o444),
+        )
+        .unwrap();
+        let editor = opened(&filename);
+        assert!(editor.read_only);
+        assert!(editor.no_write_permission);
+        let mut status = String::new();
+        editor.draw_status_bar(&mut status);
+        assert!(status.contains("[RO: no write permission]"));
+    }
 
-        self.cursor_position.x = start;
+    #[test]
+    fn unwritable_files_can_be_made_writable() {
+        let mut editor = editor_with("x.txt", "text\n", Config::default());
+        editor.read_only = true;
+        editor.no_write_permission = true;
+        editor.make_writable().unwrap();
+        assert!(!editor.read_only);
+        editor.insert_char('a');
+        assert_eq!(rows(&editor), ["atext"]);
+    }
 
-        // Highlight the match.
-        self.saved_highlight_index = self.matches[self.match_index];
-        self.saved_highlight = row.highlight.clone();
-        let render_start = self.get_render_index(start, self.cursor_position.y);
-        let render_end = self.get_render_index(end, self.cursor_position.y);
+    #[test]
+    fn other_read_only_buffers_cant_be_made_writable() {
+        let mut editor = editor_with("x.txt", "text\n", Config::default());
+        editor.read_only = true;
+        assert!(editor.make_writable().is_err());
+        assert!(editor.read_only);
+    }
 
-        let row = &mut self.rows[self.matches[self.match_index]];
+    fn newline_at_the_bottom(margin: usize) -> Editor {
+        let config = Config {
+            newline_scroll_margin: margin,
+            ..Config::default()
+        };
+        let mut editor = editor_with("x.txt", &numbered_lines(21), config);
+        let bottom = editor.text_rows() - 2;
+        editor.cursor_position = Position { x: 2, y: bottom };
+        editor.insert_newline();
+        editor.scroll();
+        editor
+    }
 
-        for i in render_start..render_end + 1 {
-            row.highlight[i] = Highlight::Match;
-        }
+    #[test]
+    fn newlines_at_the_bottom_scroll_ahead_by_the_margin() {
+        let editor = newline_at_the_bottom(3);
+        let rows = editor.text_rows();
+        assert_eq!(editor.cursor_position.y, rows - 1);
+        assert_eq!(editor.text_offset.y, 3);
+    }
 
-        format!(
-            ": {} out of {} results",
-            self.match_index + 1,
-            self.matches.len()
-        )
+    #[test]
+    fn newlines_dont_scroll_ahead_without_a_margin() {
+        let editor = newline_at_the_bottom(0);
+        assert_eq!(editor.text_offset.y, 0);
     }
 
-    fn find(&mut self) {
-        let saved_cursor_position = self.cursor_position;
-        let saved_text_offset = self.text_offset;
+    #[test]
+    fn the_newline_margin_is_at_most_half_the_screen() {
+        let editor = newline_at_the_bottom(1000);
+        let rows = editor.text_rows();
+        assert_eq!(editor.text_offset.y, rows / 2);
+    }
 
-        if self
-            .prompt("Search: {} (Use ESC/Arrows/Enter)", Editor::find_callback)
-            .is_none()
-        {
-            self.cursor_position = saved_cursor_position;
-            self.text_offset = saved_text_offset;
+    #[test]
+    fn newlines_in_the_middle_of_the_screen_dont_scroll() {
+        let config = Config {
+            newline_scroll_margin: 3,
+            ..Config::default()
+        };
+        let mut editor = editor_with("x.txt", &numbered_lines(21), config);
+        editor.cursor_position = Position { x: 1, y: 5 };
+        editor.insert_newline();
+        editor.scroll();
+        assert_eq!(editor.text_offset.y, 0);
+    }
+
+    #[test]
+    fn switching_files_and_back_keeps_the_view() {
+        let a = temp_path("switch-a.txt");
+        let b = temp_path("switch-b.txt");
+        std::fs::write(&a, numbered_lines(1000)).unwrap();
+        std::fs::write(&b, "other\n").unwrap();
+        let mut editor = opened(&a);
+        editor.cursor_position = Position { x: 2, y: 500 };
+        editor.text_offset = Position { x: 0, y: 480 };
+        editor.insert_char('x');
+        editor.save_without_prompting().unwrap();
+        let (cursor, offset) = (editor.cursor_position, editor.text_offset);
+
+        editor.switch_to_file(&b).unwrap();
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 0 });
+        assert_eq!(editor.text_offset, Position { x: 0, y: 0 });
+        editor.switch_to_file(&a).unwrap();
+        assert_eq!(editor.cursor_position, cursor);
+        assert_eq!(editor.text_offset, offset);
+        assert!(editor.rows[500].chars.contains('x'));
+    }
+
+    // After an edit, every row should be highlighted as it would be if the
+    // file had been opened like that.
+    fn assert_highlighted_as_if_opened(editor: &mut Editor) {
+        editor.update_stale_highlights(usize::MAX);
+        let filename = editor.filename.clone().unwrap();
+        let opened = editor_with(&filename, &editor.rows_to_string(), editor.config.clone());
+        for y in 0..editor.rows.len() {
+            assert_eq!(highlights(editor, y), highlights(&opened, y), "row {}", y);
         }
     }
 
-    // *** Output ***
+    fn both_ways(test: impl Fn(Config)) {
+        for defer_highlight in [true, false] {
+            test(Config {
+                defer_highlight,
+                ..Config::default()
+            });
+        }
+    }
 
-    fn clear_screen(contents: &mut String) {
-        // Clear the whole screen.
-        contents.push_str("\x1b[2J");
+    #[test]
+    fn deleting_the_row_that_opens_a_comment_uncomments_the_rest() {
+        both_ways(|config| {
+            let mut editor = editor_with("x.c", "int a;\n/*\nint b;\n*/\nint c;\n", config);
+            assert_eq!(highlights(&editor, 2)[0], Highlight::MultilineComment);
+            editor.delete_row(1);
+            assert_highlighted_as_if_opened(&mut editor);
+            assert_eq!(highlights(&editor, 1)[0], Highlight::Keyword2);
+        });
     }
 
-    fn clear_row(contents: &mut String) {
-        // Clear the current row from the cursor to the end.
-        contents.push_str("\x1b[K");
+    #[test]
+    fn inserting_a_row_that_opens_a_comment_comments_the_rest() {
+        both_ways(|config| {
+            let mut editor = editor_with("x.c", "int a;\nint b;\n*/\nint c;\n", config);
+            editor.insert_row(1, "/*");
+            assert_highlighted_as_if_opened(&mut editor);
+            assert_eq!(highlights(&editor, 2)[0], Highlight::MultilineComment);
+        });
     }
 
-    fn draw_cursor(contents: &mut String, cursor_position: &Position) {
-        // Move the displayed cursor to a certain position.
-        let s = format!("\x1b[{};{}H", cursor_position.y + 1, cursor_position.x + 1);
-        contents.push_str(&s);
+    #[test]
+    fn joining_onto_a_comment_opener_rehighlights_below() {
+        both_ways(|config| {
+            let mut editor = editor_with("x.c", "int a; /*\nb\nint c;\n*/\nint d;\n", config);
+            // Join "b" onto the line before, then take the opener apart.
+            editor.cursor_position = Position { x: 0, y: 1 };
+            editor.delete_char();
+            assert_highlighted_as_if_opened(&mut editor);
+            editor.cursor_position = Position { x: 8, y: 0 };
+            editor.delete_char();
+            assert_highlighted_as_if_opened(&mut editor);
+            assert_eq!(highlights(&editor, 1)[0], Highlight::Keyword2);
+        });
     }
 
-    fn reset_cursor(contents: &mut String) {
-        // Move the cursor to the top left.
-        Editor::draw_cursor(contents, &Position { x: 0, y: 0 });
+    fn split_at(filename: &str, text: &str, at: Position, config: Config) -> Editor {
+        let mut editor = editor_with(filename, text, config);
+        editor.cursor_position = at;
+        editor.insert_newline();
+        assert_highlighted_as_if_opened(&mut editor);
+        editor
     }
 
-    fn hide_cursor(contents: &mut String) {
-        // Make the cursor invisible.
-        contents.push_str("\x1b[?25l");
+    #[test]
+    fn splitting_a_line_inside_a_comment() {
+        both_ways(|config| {
+            let text = "int a; /* one two */ int b;\nint c;\n";
+            let editor = split_at("x.c", text, Position { x: 14, y: 0 }, config);
+            assert_eq!(rows(&editor)[1], "two */ int b;");
+            assert_eq!(highlights(&editor, 1)[0], Highlight::MultilineComment);
+            assert_eq!(highlights(&editor, 2)[0], Highlight::Keyword2);
+        });
     }
 
-    fn show_cursor(contents: &mut String) {
-        // Make the cursor visible.
-        contents.push_str("\x1b[?25h");
+    #[test]
+    fn splitting_a_line_before_a_comment_opener() {
+        both_ways(|config| {
+            let text = "int a; /*\nint b;\n*/\n";
+            let editor = split_at("x.c", text, Position { x: 7, y: 0 }, config);
+            assert_eq!(highlights(&editor, 0)[0], Highlight::Keyword2);
+            assert_eq!(highlights(&editor, 1)[0], Highlight::MultilineComment);
+            assert_eq!(highlights(&editor, 2)[0], Highlight::MultilineComment);
+        });
     }
 
-    fn set_color(contents: &mut String, color: Color) {
-        let color_code = match color {
-            Color::Black => "0;30",
-            Color::Red => "0;31",
-            Color::Green => "0;32",
-            Color::Yellow => "0;33",
-            Color::Blue => "0;34",
-            Color::Magenta => "0;35",
-            Color::Cyan => "0;36",
-            Color::White => "0;37",
-            Color::BrightBlack => "1;30",
-            Color::BrightRed => "1;31",
-            Color::BrightGreen => "1;32",
-            Color::BrightYellow => "1;33",
-            Color::BrightBlue => "1;34",
-            Color::BrightMagenta => "1;35",
-            Color::BrightCyan => "1;36",
-            Color::BrightWhite => "1;37",
-            Color::Default => "0;39",
-        };
-        contents.push_str(&format!("\x1b[{}m", color_code));
+    #[test]
+    fn splitting_a_line_inside_a_string() {
+        both_ways(|config| {
+            let text = "char *s = \"one two\";\nint c;\n";
+            let editor = split_at("x.c", text, Position { x: 15, y: 0 }, config);
+            // Strings end with the line, so the lower half's quote starts a
+            // new one there.
+            assert_eq!(rows(&editor)[1], "two\";");
+            assert_eq!(highlights(&editor, 0)[14], Highlight::String);
+            assert_eq!(highlights(&editor, 1)[0], Highlight::Normal);
+            assert_eq!(highlights(&editor, 1)[3], Highlight::String);
+            assert_eq!(highlights(&editor, 2)[0], Highlight::Keyword2);
+        });
     }
 
-    fn invert_colors(contents: &mut String) {
-        contents.push_str("\x1b[7m");
+    #[test]
+    fn a_given_config_file_is_loaded() {
+        let path = temp_path("given.kilorc");
+        std::fs::write(&path, "# Narrow tabs\ntab_stop=3\n\nexpand_tabs=true\n").unwrap();
+        let config = load_config(Some(path)).unwrap();
+        assert_eq!(config.tab_stop, 3);
+        assert!(config.expand_tabs);
     }
 
-    fn clear_formatting(contents: &mut String) {
-        contents.push_str("\x1b[m");
+    #[test]
+    fn a_given_config_file_has_to_exist() {
+        let path = temp_path("missing.kilorc");
+        let error = load_config(Some(path.clone())).err().unwrap();
+        assert!(error.starts_with(&format!("can't read config file {}", path)));
     }
 
-    fn draw_rows(&self, contents: &mut String) {
-        let line_number_padding = format!("{}", self.rows.len()).len();
-        for y in 0..self.screen_dimensions.rows {
-            let mut filled_line = false;
-            let file_row = y + self.text_offset.y;
-            if file_row >= self.rows.len() {
-                if self.rows.is_empty() && y == self.screen_dimensions.rows / 3 {
-                    let welcome_message = format!("Kilo editor -- version {}", VERSION);
-                    let message_length =
-                        cmp::min(welcome_message.len(), self.screen_dimensions.cols - 1);
+    #[test]
+    fn config_errors_say_which_line() {
+        let path = temp_path("bad.kilorc");
+        std::fs::write(&path, "tab_stop=3\nno_such_option=1\n").unwrap();
+        let error = load_config(Some(path.clone())).err().unwrap();
+        assert!(error.starts_with(&format!("{}: line 2: ", path)));
+    }
 
-                    let mut padding = (self.screen_dimensions.cols - message_length) / 2;
-                    if padding > 0 {
-                        Editor::set_color(contents, Color::Blue);
-                        contents.push('~');
-                        Editor::set_color(contents, Color::Default);
-                        padding -= 1;
-                    }
+    // A directory for a test's files with a .kilo/syntax.toml in it.
+    fn project_with_syntax(name: &str, syntax: &str) -> String {
+        let directory = temp_path(name);
+        std::fs::create_dir_all(format!("{}/.kilo", directory)).unwrap();
+        std::fs::write(format!("{}/.kilo/syntax.toml", directory), syntax).unwrap();
+        directory
+    }
 
-                    for _ in 0..padding {
-                        contents.push(' ');
-                    }
+    #[test]
+    fn checking_a_good_config_passes() {
+        let config = temp_path("good-check.kilorc");
+        std::fs::write(&config, "tab_stop=4\n").unwrap();
+        let directory = project_with_syntax(
+            "good-check",
+            "[[filetype]]\nname = \"nix\"\nfilename_patterns = [\".nix\"]\n",
+        );
+        let file = format!("{}/x.nix", directory);
+        assert!(check_config(Some(config), Some(&file)).is_ok());
+    }
 
-                    contents.push_str(&welcome_message[..message_length]);
-                } else {
-                    Editor::set_color(contents, Color::Blue);
-                    contents.push('~');
-                    Editor::set_color(contents, Color::Default);
-                }
-            } else {
-                Editor::set_color(contents, Color::BrightBlack);
-                contents.push_str(&format!(
-                    "{:>width$} ",
-                    file_row + 1,
-                    width = line_number_padding
-                ));
-                Editor::set_color(contents, Color::Default);
+    #[test]
+    fn checking_a_bad_config_fails_with_exit_code_1() {
+        let config = temp_path("bad-check.kilorc");
+        std::fs::write(&config, "tab_stop=0\n").unwrap();
+        let error = check_config(Some(config.clone()), None).err().unwrap();
+        assert_eq!(error.exit_code(), 1);
+        assert!(error
+            .message()
+            .starts_with(&format!("{}: line 1: ", config)));
+    }
 
-                let line_length = self.rows[file_row]
-                    .render
-                    .chars()
-                    .map(|c| {
-                        if c.is_control() {
-                            1
-                        } else {
-                            UnicodeWidthChar::width(c).unwrap_or(0)
-                        }
-                    })
-                    .sum();
-                // Check if any of this line is visible.
-                if self.text_offset.x < line_length {
-                    let mut displayed_length = line_length - self.text_offset.x;
-                    // Cap the displayed length to the length of the screen.
-                    if displayed_length >= self.screen_dimensions.cols - (line_number_padding + 1) {
-                        displayed_length = self.screen_dimensions.cols - (line_number_padding + 1);
-                        filled_line = true;
-                    }
-                    // Start displaying the line at the text offset.
-                    let row = &self.rows[file_row];
-                    let start_index = self.text_offset.x;
-                    let mut current_color = Color::Default;
-                    let mut screen_index = 0;
-                    let mut prev_width = 0;
-
-                    let mut zip = row.zip().into_iter().peekable();
-
-                    loop {
-                        let next = zip.next();
-                        if let Some((char, char_index, render, highlight)) = next {
-                            let curr_width = if char.is_control() {
-                                1
-                            } else {
-                                UnicodeWidthChar::width(char).unwrap_or(0)
-                            };
-                            if screen_index >= start_index
-                                && screen_index < start_index + displayed_length
-                            {
-                                if prev_width > 1
-                                    && screen_index > start_index
-                                    && screen_index - prev_width < start_index
-                                {
-                                    // There's a cut off wide character at the start
-                                    // of the row.
-                                    Editor::set_color(contents, Color::Blue);
-                                    contents.push('<');
-                                    Editor::set_color(contents, current_color);
-                                } else if curr_width > 1
-                                    && screen_index + curr_width > start_index + displayed_length
-                                {
-                                    // There's a cut off wide character at the end
-                                    // of the row.
-                                    Editor::set_color(contents, Color::Blue);
-                                    contents.push('>');
-                                    Editor::set_color(contents, current_color);
-                                    prev_width = curr_width;
-                                    screen_index += curr_width;
-                                    continue;
-                                }
+    #[test]
+    fn checking_a_bad_syntax_file_says_where() {
+        let config = temp_path("syntax-check.kilorc");
+        std::fs::write(&config, "").unwrap();
+        let directory = project_with_syntax("bad-syntax-check", "[[filetype]]\nname = nix\n");
+        let file = format!("{}/x.nix", directory);
+        let error = check_config(Some(config), Some(&file)).err().unwrap();
+        assert_eq!(error.exit_code(), 1);
+        let syntax_file = format!("{}/.kilo/syntax.toml: line 2", directory);
+        assert!(
+            error.message().starts_with(&syntax_file),
+            "{}",
+            error.message()
+        );
+    }
 
-                                if RENDER_WHITESPACE && char == ' ' {
-                                    Editor::set_color(contents, Color::BrightBlack);
-                                    contents.push('∙');
-                                    Editor::set_color(contents, current_color);
-                                } else if RENDER_WHITESPACE && char == '\t' {
-                                    Editor::set_color(contents, Color::BrightBlack);
-                                    contents.push('⇀');
-                                    while zip.peek().is_some()
-                                        && zip.peek().unwrap().1 == char_index
-                                    {
-                                        zip.next();
-                                        contents.push(' ');
-                                    }
-                                    Editor::set_color(contents, current_color);
-                                } else if render.is_control() {
-                                    Editor::invert_colors(contents);
-                                    contents.push(if char as u8 <= 26 {
-                                        (char as u8 | !0b10111111) as char
-                                    } else {
-                                        '?'
-                                    });
-                                    Editor::clear_formatting(contents);
-                                    Editor::set_color(contents, current_color);
-                                } else if let Highlight::Normal = highlight {
-                                    if current_color != Color::Default {
-                                        Editor::set_color(contents, Color::Default);
-                                        current_color = Color::Default;
-                                    }
-                                    contents.push(render);
-                                } else {
-                                    let color = Editor::highlight_to_color(highlight);
-                                    if current_color != color {
-                                        Editor::set_color(contents, color);
-                                        current_color = color;
-                                    }
-                                    contents.push(render);
-                                }
-                            }
-                            prev_width = curr_width;
-                            screen_index += curr_width;
-                        } else {
-                            break;
-                        }
-                    }
-                    Editor::set_color(contents, Color::Default);
+    fn wrapping_editor(text: &str) -> Editor {
+        let config = Config {
+            soft_wrap: true,
+            visual_line_movement: true,
+            ..Config::default()
+        };
+        editor_with("x.txt", text, config)
+    }
+
+    // How many columns of text fit on a line with a one digit gutter.
+    fn wrap_width() -> usize {
+        wrapping_editor("").text_cols()
+    }
+
+    #[test]
+    fn up_and_down_move_through_a_wrapped_line() {
+        let cols = wrap_width();
+        // A line that wraps three times, then a short one.
+        let text = format!("{}\nshort\n", "x".repeat(cols * 3 + 10));
+        let mut editor = wrapping_editor(&text);
+        editor.cursor_position.x = 5;
+        for line in 1..4 {
+            editor.move_cursor(Arrow::Down);
+            assert_eq!(
+                editor.cursor_position,
+                Position {
+                    x: cols * line + 5,
+                    y: 0
                 }
+            );
+        }
+        editor.move_cursor(Arrow::Down);
+        assert_eq!(editor.cursor_position, Position { x: 5, y: 1 });
+        editor.move_cursor(Arrow::Up);
+        assert_eq!(
+            editor.cursor_position,
+            Position {
+                x: cols * 3 + 5,
+                y: 0
             }
-            if !filled_line {
-                Editor::clear_row(contents);
-            }
-
-            contents.push_str("\r\n");
+        );
+        for line in (0..3).rev() {
+            editor.move_cursor(Arrow::Up);
+            assert_eq!(
+                editor.cursor_position,
+                Position {
+                    x: cols * line + 5,
+                    y: 0
+                }
+            );
         }
     }
 
-    fn draw_status_bar(&self, contents: &mut String) {
-        Editor::invert_colors(contents);
+    #[test]
+    fn the_goal_column_is_kept_past_a_short_last_line() {
+        let cols = wrap_width();
+        let text = format!("{}\n", "x".repeat(cols + 3));
+        let mut editor = wrapping_editor(&text);
+        editor.cursor_position.x = 20;
+        editor.move_cursor(Arrow::Down);
+        assert_eq!(editor.cursor_position, Position { x: cols + 3, y: 0 });
+        editor.move_cursor(Arrow::Up);
+        assert_eq!(editor.cursor_position, Position { x: 20, y: 0 });
+    }
 
-        let filename = match &self.filename {
-            Some(filename) => {
-                if filename.len() > MAX_STATUS_FILENAME_LENGTH {
-                    &filename[0..MAX_STATUS_FILENAME_LENGTH]
-                } else {
-                    filename
-                }
-            }
-            None => "[No name]",
-        };
+    #[test]
+    fn without_visual_lines_up_and_down_move_by_row() {
+        let cols = wrap_width();
+        let text = format!("{}\nshort\n", "x".repeat(cols * 3 + 10));
+        let mut editor = editor_with(
+            "x.txt",
+            &text,
+            Config {
+                soft_wrap: true,
+                visual_line_movement: false,
+                ..Config::default()
+            },
+        );
+        editor.cursor_position.x = 5;
+        editor.move_cursor(Arrow::Down);
+        assert_eq!(editor.cursor_position, Position { x: 5, y: 1 });
+    }
 
-        let left_status = format!(
-            "{} - {} lines {}",
-            filename,
-            self.rows.len(),
-            if self.dirty { "(modified)" } else { "" }
+    // The text of each line of the screen after drawing a frame, without
+    // any escape sequences.
+    fn screen(editor: &mut Editor) -> Vec<String> {
+        editor.draw_frame();
+        let escapes =
+            Regex::new("\x1b\\[[0-9;?]*[a-zA-Z]|\x1b\\][^\x07\x1b]*(\x07|\x1b\\\\)").unwrap();
+        escapes
+            .replace_all(&editor.output, "")
+            .split("\r\n")
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn wrapped_lines_are_marked_in_the_gutter() {
+        let cols = wrap_width();
+        let text = format!(
+            "a{}b{}c\nshort\n",
+            "x".repeat(cols - 1),
+            "y".repeat(cols - 1)
         );
+        let mut editor = wrapping_editor(&text);
+        let screen = screen(&mut editor);
+        let gutter = |line: &str| {
+            line.chars()
+                .take(editor.line_number_space())
+                .collect::<String>()
+        };
+        assert!(screen[0].ends_with(&format!("a{}", "x".repeat(cols - 1))));
+        assert_eq!(gutter(&screen[0]).trim(), "1");
+        assert_eq!(gutter(&screen[1]).trim(), WRAP_MARKER);
+        assert_eq!(gutter(&screen[2]).trim(), WRAP_MARKER);
+        assert!(screen[2].ends_with('c'));
+        assert_eq!(gutter(&screen[3]).trim(), "2");
+        // The marker takes up the same room as a line number.
+        let width = |line: &str| line.chars().count();
+        assert_eq!(width(&screen[0]), width(&screen[1]));
+    }
 
-        let right_status = format!(
-            "{} | {}:{} ",
-            if self.filetype.is_none() {
-                "no ft"
-            } else {
-                self.filetype.unwrap().name
-            },
-            self.cursor_position.y + 1,
-            self.cursor_position.x + 1
+    #[test]
+    fn new_files_arent_created_until_saved() {
+        let filename = temp_path("new.txt");
+        let mut editor = opened(&filename);
+        assert!(!Path::new(&filename).exists());
+        assert_eq!(editor.filename.as_deref(), Some(filename.as_str()));
+        assert!(editor.rows.is_empty());
+        assert_eq!(editor.status_message, "[New File]");
+        assert!(!editor.read_only);
+
+        editor.insert_char('a');
+        editor.save_without_prompting().unwrap();
+        assert_eq!(std::fs::read_to_string(&filename).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn new_files_can_be_created_when_opened() {
+        let filename = temp_path("created.txt");
+        let mut editor = Editor::headless(Config {
+            create_missing_files: true,
+            ..Config::default()
+        });
+        editor.open(&filename).unwrap();
+        assert_eq!(std::fs::read_to_string(&filename).unwrap(), "");
+        assert!(!editor.dirty);
+    }
+
+    #[test]
+    fn new_files_in_missing_directories_open_until_saved() {
+        let filename = temp_path("no-such-directory/new.txt");
+        let mut editor = opened(&filename);
+        editor.insert_char('a');
+        let error = editor.save_without_prompting().err().unwrap();
+        assert!(error.starts_with("Save failed: "));
+    }
+
+    fn replacements(text: &str, regex: &str, replacement: &str) -> (Editor, Vec<(usize, String)>) {
+        let editor = editor_with("x.txt", text, Config::default());
+        let (changes, _) = editor.replacements(&Regex::new(regex).unwrap(), replacement);
+        (editor, changes)
+    }
+
+    #[test]
+    fn replacements_list_only_the_rows_that_change() {
+        let text = "a cat\nno match\ncat cat\n";
+        let (editor, changes) = replacements(text, "cat", "dog");
+        assert_eq!(
+            changes,
+            [(0, "a dog".to_string()), (2, "dog dog".to_string())]
         );
+        let (_, count) = editor.replacements(&Regex::new("cat").unwrap(), "dog");
+        assert_eq!(count, 3);
+        // Nothing changes until the replacements are applied.
+        assert_eq!(rows(&editor), ["a cat", "no match", "cat cat"]);
+        assert!(!editor.dirty);
+    }
 
-        let mut status: String = format!(
-            "{:width$}",
-            left_status,
-            width = self.screen_dimensions.cols - right_status.len()
-        )
-        .to_string();
+    #[test]
+    fn replacements_can_use_groups() {
+        let (_, changes) = replacements("x = 1\n", r"(\w+) = (\d+)", "$2 = ${1}");
+        assert_eq!(changes, [(0, "1 = x".to_string())]);
+    }
 
-        status.push_str(&right_status);
+    #[test]
+    fn the_replacement_preview_shows_before_and_after() {
+        let text = format!("{}cat\n", "\n".repeat(9));
+        let (editor, changes) = replacements(&text, "cat", "dog");
+        assert_eq!(
+            editor.replacement_preview(&changes),
+            ["10 - cat", "   + dog"]
+        );
+    }
 
-        if status.len() > self.screen_dimensions.cols {
-            contents.push_str(&status[0..self.screen_dimensions.cols]);
-        } else {
-            contents.push_str(&status);
+    #[test]
+    fn applying_replacements_changes_the_rows() {
+        let (mut editor, changes) = replacements("a cat\ncat\n", "cat", "dog");
+        editor.cursor_position = Position { x: 4, y: 1 };
+        editor.apply_replacements(changes);
+        assert_eq!(rows(&editor), ["a dog", "dog"]);
+        assert!(editor.dirty);
+        assert_eq!(editor.cursor_position, Position { x: 3, y: 1 });
+        assert_highlighted_as_if_opened(&mut editor);
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let text = "café ♥ 𝄞\n";
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(encoding.decode(bytes).unwrap(), text.as_bytes());
         }
+        let bytes = Encoding::Latin1.encode("café\n").unwrap();
+        assert_eq!(bytes, b"caf\xe9\n");
+        assert_eq!(Encoding::Latin1.decode(bytes).unwrap(), "café\n".as_bytes());
+    }
 
-        Editor::clear_formatting(contents);
-        contents.push_str("\r\n");
+    #[test]
+    fn text_that_cant_be_encoded_is_an_error() {
+        let error = Encoding::Latin1.encode("♥").err().unwrap();
+        assert_eq!(error, "♥ can't be written in latin-1");
+        assert!(Encoding::Utf16Le.decode(vec![b'a']).is_err());
+        // An unpaired surrogate.
+        assert!(Encoding::Utf16Le.decode(vec![0x00, 0xd8]).is_err());
     }
 
-    fn draw_message_bar(&self, contents: &mut String) {
-        Editor::clear_row(contents);
-        let message = if self.status_message.len() > self.screen_dimensions.cols {
-            &self.status_message[0..self.screen_dimensions.cols]
-        } else {
-            &self.status_message
+    #[test]
+    fn encodings_are_found_by_any_of_their_names() {
+        assert!(Encoding::from_name("UTF8") == Some(Encoding::Utf8));
+        assert!(Encoding::from_name("iso-8859-1") == Some(Encoding::Latin1));
+        assert!(Encoding::from_name("utf-16be") == Some(Encoding::Utf16Be));
+        assert!(Encoding::from_name("ebcdic").is_none());
+    }
+
+    #[test]
+    fn files_are_reopened_and_saved_in_another_encoding() {
+        let filename = temp_path("latin1.txt");
+        std::fs::write(&filename, b"caf\xe9\n").unwrap();
+        let mut editor = Editor::headless(Config::default());
+        editor.encoding = Encoding::Latin1;
+        editor.open(&filename).unwrap();
+        assert_eq!(rows(&editor), ["café"]);
+        // The buffer is kept when it can't be read as UTF-8.
+        let error = editor.reopen_with_encoding("utf-8").err().unwrap();
+        assert_eq!(error, "It isn't valid utf-8");
+        assert_eq!(rows(&editor), ["café"]);
+        editor.reopen_with_encoding("latin-1").unwrap();
+        assert_eq!(rows(&editor), ["café"]);
+
+        editor.set_file_encoding("utf-16le").unwrap();
+        assert!(editor.dirty);
+        editor.save_without_prompting().unwrap();
+        let saved = std::fs::read(&filename).unwrap();
+        assert_eq!(saved, Encoding::Utf16Le.encode("café\n").unwrap());
+    }
+
+    fn search_matches(smart_case: bool, query: &str, text: &str) -> bool {
+        let config = Config {
+            smart_case,
+            ..Config::default()
         };
+        let editor = editor_with("x.txt", "", config);
+        editor.search_regex(query).unwrap().is_match(text)
+    }
 
-        if !message.is_empty() && self.status_message_time.elapsed().as_secs() < 5 {
-            contents.push_str(message);
-        }
+    #[test]
+    fn lowercase_searches_ignore_case() {
+        assert!(search_matches(true, "hello", "Hello"));
+        assert!(search_matches(true, "hello", "HELLO"));
     }
 
-    fn set_status_message(&mut self, message: &str) {
-        self.status_message = message.to_string();
-        self.status_message_time = Instant::now();
+    #[test]
+    fn searches_with_uppercase_match_case() {
+        assert!(search_matches(true, "Hello", "Hello"));
+        assert!(!search_matches(true, "Hello", "hello"));
     }
 
-    fn refresh_screen(&mut self) {
-        self.scroll();
+    #[test]
+    fn escapes_arent_uppercase_letters() {
+        assert!(search_matches(true, r"a\Sc", "ABC"));
+        assert!(search_matches(true, r"a\Wc", "A-C"));
+    }
 
-        let mut contents = String::new();
+    #[test]
+    fn an_explicit_case_flag_wins_over_smart_case() {
+        assert!(!search_matches(true, "(?-i)hello", "Hello"));
+        assert!(search_matches(true, "(?i)Hello", "hello"));
+    }
 
-        Editor::hide_cursor(&mut contents);
-        Editor::reset_cursor(&mut contents);
+    #[test]
+    fn searches_match_case_without_smart_case() {
+        assert!(!search_matches(false, "hello", "Hello"));
+    }
 
-        self.draw_rows(&mut contents);
-        self.draw_status_bar(&mut contents);
-        self.draw_message_bar(&mut contents);
+    fn mode(filename: &str) -> u32 {
+        std::fs::metadata(filename).unwrap().mode() & 0o7777
+    }
 
-        let line_number_space = format!("{}", self.rows.len()).len() + 1;
+    fn set_mode(filename: &str, mode: u32) {
+        let permissions = std::os::unix::fs::PermissionsExt::from_mode(mode);
+        std::fs::set_permissions(filename, permissions).unwrap();
+    }
 
-        let cursor_screen_position = Position {
-            x: self.get_current_screen_index() - self.text_offset.x + line_number_space,
-            y: self.cursor_position.y - self.text_offset.y,
-        };
-        Editor::draw_cursor(&mut contents, &cursor_screen_position);
+    #[test]
+    fn saving_keeps_the_files_mode() {
+        for (name, file_mode) in [("private.txt", 0o600), ("setuid.sh", 0o4755)] {
+            let filename = temp_path(name);
+            std::fs::write(&filename, "text\n").unwrap();
+            set_mode(&filename, file_mode);
+            let mut editor = opened(&filename);
+            editor.insert_char('a');
+            assert_eq!(editor.save_without_prompting(), Ok(()));
+            assert_eq!(std::fs::read_to_string(&filename).unwrap(), "atext\n");
+            assert_eq!(mode(&filename), file_mode, "{}", name);
+        }
+    }
 
-        Editor::show_cursor(&mut contents);
+    #[test]
+    fn a_changed_mode_is_put_back() {
+        let filename = temp_path("changed-mode.txt");
+        std::fs::write(&filename, "").unwrap();
+        set_mode(&filename, 0o640);
+        let (file, before) = Editor::create_file(&filename).unwrap();
+        set_mode(&filename, 0o666);
+        Editor::restore_metadata(&file, &before.unwrap()).unwrap();
+        assert_eq!(mode(&filename), 0o640);
+    }
 
-        print!("{}", contents);
-        io::stdout().flush().unwrap();
+    #[test]
+    fn new_files_have_nothing_to_keep() {
+        let filename = temp_path("no-metadata.txt");
+        let (_, before) = Editor::create_file(&filename).unwrap();
+        assert!(before.is_none());
     }
 
-    fn reset_screen(&self) {
-        let mut contents = String::new();
+    fn goto_definition_from(filename: &str, text: &str, from: Position) -> Editor {
+        let mut editor = editor_with(filename, text, Config::default());
+        editor.cursor_position = from;
+        editor.goto_definition();
+        editor
+    }
 
-        Editor::clear_screen(&mut contents);
-        Editor::reset_cursor(&mut contents);
+    #[test]
+    fn goto_definition_jumps_to_the_definition() {
+        let text = "fn main() {\n    helper();\n}\n\nfn helper() {}\n";
+        let editor = goto_definition_from("x.rs", text, Position { x: 6, y: 1 });
+        assert_eq!(editor.cursor_position, Position { x: 3, y: 4 });
+        // It can be jumped back from.
+        assert_eq!(editor.jump_list, [Position { x: 6, y: 1 }]);
+    }
 
-        print!("{}", contents);
-        io::stdout().flush().unwrap();
+    #[test]
+    fn goto_definition_cycles_through_definitions() {
+        let text = "x = 1\ndef x():\n    pass\nprint(x)\n";
+        let mut editor = goto_definition_from("x.py", text, Position { x: 6, y: 3 });
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 0 });
+        assert_eq!(editor.status_message, "Definition 1 of 2");
+        editor.goto_definition();
+        assert_eq!(editor.cursor_position, Position { x: 4, y: 1 });
+        assert_eq!(editor.status_message, "Definition 2 of 2");
+        editor.goto_definition();
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 0 });
     }
 
-    // *** Input ***
+    #[test]
+    fn goto_definition_only_finds_the_whole_name() {
+        let text = "fn helpers() {}\nhelper();\n";
+        let editor = goto_definition_from("x.rs", text, Position { x: 0, y: 1 });
+        assert_eq!(editor.cursor_position, Position { x: 0, y: 1 });
+        assert_eq!(editor.status_message, "No definition of helper found");
+        assert!(editor.jump_list.is_empty());
+    }
 
-    fn prompt<F>(&mut self, prompt: &str, callback: F) -> Option<String>
-    where
-        F: Fn(&mut Editor, &str, Key) -> String,
-    {
-        let mut input = String::new();
-        let mut message = String::new();
-        loop {
-            self.set_status_message(&format!("{} {}", prompt.replace("{}", &input), &message));
-            self.refresh_screen();
+    #[test]
+    fn goto_definition_needs_patterns_and_an_identifier() {
+        let editor = goto_definition_from("x.txt", "word\n", Position { x: 0, y: 0 });
+        assert_eq!(
+            editor.status_message,
+            "No definition patterns for this filetype"
+        );
+        let editor = goto_definition_from("x.rs", "a  b\n", Position { x: 2, y: 0 });
+        assert_eq!(editor.status_message, "Not on an identifier");
+    }
 
-            let key = self.read_key();
-            match key {
-                Key::Backspace | Key::Delete => {
-                    input.pop();
-                }
-                Key::Esc => {
-                    self.set_status_message("");
-                    callback(self, &input, key);
-                    return None;
-                }
-                Key::Enter => {
-                    if !input.is_empty() {
-                        self.set_status_message("");
-                        callback(self, &input, key);
-                        return Some(input);
-                    }
-                }
-                Key::Char(c) => {
-                    input.push(c);
-                }
-                _ => {}
-            }
-            message = callback(self, &input, key);
+    #[test]
+    fn without_colors_only_inverse_video_is_drawn() {
+        let mut editor = editor_with(
+            "x.rs",
+            "fn main() {\n    let x = \"one\";\n}\n",
+            Config::default(),
+        );
+        editor.find_callback("one", Key::Char('e'));
+        editor.text_offset = Position { x: 0, y: 0 };
+        editor.search_highlight = Some(Regex::new("main").unwrap());
+        editor.selection_anchor = Some(Position { x: 4, y: 1 });
+        editor.cursor_position = Position { x: 7, y: 1 };
+        NO_COLOR.store(true, Ordering::Relaxed);
+        editor.draw_frame();
+        NO_COLOR.store(false, Ordering::Relaxed);
+
+        let sgr = Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        for code in sgr.find_iter(&editor.output) {
+            assert!(
+                ["\x1b[7m", "\x1b[m"].contains(&code.as_str()),
+                "{:?}",
+                code.as_str()
+            );
         }
+        let lines: Vec<&str> = editor.output.split("\r\n").collect();
+        assert!(lines[0].contains("\x1b[7mmain\x1b[m"), "{:?}", lines[0]);
+        assert!(lines[1].contains("\x1b[7mlet\x1b[m"), "{:?}", lines[1]);
+        assert!(lines[1].contains("\x1b[7mone\x1b[m"), "{:?}", lines[1]);
     }
 
-    fn read_key(&self) -> Key {
-        match self.input.recv() {
-            Ok(c) => {
-                if c == '\x08' || c == '\x7f' {
-                    Key::Backspace
-                } else if c == '\r' {
-                    Key::Enter
-                } else if c == '\x1b' {
-                    self.read_escape_sequence()
-                } else if c.is_control() {
-                    Key::Ctrl((c as u8 | 0b01100000) as char)
-                } else {
-                    Key::Char(c)
-                }
-            }
-            Err(_) => panic!("Error reading from input channel"),
-        }
+    #[test]
+    fn zero_tab_stop_is_rejected() {
+        let mut config = Config::default();
+        let before = config.tab_stop;
+        assert!(set_config_option(&mut config, "tab_stop=0").is_err());
+        assert_eq!(config.tab_stop, before);
+        assert!(set_config_option(&mut config, "indent_size=0").is_err());
+        assert_eq!(config.indent_size, Config::default().indent_size);
     }
 
-    fn read_escape_sequence(&self) -> Key {
-        match self.input.recv_timeout(Duration::from_millis(100)) {
-            Ok('[') => match self.input.try_recv() {
-                Ok('A') => Key::Arrow(Arrow::Up),    // <esc>[A
-                Ok('B') => Key::Arrow(Arrow::Down),  // <esc>[B
-                Ok('C') => Key::Arrow(Arrow::Right), // <esc>[C
-                Ok('D') => Key::Arrow(Arrow::Left),  // <esc>[D
-                Ok('H') => Key::Home,                // <esc>[H
-                Ok('F') => Key::End,                 // <esc>[F
-                Ok(n @ '0'..='9') => {
-                    match self.input.recv_timeout(Duration::from_millis(100)) {
-                        Ok('~') => match n {
-                            // Match on the number before the tilde.
-                            '1' | '7' => Key::Home, // <esc>[1~ or <esc>[7~
-                            '4' | '8' => Key::End,  // <esc>[4~ or <esc>[8~
-                            '3' => Key::Delete,     // <esc>[3~
-                            '5' => Key::PageUp,     // <esc>[5~
-                            '6' => Key::PageDown,   // <esc>[6~
-                            _ => Key::Esc,
-                        },
-                        // Ignore all bytes after the esc.
-                        Ok(_) | Err(RecvTimeoutError::Timeout) => Key::Esc,
-                        Err(RecvTimeoutError::Disconnected) => {
-                            panic!("Input channel disconnected")
-                        }
-                    }
-                }
-                // Ignore all bytes after the esc.
-                Ok(_) | Err(TryRecvError::Empty) => Key::Esc,
-                Err(TryRecvError::Disconnected) => {
-                    panic!("Input channel disconnected")
-                }
-            },
-            Ok('O') => {
-                match self.input.recv_timeout(Duration::from_millis(100)) {
-                    Ok('H') => Key::Home, // <esc>OH
-                    Ok('F') => Key::End,  // <esc>OF
-                    // Ignore all bytes after the esc.
-                    Ok(_) | Err(RecvTimeoutError::Timeout) => Key::Esc,
-                    Err(RecvTimeoutError::Disconnected) => {
-                        panic!("Input channel disconnected")
-                    }
-                }
-            }
-            // Ignore the byte after the esc if there is one.
-            Ok(_) | Err(RecvTimeoutError::Timeout) => Key::Esc,
-            Err(RecvTimeoutError::Disconnected) => {
-                panic!("Input channel disconnected")
-            }
-        }
+    fn csv_editor(text: &str) -> Editor {
+        let mut editor = editor_with("x.csv", text, Config::default());
+        editor.toggle_csv_view();
+        editor
     }
-    fn move_cursor(&mut self, arrow: Arrow) {
-        match arrow {
-            Arrow::Up => {
-                if self.cursor_position.y > 0 {
-                    let screen_index = self.get_current_screen_index();
-                    self.cursor_position.y -= 1;
-                    self.cursor_position.x =
-                        Editor::screen_index_to_char_index(screen_index, self.get_current_row());
-                }
-            }
-            Arrow::Left => {
-                if self.cursor_position.x > 0 {
-                    self.cursor_position.x -= 1
-                } else if self.cursor_position.y > 0 {
-                    self.cursor_position.y -= 1;
-                    self.cursor_position.x = self.get_current_row().unwrap().chars.chars().count();
-                }
-            }
-            Arrow::Down => {
-                if self.cursor_position.y < self.rows.len() {
-                    let screen_index = self.get_current_screen_index();
-                    self.cursor_position.y += 1;
-                    self.cursor_position.x =
-                        Editor::screen_index_to_char_index(screen_index, self.get_current_row());
-                }
-            }
-            Arrow::Right => {
-                if let Some(row) = self.get_current_row() {
-                    #[allow(clippy::comparison_chain)]
-                    if self.cursor_position.x < row.chars.chars().count() {
-                        self.cursor_position.x += 1
-                    } else if self.cursor_position.x == row.chars.chars().count() {
-                        self.cursor_position.y += 1;
-                        self.cursor_position.x = 0;
-                    }
-                }
-            }
-        };
 
-        let row_length = if let Some(row) = self.get_current_row() {
-            row.chars.chars().count()
-        } else {
-            0
+    fn column_starts(editor: &Editor) -> Vec<usize> {
+        editor.csv_view.as_ref().unwrap().column_starts.clone()
+    }
+
+    // The columns kept up to date as rows change should be where working
+    // them out from scratch puts them.
+    fn assert_csv_columns_fresh(editor: &mut Editor) {
+        let kept = column_starts(editor);
+        editor.update_csv_columns();
+        assert_eq!(kept, column_starts(editor));
+    }
+
+    #[test]
+    fn csv_columns_start_after_the_widest_field() {
+        let editor = csv_editor("a,bb,c\nccc,d,e\n");
+        assert_eq!(column_starts(&editor), [5, 9]);
+    }
+
+    #[test]
+    fn csv_columns_grow_and_shrink_with_the_widest_field() {
+        let mut editor = csv_editor("a,bb,c\nccc,d,e\n");
+        editor.cursor_position = Position { x: 3, y: 1 };
+        editor.insert_char('c');
+        assert_eq!(column_starts(&editor), [6, 10]);
+        editor.delete_char();
+        editor.delete_char();
+        assert_eq!(column_starts(&editor), [4, 8]);
+        assert_csv_columns_fresh(&mut editor);
+    }
+
+    #[test]
+    fn csv_columns_ignore_changes_to_narrower_fields() {
+        let mut editor = csv_editor("a,bb,c\nccc,d,e\n");
+        editor.cursor_position = Position { x: 1, y: 0 };
+        editor.insert_char('a');
+        assert_eq!(column_starts(&editor), [5, 9]);
+        assert_csv_columns_fresh(&mut editor);
+    }
+
+    #[test]
+    fn csv_columns_go_when_the_rows_with_them_do() {
+        let mut editor = csv_editor("a,b\nc,d,eeee,f\ng,h\n");
+        assert_eq!(column_starts(&editor), [3, 6, 12]);
+        editor.delete_row(1);
+        assert_eq!(column_starts(&editor), [3]);
+        assert_csv_columns_fresh(&mut editor);
+        editor.insert_row(0, "iiii,j,k");
+        assert_eq!(column_starts(&editor), [6, 9]);
+        assert_csv_columns_fresh(&mut editor);
+    }
+
+    fn long_line_count(editor: &Editor) -> usize {
+        (0..editor.rows.len())
+            .filter(|&y| editor.is_long_line(y))
+            .count()
+    }
+
+    fn max_line_length_editor(text: &str) -> Editor {
+        let config = Config {
+            max_line_length: 5,
+            ..Config::default()
         };
+        editor_with("x.txt", text, config)
+    }
 
-        // Move the cursor to the end of the line if it is past the end.
-        if self.cursor_position.x > row_length {
-            self.cursor_position.x = row_length;
+    #[test]
+    fn long_lines_are_counted_as_they_change() {
+        let mut editor = max_line_length_editor("123456\nabc\n\tx\n");
+        assert_eq!(editor.long_lines, 2);
+        editor.cursor_position = Position { x: 3, y: 1 };
+        for c in "def".chars() {
+            editor.insert_char(c);
         }
+        assert_eq!(editor.long_lines, 3);
+        editor.delete_row(0);
+        assert_eq!(editor.long_lines, 2);
+        editor.cursor_position = Position { x: 6, y: 0 };
+        editor.delete_char();
+        assert_eq!(editor.long_lines, 1);
+        editor.insert_row(0, "1234567");
+        assert_eq!(editor.long_lines, 2);
+        assert_eq!(editor.long_lines, long_line_count(&editor));
     }
 
-    fn scroll(&mut self) {
-        // Update which part of the file we're looking at based on the new
-        // position of the cursor.
-        let screen_x = self.get_current_screen_index();
+    #[test]
+    fn long_lines_are_counted_again_when_the_limit_changes() {
+        let mut editor = max_line_length_editor("123456\nabc\n");
+        editor.set_option("max_line_length=2", false).unwrap();
+        assert_eq!(editor.long_lines, 2);
+        editor.set_option("max_line_length=0", false).unwrap();
+        assert_eq!(editor.long_lines, 0);
+        editor.clear_buffer();
+        assert_eq!(editor.long_lines, 0);
+    }
 
-        if self.cursor_position.y < self.text_offset.y {
-            self.text_offset.y = self.cursor_position.y;
-        }
+    #[test]
+    fn next_long_line_wraps_around() {
+        let mut editor = max_line_length_editor("1234567\nabc\nabcdefg\n");
+        editor.next_long_line();
+        assert_eq!(editor.cursor_position, Position { x: 5, y: 2 });
+        editor.next_long_line();
+        assert_eq!(editor.cursor_position, Position { x: 5, y: 0 });
+    }
 
-        if self.cursor_position.y >= self.text_offset.y + self.screen_dimensions.rows {
-            self.text_offset.y = self.cursor_position.y - self.screen_dimensions.rows + 1;
-        }
+    fn long_line_editor(text: &str) -> Editor {
+        let config = Config {
+            max_highlight_length: 100,
+            ..Config::default()
+        };
+        editor_with("x.c", text, config)
+    }
 
-        if screen_x < self.text_offset.x {
-            self.text_offset.x = screen_x;
-        }
+    #[test]
+    fn long_rows_arent_highlighted() {
+        let text = format!("int x = 1; /* {} */\nint y;\n", "x".repeat(200));
+        let mut editor = long_line_editor(&text);
+        assert!(highlights(&editor, 0).is_empty());
+        // The comment opener doesn't count either.
+        assert_eq!(highlights(&editor, 1)[0], Highlight::Keyword2);
+        let mut status = String::new();
+        editor.draw_status_bar(&mut status);
+        assert!(status.contains("[long line: not highlighted]"));
+        // Short rows still are.
+        editor.cursor_position.y = 1;
+        let mut status = String::new();
+        editor.draw_status_bar(&mut status);
+        assert!(!status.contains("[long line"));
+        assert_highlighted_as_if_opened(&mut editor);
+    }
 
-        if screen_x >= self.text_offset.x + self.screen_dimensions.cols {
-            self.text_offset.x = screen_x - self.screen_dimensions.cols + 1;
-        }
+    #[test]
+    fn long_rows_are_drawn_as_normal_text() {
+        let text = format!("int x = 1; {}\n", "y".repeat(200));
+        let mut editor = long_line_editor(&text);
+        let screen = screen(&mut editor);
+        let cols = editor.text_cols();
+        assert!(screen[0].ends_with(&text[..cols]));
     }
 
-    fn process_keypress(&mut self) -> KeypressResult {
-        let key = self.read_key();
+    #[test]
+    fn matches_in_long_rows_are_only_found_near_the_screen() {
+        let far = LONG_LINE_MARGIN + 500;
+        let text = format!("cat {} cat {}\n", " ".repeat(far), "y".repeat(far));
+        let mut editor = long_line_editor(&text);
+        editor.search_highlight = Some(Regex::new("cat").unwrap());
+        let cols = editor.text_cols();
+        let window = editor.drawn_window(0, 0, cols);
+        assert_eq!(editor.search_highlight_ranges(0, window), [(0, 3)]);
+        // Scrolled over to the second one, the first is too far away.
+        let window = editor.drawn_window(0, far, cols);
+        assert_eq!(
+            editor.search_highlight_ranges(0, window),
+            [(far + 5, far + 8)]
+        );
+    }
 
-        let mut result = KeypressResult::Continue;
+    #[test]
+    fn other_words_in_long_rows_are_offset_by_the_window() {
+        let far = LONG_LINE_MARGIN * 3;
+        let text = format!("{} cat cat {}\n", "y".repeat(far), "y".repeat(far));
+        let mut editor = long_line_editor(&text);
+        editor.cursor_word = Some("cat".to_string());
+        editor.cursor_position.x = far + 1;
+        let window = editor.drawn_window(0, far, editor.text_cols());
+        assert!(window.0 > 0);
+        assert_eq!(editor.cursor_word_ranges(0, window), [(far + 5, far + 8)]);
+    }
 
-        match key {
-            Key::Enter => {
-                self.insert_newline();
-            }
-            Key::Ctrl('q') => {
-                if self.dirty && self.quit_times > 0 {
-                    self.set_status_message(&format!(
-                        "WARNING!!! File has unsaved changes. \
-                         Press Ctrl-Q {} more times to quit.",
-                        self.quit_times
-                    ));
-                    self.quit_times -= 1;
-                    return result;
-                } else {
-                    result = KeypressResult::Terminate;
-                }
-            }
-            Key::Ctrl('s') => {
-                self.save();
-            }
-            Key::Ctrl('r') => {
-                self.find();
-            }
-            Key::Arrow(arrow) => {
-                self.move_cursor(arrow);
-            }
-            key @ Key::PageUp | key @ Key::PageDown => {
-                match key {
-                    Key::PageUp => self.cursor_position.y = self.text_offset.y,
-                    Key::PageDown => {
-                        self.cursor_position.y =
-                            self.text_offset.y + self.screen_dimensions.rows - 1;
-                        if self.cursor_position.y > self.rows.len() {
-                            self.cursor_position.y = self.rows.len();
-                        }
-                    }
-                    _ => {}
-                }
+    #[test]
+    fn a_search_match_in_a_long_row_is_shown_and_then_put_back() {
+        let text = format!("{} cat\n", "y".repeat(200));
+        let mut editor = long_line_editor(&text);
+        editor.find_callback("cat", Key::Char('t'));
+        assert_eq!(editor.cursor_position.x, 201);
+        let row = highlights(&editor, 0);
+        assert_eq!(row[201..204], [Highlight::Match; 3]);
+        assert!(row[..201]
+            .iter()
+            .all(|&highlight| highlight == Highlight::Normal));
+        editor.find_callback("", Key::Esc);
+        assert!(highlights(&editor, 0)
+            .iter()
+            .all(|&highlight| highlight == Highlight::Normal));
+    }
 
-                for _ in 0..self.screen_dimensions.rows - 1 {
-                    self.move_cursor(if let Key::PageUp = key {
-                        Arrow::Up
-                    } else {
-                        Arrow::Down
-                    });
-                }
-            }
-            Key::Home => {
-                self.cursor_position.x = 0;
-            }
-            Key::End => {
-                if let Some(row) = self.get_current_row() {
-                    self.cursor_position.x = row.chars.chars().count();
-                }
-            }
-            Key::Backspace => {
-                self.delete_char();
-            }
-            Key::Delete => {
-                self.move_cursor(Arrow::Right);
-                self.delete_char();
-            }
-            // Ignore these keys.
-            Key::Ctrl('l') | Key::Esc => {}
-            Key::Char(c) => {
-                self.insert_char(c);
-            }
-            Key::Ctrl(c) => {
-                self.insert_char((c as u8 & 0b10011111) as char);
-            }
+    fn indent_editor(filename: &str, text: &str) -> Editor {
+        let config = Config {
+            warn_mixed_indent: true,
+            ..Config::default()
         };
+        editor_with(filename, text, config)
+    }
 
-        self.quit_times = QUIT_TIMES;
-        result
+    fn bad_indents(editor: &Editor) -> Vec<usize> {
+        editor.rows.iter().map(|row| row.bad_indent).collect()
     }
 
-    fn render_loop(&mut self) {
-        loop {
-            self.refresh_screen();
-            if let KeypressResult::Terminate = self.process_keypress() {
-                break;
+    // The flags kept up to date as rows change should be what a fresh load
+    // of the same text gives.
+    fn assert_bad_indents_fresh(editor: &Editor) {
+        let filename = editor.filename.clone().unwrap();
+        let opened = indent_editor(&filename, &editor.rows_to_string());
+        assert_eq!(bad_indents(editor), bad_indents(&opened));
+        assert_eq!(editor.bad_indents, opened.bad_indents);
+    }
+
+    #[test]
+    fn mixed_indentation_is_flagged() {
+        let editor = indent_editor("x.txt", "\t  a\n    b\n\tc\n \t \n");
+        assert_eq!(bad_indents(&editor), [3, 0, 0, 0]);
+        assert_eq!(editor.bad_indents, 1);
+    }
+
+    #[test]
+    fn recipes_indented_with_spaces_are_flagged() {
+        let text = "all: x\n\tcc x.c\n    cc y.c\n\nX = 1\n    not a recipe\n";
+        let editor = indent_editor("Makefile", text);
+        assert_eq!(bad_indents(&editor), [0, 0, 4, 0, 0, 0]);
+        // Spaces are fine in files that don't need tabs.
+        let editor = indent_editor("x.txt", text);
+        assert_eq!(editor.bad_indents, 0);
+    }
+
+    #[test]
+    fn recipes_are_flagged_again_when_the_rule_above_changes() {
+        let text = "all: x\n\n\n    cc x.c\n";
+        let mut editor = indent_editor("Makefile", text);
+        assert_eq!(editor.bad_indents, 1);
+        // Turn the rule into a variable, through the blank lines.
+        editor.cursor_position = Position { x: 3, y: 0 };
+        editor.delete_char_forward();
+        editor.insert_char('=');
+        assert_eq!(editor.bad_indents, 0);
+        assert_bad_indents_fresh(&editor);
+        editor.delete_row(0);
+        editor.insert_row(0, "build:");
+        assert_eq!(editor.bad_indents, 1);
+        assert_bad_indents_fresh(&editor);
+    }
+
+    #[test]
+    fn deleting_a_row_rechecks_the_one_after() {
+        let mut editor = indent_editor("Makefile", "all:\nX = 1\n    cc x.c\n");
+        assert_eq!(editor.bad_indents, 0);
+        editor.delete_row(1);
+        assert_eq!(bad_indents(&editor), [0, 4]);
+        assert_bad_indents_fresh(&editor);
+        editor.delete_row(0);
+        assert_eq!(editor.bad_indents, 0);
+        assert_bad_indents_fresh(&editor);
+    }
+
+    #[cfg(feature = "compression")]
+    fn compressed(extension: &str, text: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match extension {
+            ".gz" => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(&mut bytes, flate2::Compression::default());
+                encoder.write_all(text.as_bytes()).unwrap();
+                encoder.finish().unwrap();
+            }
+            ".bz2" => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(&mut bytes, bzip2::Compression::default());
+                encoder.write_all(text.as_bytes()).unwrap();
+                encoder.finish().unwrap();
             }
+            _ => lzma_rs::xz_compress(&mut text.as_bytes(), &mut bytes).unwrap(),
         }
+        bytes
+    }
 
-        self.reset_screen();
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_files_open_read_only() {
+        for extension in COMPRESSED_EXTENSIONS {
+            let filename = temp_path(&format!("log.txt{}", extension));
+            std::fs::write(&filename, compressed(extension, "one\ntwo\n")).unwrap();
+            let editor = opened(&filename);
+            assert_eq!(rows(&editor), ["one", "two"], "{}", extension);
+            assert!(editor.read_only);
+        }
     }
-}
 
-/*** init ***/
+    #[cfg(feature = "compression")]
+    #[test]
+    fn corrupt_compressed_files_arent_opened() {
+        for extension in COMPRESSED_EXTENSIONS {
+            let filename = temp_path(&format!("corrupt.txt{}", extension));
+            let mut bytes = compressed(extension, &"a line\n".repeat(100));
+            bytes.truncate(bytes.len() / 2);
+            std::fs::write(&filename, bytes).unwrap();
+            let mut editor = Editor::headless(Config::default());
+            let error = editor.open(&filename).err().unwrap();
+            assert!(error.starts_with("can't decompress"), "{}", error);
+            assert!(editor.rows.is_empty());
+        }
+    }
 
-fn enable_raw_mode() -> Termios {
-    let stdin = io::stdin();
-    let orig_termios = termios::tcgetattr(stdin.as_fd()).expect("Error in tcgetattr");
+    #[test]
+    fn saving_removes_the_backup() {
+        let filename = temp_path("backed-up.txt");
+        std::fs::write(&filename, "text\n").unwrap();
+        let mut editor = Editor::headless(Config {
+            backup_idle_secs: 1,
+            ..Config::default()
+        });
+        editor.open(&filename).unwrap();
+        editor.insert_char('x');
+        editor.write_backup();
+        let backup = editor.backup_path().unwrap();
+        assert!(Path::new(&backup).exists());
+        editor.finish_save();
+        assert!(!Path::new(&backup).exists());
+        assert!(!editor.dirty);
+    }
 
-    let mut termios = orig_termios.clone();
-    termios.input_flags &= !(InputFlags::BRKINT
-        | InputFlags::ICRNL
-        | InputFlags::INPCK
-        | InputFlags::ISTRIP
-        | InputFlags::IXON);
-    termios.output_flags &= !(OutputFlags::OPOST);
-    termios.control_flags |= ControlFlags::CS8;
-    termios.local_flags &=
-        !(LocalFlags::ECHO | LocalFlags::ICANON | LocalFlags::IEXTEN | LocalFlags::ISIG);
-    // Rust always blocks when reading from stdin.
-    // termios.c_cc[VMIN] = 0;
-    // termios.c_cc[VTIME] = 1;
-    termios::tcsetattr(stdin.as_fd(), SetArg::TCSAFLUSH, &termios).expect("Error in tcsetattr");
+    // Run a script on a file with the text, returning what the file has in it
+    // afterwards, or the error.
+    fn scripted(name: &str, script: &str, text: &str) -> Result<String, String> {
+        let script_path = temp_path(&format!("{}.kilo", name));
+        let filename = temp_path(&format!("{}.txt", name));
+        std::fs::write(&script_path, script).unwrap();
+        std::fs::write(&filename, text).unwrap();
+        run_script(
+            &script_path,
+            Config::default(),
+            Encoding::Utf8,
+            Some(&filename),
+        )
+        .map_err(|error| error.message().to_string())?;
+        Ok(std::fs::read_to_string(&filename).unwrap())
+    }
 
-    orig_termios
-}
+    #[test]
+    fn scripts_edit_and_save_the_file() {
+        let script = "# Comments and blank lines are skipped.\n\n\
+                      goto 2\n\
+                      insert   indented\\n\n\
+                      replace /o/0/\n\
+                      save\n";
+        assert_eq!(
+            scripted("edit", script, "one\ntwo\nthree\n").unwrap(),
+            "0ne\n  indented\ntw0\nthree\n"
+        );
+    }
 
-fn disable_raw_mode(orig_termios: &mut Termios) {
-    termios::tcsetattr(io::stdin(), SetArg::TCSAFLUSH, orig_termios).expect("Error in tcsetattr");
-}
+    #[test]
+    fn scripts_run_commands() {
+        let script = "set tab_stop=4\nsort\nunique\nsave\nquit\n";
+        assert_eq!(scripted("commands", script, "b\na\nb\n").unwrap(), "a\nb\n");
+    }
 
-struct TerminalRestorer {
-    orig_termios: Termios,
-}
+    #[test]
+    fn scripts_stop_at_quit() {
+        let script = "insert x\nsave\nquit\ninsert y\n";
+        assert_eq!(scripted("quit", script, "text\n").unwrap(), "xtext\n");
+    }
 
-impl Drop for TerminalRestorer {
-    fn drop(&mut self) {
-        disable_raw_mode(&mut self.orig_termios);
+    #[test]
+    fn script_errors_name_the_line() {
+        assert_eq!(
+            scripted("unsaved", "insert x\nquit\n", "text\n").unwrap_err(),
+            format!(
+                "{}:2: There are unsaved changes (use quit! to drop them)",
+                temp_path("unsaved.kilo")
+            )
+        );
+        assert_eq!(
+            scripted("unknown", "goto 1\nfrobnicate\n", "text\n").unwrap_err(),
+            format!(
+                "{}:2: Unknown command: frobnicate",
+                temp_path("unknown.kilo")
+            )
+        );
+        assert_eq!(
+            scripted("dirty", "insert x\n", "text\n").unwrap_err(),
+            format!(
+                "{}: the changes weren't saved (end with save, or quit! to drop them)",
+                temp_path("dirty.kilo")
+            )
+        );
     }
-}
 
-fn main() {
-    // Enabling raw mode and saving current terminal options.
-    let orig_termios = enable_raw_mode();
-    // Restore the original terminal options when this struct is dropped.
-    // This ensures the original options are restored even if we panic.
-    let _terminal_restorer = TerminalRestorer { orig_termios };
+    #[test]
+    fn scripts_cant_use_commands_that_need_a_terminal() {
+        assert_eq!(
+            scripted("pager", "set\nquit\n", "text\n").unwrap_err(),
+            format!(
+                "{}:1: set needs an OPTION=VALUE in a script",
+                temp_path("pager.kilo")
+            )
+        );
+        assert_eq!(
+            scripted("global-pager", "setglobal\nquit\n", "text\n").unwrap_err(),
+            format!(
+                "{}:1: setglobal needs an OPTION=VALUE in a script",
+                temp_path("global-pager.kilo")
+            )
+        );
+    }
 
-    let mut editor = Editor::new();
+    #[test]
+    fn a_gutter_wider_than_the_screen_isnt_drawn() {
+        for config in [
+            Config {
+                gutter_padding_left: 200,
+                ..Config::default()
+            },
+            Config {
+                gutter_separator: "|".repeat(80),
+                ..Config::default()
+            },
+        ] {
+            let mut editor = editor_with("x.txt", "one\ntwo\n", config);
+            assert_eq!(editor.text_cols(), 80);
+            let screen = screen(&mut editor);
+            assert_eq!(screen[0], "one");
+            assert_eq!(screen[1], "two");
+        }
+    }
 
-    let mut args = env::args();
-    if args.len() >= 2 {
-        editor.open(&args.nth(1).unwrap());
+    #[test]
+    fn a_narrow_screen_leaves_no_room_for_the_gutter() {
+        let mut editor = editor_with("x.txt", "one\n", Config::default());
+        editor.screen_dimensions.cols = 2;
+        assert_eq!(editor.text_cols(), 2);
+        assert_eq!(screen(&mut editor)[0], "on");
+        editor.screen_dimensions.cols = 0;
+        assert_eq!(editor.text_cols(), 0);
+        editor.draw_frame();
     }
 
-    editor.set_status_message("HELP: Ctrl-S = Save | Ctrl-F = Find | Ctrl-Q = Quit");
+    #[test]
+    fn reflowing_uses_the_text_width_and_hanging_indent_options() {
+        let config = Config {
+            text_width: 10,
+            ..Config::default()
+        };
+        let mut editor = editor_with("x.txt", "  one two three four\n", config);
+        editor.reflow_paragraph();
+        assert_eq!(rows(&editor), ["  one two", "  three", "  four"]);
+
+        let mut editor = editor_with("x.txt", "  one two three four\n", Config::default());
+        editor.set_option("text_width=10", false).unwrap();
+        editor
+            .set_option("reflow_hanging_indent=false", false)
+            .unwrap();
+        editor.reflow_paragraph();
+        assert_eq!(rows(&editor), ["  one two", "three four"]);
+        assert!(editor.set_option("text_width=0", false).is_err());
+    }
 
-    editor.render_loop();
+    #[test]
+    fn unset_variables_in_paths_are_empty_unless_theyre_an_error() {
+        let path = "$KILO_TEST_NEVER_SET/x.txt";
+        assert_eq!(expand_path(path, false).unwrap(), "/x.txt");
+        assert_eq!(
+            expand_path(path, true).err().unwrap(),
+            "Environment variable KILO_TEST_NEVER_SET is not set"
+        );
+        let mut config = Config::default();
+        set_config_option(&mut config, "unset_variable_is_error").unwrap();
+        assert!(config.unset_variable_is_error);
+    }
+
+    #[test]
+    fn being_continued_stops_the_wait_for_a_key() {
+        let (_keys, input) = mpsc::channel();
+        let mut editor = Editor::with_input(
+            None,
+            Dimensions { rows: 22, cols: 80 },
+            input,
+            Config::default(),
+        );
+        let continued = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(RESUME_POLL_MS));
+            RESUMED.store(true, Ordering::SeqCst);
+        });
+        assert!(!editor.wait_for_key());
+        assert!(!RESUMED.load(Ordering::SeqCst));
+        continued.join().unwrap();
+    }
+
+    #[test]
+    fn load_warnings_are_shown_together() {
+        let editor = editor_with("x.txt", "a\r\nb\u{202e}\nc\n", Config::default());
+        assert_eq!(
+            editor.status_message,
+            "Warning: mixed line endings. Saving will use LF for every line; \
+             1 invisible or bidi control character found. \
+             Use the reveal or strip command to see or remove them"
+        );
+    }
 }